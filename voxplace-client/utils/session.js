@@ -0,0 +1,99 @@
+import jwtDecode from "jwt-decode";
+
+const userApi = (path) => `http://${window.location.hostname}:8000/api/user/${path}`;
+
+const REFRESH_LOCK = 'voxplace-session-refresh';
+
+let pendingRefresh = null;
+
+export function saveSession(session) {
+  localStorage.setItem('token', session.token);
+  localStorage.setItem('refreshToken', session.refresh_token);
+}
+
+export function clearSession() {
+  localStorage.removeItem('token');
+  localStorage.removeItem('refreshToken');
+}
+
+// The stored access token, unless it expires within a minute.
+function freshToken() {
+  const token = localStorage.getItem('token');
+  if (token && jwtDecode(token).exp * 1000 > Date.now() + 60 * 1000) {
+    return token;
+  }
+  return null;
+}
+
+// Access tokens only last a few minutes, so a new one is fetched with the refresh token when the
+// current one is about to expire. Returns the access token, or null when logged out.
+export async function refreshSession() {
+  const token = freshToken();
+  if (token) {
+    return token;
+  }
+  if (!localStorage.getItem('refreshToken')) {
+    return null;
+  }
+
+  if (!pendingRefresh) {
+    pendingRefresh = withRefreshLock(refreshStoredSession).finally(() => {
+      pendingRefresh = null;
+    });
+  }
+
+  return pendingRefresh;
+}
+
+// The refresh token changes on every use and sending an old one again ends the session, so tabs,
+// which share it, refresh one at a time.
+function withRefreshLock(callback) {
+  if (navigator.locks) {
+    return navigator.locks.request(REFRESH_LOCK, callback);
+  }
+  return callback();
+}
+
+// Storage is read again once the lock is held: the tab that held it before may have refreshed
+// the session already.
+async function refreshStoredSession() {
+  const token = freshToken();
+  if (token) {
+    return token;
+  }
+
+  const refreshToken = localStorage.getItem('refreshToken');
+  if (!refreshToken) {
+    return null;
+  }
+
+  const response = await fetch(userApi('refresh'), {
+    method: 'POST',
+    headers: {
+      'Content-Type': 'application/json'
+    },
+    body: JSON.stringify({ refresh_token: refreshToken })
+  });
+  if (!response.ok) {
+    clearSession();
+    return null;
+  }
+  const session = await response.json();
+  saveSession(session);
+  return session.token;
+}
+
+export async function endSession() {
+  const refreshToken = localStorage.getItem('refreshToken');
+  clearSession();
+
+  if (refreshToken) {
+    await fetch(userApi('logout'), {
+      method: 'POST',
+      headers: {
+        'Content-Type': 'application/json'
+      },
+      body: JSON.stringify({ refresh_token: refreshToken })
+    });
+  }
+}
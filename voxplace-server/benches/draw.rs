@@ -0,0 +1,97 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use rand::{Rng, thread_rng};
+use voxplace_server::database::db::Database;
+use voxplace_server::flush::flush_places;
+use voxplace_server::place::Place;
+use voxplace_server::voxel::Voxel;
+
+const GRID_SIZE: usize = 64;
+const MAX_PLACES: usize = 8;
+const MAX_THREADS: usize = 8;
+const DRAWS_PER_THREAD: usize = 100_000;
+
+// Draw throughput on an in-memory database, for 1 up to MAX_THREADS users spread evenly over 1 up
+// to MAX_PLACES places. Update batching and the database flush run alongside, as they do in the
+// server. Run with `cargo bench --bench draw [max threads] [draws per thread]`.
+fn main() {
+    let mut args = std::env::args().skip(1).filter_map(|arg| arg.parse::<usize>().ok());
+    let max_threads = args.next().unwrap_or(MAX_THREADS);
+    let draws_per_thread = args.next().unwrap_or(DRAWS_PER_THREAD);
+
+    println!("{:>8} {:>8} {:>12} {:>14}", "places", "threads", "draws", "draws/s");
+
+    let mut place_count = 1;
+    while place_count <= MAX_PLACES {
+        let mut threads = place_count;
+        while threads <= max_threads {
+            let draws = threads * draws_per_thread;
+            let elapsed = run(place_count, threads, draws_per_thread);
+            println!(
+                "{:>8} {:>8} {:>12} {:>14.0}",
+                place_count,
+                threads,
+                draws,
+                draws as f64 / elapsed.as_secs_f64()
+            );
+            threads *= 2;
+        }
+        place_count *= 2;
+    }
+}
+
+fn run(place_count: usize, threads: usize, draws_per_thread: usize) -> Duration {
+    let database = Database::open(":memory:").expect("Failed to open database");
+    database.migrate().expect("Failed to migrate database");
+
+    let grid_size = (GRID_SIZE, GRID_SIZE, GRID_SIZE);
+    let places: Vec<Arc<RwLock<Place>>> = (0..place_count)
+        .map(|_| {
+            let voxel = Voxel::new(thread_rng().gen::<i64>(), "bench", 0, grid_size, None, None, None);
+            let place = Place::new(thread_rng().gen::<i64>(), true, 0, voxel);
+            database.save_new_voxel(&place.voxel).expect("Failed to save voxel");
+            database.save_new_place(&place).expect("Failed to save place");
+            Arc::new(RwLock::new(place))
+        })
+        .collect();
+    let database = Arc::new(Mutex::new(database));
+
+    let done = Arc::new(AtomicBool::new(false));
+    let flusher = {
+        let done = done.clone();
+        let places = places.clone();
+        thread::spawn(move || {
+            while !done.load(Ordering::Acquire) {
+                thread::sleep(Duration::from_millis(50));
+                for place in &places {
+                    place.read().unwrap().voxel.take_update_frame();
+                }
+                flush_places(&database, &places, true);
+            }
+        })
+    };
+
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for user_id in 0..threads {
+            let place = &places[user_id % place_count];
+            scope.spawn(move || {
+                let mut rng = thread_rng();
+                for _ in 0..draws_per_thread {
+                    let x = rng.gen_range(0..GRID_SIZE);
+                    let z = rng.gen_range(0..GRID_SIZE);
+                    let color = rng.gen_range(1..=32);
+                    let _ = place.read().unwrap().draw(user_id as i64, x, 0, z, color);
+                }
+            });
+        }
+    });
+    let elapsed = start.elapsed();
+
+    done.store(true, Ordering::Release);
+    let _ = flusher.join();
+
+    elapsed
+}
@@ -1,22 +1,29 @@
+use actix_web::web::Bytes;
+use chrono::Utc;
+use crate::config::Config;
 use crate::database::db::Database;
-use crate::database::place::PlaceUserUpdate;
 use crate::place::Place;
 use crate::voxel::Voxel;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex, RwLock};
 use crate::palette::Palette;
+use crate::render::ThumbnailCache;
+use crate::timelapse::TimelapseJob;
 
 pub struct AppState {
+    pub config: Config,
     pub database: Arc<Mutex<Database>>,
     pub places: HashMap<i64, Arc<RwLock<Place>>>,
-    last_user_update: i64,
+    pub thumbnails: Mutex<ThumbnailCache>,
+    pub place_meshes: Mutex<HashMap<i64, (String, Bytes)>>,
+    pub timelapses: Mutex<HashMap<i64, TimelapseJob>>,
+    usernames: Mutex<HashMap<i64, String>>,
 }
 
 impl AppState {
-    pub fn new(database: Database) -> Self {
+    pub fn new(database: Database, config: Config) -> Self {
         let mut places = HashMap::new();
-        database.init();
-        let std_palette = Palette::new(0, None);
+        let std_palette = Palette::new(0, None, None);
         database.save_new_palette(std_palette).unwrap();
 
         let places_infos = match database.get_places_infos() {
@@ -43,6 +50,10 @@ impl AppState {
                     }
                 };
                 let place = Place::new(place_id, true, place_info.cooldown, voxel);
+                match database.get_place_cooldowns(place_id, Utc::now().timestamp()) {
+                    Ok(cooldowns) => place.load_cooldowns(cooldowns),
+                    Err(e) => eprintln!("Failed to read place cooldowns: {}", e),
+                }
                 let place_id = place.id;
                 let place_arc = Arc::new(RwLock::new(place));
                 places.insert(place_id, place_arc);
@@ -50,9 +61,13 @@ impl AppState {
         }
 
         Self {
+            config,
             database: Arc::new(Mutex::new(database)),
             places,
-            last_user_update: 0,
+            thumbnails: Mutex::new(ThumbnailCache::new()),
+            place_meshes: Mutex::new(HashMap::new()),
+            timelapses: Mutex::new(HashMap::new()),
+            usernames: Mutex::new(HashMap::new()),
         }
     }
 
@@ -105,63 +120,19 @@ impl AppState {
         }
     }
 
-    pub fn get_places_users_updates(&mut self) -> Vec<PlaceUserUpdate> {
-        let mut updates = Vec::new();
-
-        for (_, place) in self.places.iter_mut() {
-            let place = place.read().unwrap();
-            let place_updates = place.get_place_updates();
-            for update in place_updates {
-                updates.push(update);
-            }
+    // Usernames are cached so the draw path doesn't wait on the database; `edit_user` forgets a
+    // user's entry when it changes.
+    pub fn username(&self, user_id: i64) -> Option<String> {
+        if let Some(username) = self.usernames.lock().unwrap().get(&user_id) {
+            return Some(username.clone());
         }
 
-        updates
-    }
-
-    pub fn places_users_updates(&mut self) {
-        let time = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
-        if time - self.last_user_update > 5 {
-            self.last_user_update = time;
-            let updates = self.get_places_users_updates();
-            match self.database.lock().unwrap().save_places_users(updates) {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("Failed to save updates: {}", e);
-                }
-            }
-        }
+        let username = self.database.lock().ok()?.get_username(user_id).ok()?;
+        self.usernames.lock().unwrap().insert(user_id, username.clone());
+        Some(username)
     }
 
-    pub fn update_place_grid(&mut self, place_id: i64) {
-        let place = match self.places.get_mut(&place_id) {
-            Some(place) => place,
-            None => {
-                eprintln!("Failed to get place with id {}", place_id);
-                return;
-            }
-        };
-
-        let time = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
-
-        let mut place = place.write().unwrap();
-
-        if time - place.last_grid_update() > 5 {
-            place.set_last_grid_update(time);
-            let grid: Vec<u8> = place.voxel.grid.iter().map(|cell| cell.load()).collect();
-
-            match self.database.lock().unwrap().save_voxel_grid(place.voxel.id, grid) {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("Failed to save voxel grid: {}", e);
-                }
-            }
-        }
+    pub fn forget_username(&self, user_id: i64) {
+        self.usernames.lock().unwrap().remove(&user_id);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,104 @@
+use std::future::{ready, Ready};
+use std::sync::Mutex;
+use actix_web::dev::Payload;
+use actix_web::web::Data;
+use actix_web::{FromRequest, HttpRequest};
+use crate::config::Config;
+use crate::database::db::Database;
+use crate::error::ApiError;
+use crate::role::Role;
+use crate::user::check_token;
+
+// The config and database are registered as app data of their own, so authenticating a request
+// never takes the app state lock.
+pub struct AuthUser {
+    pub id: i64,
+    database: Data<Mutex<Database>>,
+}
+
+impl AuthUser {
+    pub fn role(&self, place_id: Option<i64>) -> Result<Role, ApiError> {
+        let db = self.database.lock()?;
+        Ok(db.get_role(self.id, place_id)?)
+    }
+
+    // Checks the role on the place, or on every place when there is none. Must be called before
+    // the handler locks the database itself.
+    pub fn require(&self, role: Role, place_id: Option<i64>) -> Result<(), ApiError> {
+        if self.role(place_id)? < role {
+            return Err(ApiError::Forbidden(format!("This requires the {} role", role.as_str())));
+        }
+        Ok(())
+    }
+}
+
+impl FromRequest for AuthUser {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(authenticate(req))
+    }
+}
+
+// For routes where only some requests need the user, like "me" paths. Failing to authenticate
+// is only an error once the handler asks for the user.
+pub struct OptionalUser(Result<AuthUser, ApiError>);
+
+impl OptionalUser {
+    pub fn required(self) -> Result<AuthUser, ApiError> {
+        self.0
+    }
+}
+
+impl FromRequest for OptionalUser {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(OptionalUser(authenticate(req))))
+    }
+}
+
+pub struct AdminUser {
+    pub id: i64,
+}
+
+impl FromRequest for AdminUser {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(authenticate(req).and_then(|user| {
+            user.require(Role::Admin, None)?;
+            Ok(AdminUser { id: user.id })
+        }))
+    }
+}
+
+fn authenticate(req: &HttpRequest) -> Result<AuthUser, ApiError> {
+    let token = req
+        .headers()
+        .get("Authorization")
+        .ok_or_else(|| ApiError::Unauthorized("No token provided".to_string()))?
+        .to_str()
+        .map_err(|_| invalid_token())?;
+
+    let config = req
+        .app_data::<Data<Config>>()
+        .ok_or_else(|| ApiError::Internal("Missing config".to_string()))?;
+    let database = req
+        .app_data::<Data<Mutex<Database>>>()
+        .ok_or_else(|| ApiError::Internal("Missing database".to_string()))?;
+
+    let id = check_token(token, &config.jwt_secret).ok_or_else(invalid_token)?;
+
+    Ok(AuthUser {
+        id,
+        database: database.clone(),
+    })
+}
+
+fn invalid_token() -> ApiError {
+    ApiError::Unauthorized("Invalid token".to_string())
+}
@@ -1,11 +1,14 @@
 use std::sync::RwLock;
-use actix_web::{get, HttpRequest, HttpResponse, post, Responder};
+use actix_web::{get, HttpResponse, post};
 use actix_web::web::{Data, Json, Path};
 use chrono::Utc;
 use rand::{Rng, thread_rng};
 use serde_derive::Deserialize;
 use crate::app_state::AppState;
-use crate::user::check_user;
+use crate::auth::AuthUser;
+use crate::error::ApiError;
+use crate::role::Role;
+use crate::websocket::ServerMessage;
 
 pub struct Comment {
     pub comment_id: i64,
@@ -47,84 +50,51 @@ struct CreateCommentRequest {
 async fn get_post_comments(
     data: Data<RwLock<AppState>>,
     path: Path<i64>,
-) -> impl Responder {
+) -> Result<HttpResponse, ApiError> {
     let post_id = path.into_inner();
 
-    let app_state = match data.read() {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read app state"),
-    };
+    let app_state = data.read()?;
 
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock database"),
-    };
+    let db = app_state.database.lock()?;
 
-    match db.get_comments_by_post_id(post_id) {
-        Ok(comments) => HttpResponse::Ok().json(comments),
-        Err(e) => HttpResponse::InternalServerError().body(format!("Failed to get comments : {}", e)),
-    }
+    Ok(HttpResponse::Ok().json(db.get_comments_by_post_id(post_id)?))
 }
 
 #[get("/api/comment/place/{place_id}")]
 async fn get_place_comments(
     data: Data<RwLock<AppState>>,
     path: Path<i64>,
-) -> impl Responder {
+) -> Result<HttpResponse, ApiError> {
     let place_id = path.into_inner();
 
-    let app_state = match data.read() {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read app state"),
-    };
+    let app_state = data.read()?;
 
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock database"),
-    };
+    let db = app_state.database.lock()?;
 
-    match db.get_comments_by_place_id(place_id) {
-        Ok(comments) => HttpResponse::Ok().json(comments),
-        Err(_) => HttpResponse::InternalServerError().body("Failed to get comments"),
-    }
+    Ok(HttpResponse::Ok().json(db.get_comments_by_place_id(place_id)?))
 }
 
 #[post("/api/comment/create")]
 async fn create_comment(
     data: Data<RwLock<AppState>>,
-    req: HttpRequest,
+    user: AuthUser,
     json: Json<CreateCommentRequest>,
-) -> impl Responder {
-    let user_id = match check_user(req) {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::Unauthorized().body("Invalid user"),
-    };
+) -> Result<HttpResponse, ApiError> {
+    let user_id = user.id;
 
     let place_id = match json.place_id {
-        Some(ref id) => match id.parse::<i64>() {
-            Ok(id) => Some(id),
-            Err(_) => return HttpResponse::BadRequest().body("Invalid place id"),
-        },
+        Some(ref id) => Some(id.parse::<i64>().map_err(|_| ApiError::BadRequest("Invalid place id".to_string()))?),
         None => None,
     };
 
     let post_id = match json.post_id {
-        Some(ref id) => match id.parse::<i64>() {
-            Ok(id) => Some(id),
-            Err(_) => return HttpResponse::BadRequest().body("Invalid post id"),
-        },
+        Some(ref id) => Some(id.parse::<i64>().map_err(|_| ApiError::BadRequest("Invalid post id".to_string()))?),
         None => None,
     };
 
-    let app_state = match data.read() {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read app state"),
-    };
+    let app_state = data.read()?;
 
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock database"),
-    };
+    let db = app_state.database.lock()?;
 
     let comment_id = thread_rng().gen::<i64>();
 
@@ -139,8 +109,34 @@ async fn create_comment(
         time,
     );
 
-    match db.save_new_comment(comment) {
-        Ok(_) => HttpResponse::Ok().json("Comment created"),
-        Err(_) => HttpResponse::InternalServerError().body("Failed to create comment"),
+    db.save_new_comment(comment)?;
+
+    Ok(HttpResponse::Ok().json("Comment created"))
+}
+
+#[post("/api/comment/delete/{comment_id}")]
+async fn delete_comment(
+    data: Data<RwLock<AppState>>,
+    user: AuthUser,
+    path: Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    let comment_id = path.into_inner();
+
+    let app_state = data.read()?;
+
+    // Place moderators can delete the chat of their place; post comments need a global one.
+    let place_id = app_state.database.lock()?.get_comment_place_id(comment_id)?;
+    user.require(Role::Moderator, place_id)?;
+
+    app_state.database.lock()?.delete_comment(comment_id)?;
+
+    // Viewers of a live place drop the message from their chat.
+    if let Some(place) = place_id.and_then(|id| app_state.places.get(&id)) {
+        let message = ServerMessage::ChatDeleted {
+            comment_id: comment_id.to_string(),
+        };
+        place.read()?.voxel.broadcast_message(message.to_json());
     }
-}
\ No newline at end of file
+
+    Ok(HttpResponse::Ok().json("Comment deleted"))
+}
@@ -0,0 +1,296 @@
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use clap::{Args, Parser, Subcommand};
+use rand::{Rng, thread_rng};
+use serde_derive::Deserialize;
+use thiserror::Error;
+use crate::flush::FlushIntervals;
+
+const DEFAULT_CONFIG_PATH: &str = "voxplace.toml";
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read {0}: {1}")]
+    Read(String, std::io::Error),
+
+    #[error("Failed to parse {0}: {1}")]
+    Parse(String, toml::de::Error),
+
+    #[error("Invalid {0}: {1}")]
+    Invalid(&'static str, String),
+}
+
+#[derive(Parser)]
+#[command(name = "voxplace-server", about = "Voxplace server")]
+pub struct Cli {
+    // Defaults to voxplace.toml in the working directory, if there is one.
+    #[arg(long, env = "VOXPLACE_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    settings: Settings,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Apply pending database migrations and exit
+    Migrate,
+}
+
+// Every setting can come from a flag, an environment variable or the config file, in that order
+// of precedence; whatever is left unset takes its default.
+#[derive(Args, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    #[arg(long, env = "VOXPLACE_BIND_ADDRESS")]
+    bind_address: Option<String>,
+
+    #[arg(long, env = "VOXPLACE_DATABASE_PATH")]
+    database_path: Option<String>,
+
+    #[arg(long, env = "VOXPLACE_JWT_SECRET", hide_env_values = true)]
+    jwt_secret: Option<String>,
+
+    // Local development only: without a jwt_secret, sign tokens with a random one.
+    #[arg(long, env = "VOXPLACE_DEV_JWT_SECRET", num_args = 0..=1, default_missing_value = "true")]
+    dev_jwt_secret: Option<bool>,
+
+    #[arg(long, env = "VOXPLACE_JWT_EXPIRY_SECS")]
+    jwt_expiry_secs: Option<i64>,
+
+    #[arg(long, env = "VOXPLACE_REFRESH_EXPIRY_SECS")]
+    refresh_expiry_secs: Option<i64>,
+
+    #[arg(long, env = "VOXPLACE_DEFAULT_COOLDOWN")]
+    default_cooldown: Option<i64>,
+
+    #[arg(long, env = "VOXPLACE_MAX_PLACE_SIZE")]
+    max_place_size: Option<usize>,
+
+    #[arg(long, env = "VOXPLACE_MAX_VOXEL_SIZE")]
+    max_voxel_size: Option<usize>,
+
+    #[arg(long, env = "VOXPLACE_CORS_ORIGINS", value_delimiter = ',')]
+    cors_origins: Option<Vec<String>>,
+
+    #[arg(long, env = "VOXPLACE_FLUSH_INTERVAL_MS")]
+    flush_interval_ms: Option<u64>,
+
+    #[arg(long, env = "VOXPLACE_GRID_FLUSH_INTERVAL_MS")]
+    grid_flush_interval_ms: Option<u64>,
+}
+
+impl Settings {
+    fn or(self, other: Settings) -> Settings {
+        Settings {
+            bind_address: self.bind_address.or(other.bind_address),
+            database_path: self.database_path.or(other.database_path),
+            jwt_secret: self.jwt_secret.or(other.jwt_secret),
+            dev_jwt_secret: self.dev_jwt_secret.or(other.dev_jwt_secret),
+            jwt_expiry_secs: self.jwt_expiry_secs.or(other.jwt_expiry_secs),
+            refresh_expiry_secs: self.refresh_expiry_secs.or(other.refresh_expiry_secs),
+            default_cooldown: self.default_cooldown.or(other.default_cooldown),
+            max_place_size: self.max_place_size.or(other.max_place_size),
+            max_voxel_size: self.max_voxel_size.or(other.max_voxel_size),
+            cors_origins: self.cors_origins.or(other.cors_origins),
+            flush_interval_ms: self.flush_interval_ms.or(other.flush_interval_ms),
+            grid_flush_interval_ms: self.grid_flush_interval_ms.or(other.grid_flush_interval_ms),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub bind_address: String,
+    pub database_path: String,
+    // Has no default: anyone knowing it can sign tokens for any user.
+    pub jwt_secret: String,
+    // Access tokens can't be revoked, so they are kept short; sessions last as long as their
+    // refresh token.
+    pub jwt_expiry_secs: i64,
+    pub refresh_expiry_secs: i64,
+    pub default_cooldown: i64,
+    pub max_place_size: usize,
+    pub max_voxel_size: usize,
+    // "*" allows any origin.
+    pub cors_origins: Vec<String>,
+    pub flush: FlushIntervals,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind_address: "0.0.0.0:8000".to_string(),
+            database_path: "database.db".to_string(),
+            jwt_secret: String::new(),
+            jwt_expiry_secs: 15 * 60,
+            refresh_expiry_secs: 30 * 24 * 60 * 60,
+            default_cooldown: 60,
+            max_place_size: 256,
+            max_voxel_size: 128,
+            cors_origins: vec!["*".to_string()],
+            flush: FlushIntervals {
+                flush: Duration::from_millis(1000),
+                grid: Duration::from_millis(5000),
+            },
+        }
+    }
+}
+
+impl Config {
+    pub fn load(cli: &mut Cli) -> Result<Config, ConfigError> {
+        let file = match &cli.config {
+            Some(path) => read_settings(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => read_settings(Path::new(DEFAULT_CONFIG_PATH))?,
+            None => Settings::default(),
+        };
+
+        Config::from_settings(std::mem::take(&mut cli.settings).or(file))
+    }
+
+    fn from_settings(settings: Settings) -> Result<Config, ConfigError> {
+        let defaults = Config::default();
+
+        let jwt_secret = match (settings.jwt_secret, settings.dev_jwt_secret) {
+            (Some(jwt_secret), _) => jwt_secret,
+            (None, Some(true)) => {
+                eprintln!("No jwt_secret set, signing tokens with a random one; they won't survive a restart");
+                thread_rng().gen::<[u8; 32]>().iter().map(|byte| format!("{:02x}", byte)).collect()
+            }
+            (None, _) => defaults.jwt_secret,
+        };
+
+        let config = Config {
+            bind_address: settings.bind_address.unwrap_or(defaults.bind_address),
+            database_path: settings.database_path.unwrap_or(defaults.database_path),
+            jwt_secret,
+            jwt_expiry_secs: settings.jwt_expiry_secs.unwrap_or(defaults.jwt_expiry_secs),
+            refresh_expiry_secs: settings.refresh_expiry_secs.unwrap_or(defaults.refresh_expiry_secs),
+            default_cooldown: settings.default_cooldown.unwrap_or(defaults.default_cooldown),
+            max_place_size: settings.max_place_size.unwrap_or(defaults.max_place_size),
+            max_voxel_size: settings.max_voxel_size.unwrap_or(defaults.max_voxel_size),
+            cors_origins: settings.cors_origins.unwrap_or(defaults.cors_origins),
+            flush: FlushIntervals {
+                flush: settings
+                    .flush_interval_ms
+                    .map(Duration::from_millis)
+                    .unwrap_or(defaults.flush.flush),
+                grid: settings
+                    .grid_flush_interval_ms
+                    .map(Duration::from_millis)
+                    .unwrap_or(defaults.flush.grid),
+            },
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |name: &'static str, message: &str| Err(ConfigError::Invalid(name, message.to_string()));
+
+        if !self.bind_address.to_socket_addrs().is_ok_and(|mut addrs| addrs.next().is_some()) {
+            return invalid("bind_address", "expected host:port");
+        }
+        if self.database_path.is_empty() {
+            return invalid("database_path", "must not be empty");
+        }
+        if self.jwt_secret.is_empty() {
+            return invalid("jwt_secret", "must be set, or dev_jwt_secret enabled for local development");
+        }
+        if self.jwt_expiry_secs <= 0 {
+            return invalid("jwt_expiry_secs", "must be positive");
+        }
+        if self.refresh_expiry_secs < self.jwt_expiry_secs {
+            return invalid("refresh_expiry_secs", "must not be shorter than jwt_expiry_secs");
+        }
+        if self.default_cooldown < 0 {
+            return invalid("default_cooldown", "must not be negative");
+        }
+        // Chunk and update frames carry coordinates as u16.
+        for (name, size) in [("max_place_size", self.max_place_size), ("max_voxel_size", self.max_voxel_size)] {
+            if size == 0 || size > u16::MAX as usize {
+                return invalid(name, "must be between 1 and 65535");
+            }
+        }
+        if self.cors_origins.is_empty() {
+            return invalid("cors_origins", "must list at least one origin, or \"*\"");
+        }
+        for origin in &self.cors_origins {
+            if origin != "*" && !origin.starts_with("http://") && !origin.starts_with("https://") {
+                return Err(ConfigError::Invalid("cors_origins", format!("{} is not an http(s) origin", origin)));
+            }
+        }
+        if self.flush.flush.is_zero() || self.flush.grid.is_zero() {
+            return invalid("flush intervals", "must be positive");
+        }
+
+        Ok(())
+    }
+
+    pub fn allows_any_origin(&self) -> bool {
+        self.cors_origins.iter().any(|origin| origin == "*")
+    }
+}
+
+fn read_settings(path: &Path) -> Result<Settings, ConfigError> {
+    let name = path.display().to_string();
+    let content = std::fs::read_to_string(path).map_err(|e| ConfigError::Read(name.clone(), e))?;
+    toml::from_str(&content).map_err(|e| ConfigError::Parse(name, e))
+}
+
+pub fn grid_size_allowed(size: (usize, usize, usize), max: usize) -> bool {
+    [size.0, size.1, size.2].iter().all(|side| (1..=max).contains(side))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Settings are built directly, so neither VOXPLACE_* variables nor a voxplace.toml in the
+    // working directory can leak in.
+    fn with_secret(jwt_secret: Option<&str>, dev_jwt_secret: Option<bool>) -> Result<Config, ConfigError> {
+        Config::from_settings(Settings {
+            jwt_secret: jwt_secret.map(str::to_string),
+            dev_jwt_secret,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn requires_a_jwt_secret() {
+        assert!(matches!(with_secret(None, None), Err(ConfigError::Invalid("jwt_secret", _))));
+        assert!(matches!(with_secret(Some(""), None), Err(ConfigError::Invalid("jwt_secret", _))));
+        assert!(matches!(with_secret(None, Some(false)), Err(ConfigError::Invalid("jwt_secret", _))));
+        assert_eq!(with_secret(Some("s3cret"), None).unwrap().jwt_secret, "s3cret");
+        assert_eq!(with_secret(Some("s3cret"), Some(true)).unwrap().jwt_secret, "s3cret");
+    }
+
+    #[test]
+    fn dev_jwt_secret_is_random() {
+        let first = with_secret(None, Some(true)).unwrap().jwt_secret;
+        let second = with_secret(None, Some(true)).unwrap().jwt_secret;
+        assert_eq!(first.len(), 64);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn flags_take_precedence_over_the_file() {
+        let flags = Settings {
+            jwt_secret: Some("flag".to_string()),
+            ..Default::default()
+        };
+        let file = Settings {
+            jwt_secret: Some("file".to_string()),
+            max_place_size: Some(64),
+            ..Default::default()
+        };
+        let config = Config::from_settings(flags.or(file)).unwrap();
+        assert_eq!(config.jwt_secret, "flag");
+        assert_eq!(config.max_place_size, 64);
+    }
+}
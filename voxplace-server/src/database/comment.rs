@@ -24,25 +24,6 @@ pub struct PlaceComment {
 }
 
 impl Database {
-    pub fn create_comment_table(&self) -> Result<(), DatabaseError> {
-        self.get_conn()?.execute(
-            "CREATE TABLE IF NOT EXISTS Comment (
-                comment_id INTEGER PRIMARY KEY,
-                user_id INTEGER NOT NULL,
-                place_id INTEGER,
-                post_id INTEGER,
-                content TEXT NOT NULL,
-                created_at DATETIME NOT NULL,
-                FOREIGN KEY (user_id) REFERENCES User (id),
-                FOREIGN KEY (place_id) REFERENCES Place (id),
-                FOREIGN KEY (post_id) REFERENCES Post (id)
-            )",
-            [],
-        )?;
-
-        Ok(())
-    }
-
     pub fn save_new_comment(&self, comment: Comment) -> Result<(), DatabaseError> {
         let conn = self.get_conn()?;
         let mut stmt = conn.prepare(
@@ -112,4 +93,24 @@ impl Database {
 
         Ok(comments)
     }
-}
\ No newline at end of file
+
+    // Returns the place the comment was posted on, if any.
+    pub fn get_comment_place_id(&self, comment_id: i64) -> Result<Option<i64>, DatabaseError> {
+        let conn = self.get_conn()?;
+        match conn.query_row(
+            "SELECT place_id FROM Comment WHERE comment_id = ?",
+            params![comment_id],
+            |row| row.get::<_, Option<i64>>(0),
+        ) {
+            Ok(place_id) => Ok(place_id),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Err(DatabaseError::NoSuchComment()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn delete_comment(&self, comment_id: i64) -> Result<(), DatabaseError> {
+        let conn = self.get_conn()?;
+        conn.execute("DELETE FROM Comment WHERE comment_id = ?", params![comment_id])?;
+        Ok(())
+    }
+}
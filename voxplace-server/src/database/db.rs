@@ -3,6 +3,7 @@ use std::sync::{Mutex, MutexGuard};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum DatabaseError {
     #[error("Error during IO: {0}")]
     CompressionError(#[from] std::io::Error),
@@ -22,11 +23,23 @@ pub enum DatabaseError {
     #[error("No such post")]
     NoSuchPost(),
 
+    #[error("No such snapshot")]
+    NoSuchSnapshot(),
+
+    #[error("No such comment")]
+    NoSuchComment(),
+
     #[error("Error during database lock: {0}")]
     LockError(String),
 
     #[error("Invalid vote")]
     InvalidVote(),
+
+    #[error("Grid must be {0} bytes")]
+    InvalidGridSize(usize),
+
+    #[error("Database schema version {0} is newer than this server")]
+    UnknownSchemaVersion(i64),
 }
 
 pub struct Database {
@@ -34,27 +47,24 @@ pub struct Database {
 }
 
 impl Database {
-    pub fn new() -> Result<Self, DatabaseError> {
-        let conn = Connection::open("database.db")?;
+    pub fn open(path: &str) -> Result<Self, DatabaseError> {
+        let conn = Connection::open(path)?;
 
         Ok(Self {
             conn: Mutex::new(conn),
         })
     }
 
-    pub fn init(&self) {
-        self.create_palette_table().unwrap();
-        self.create_voxel_table().unwrap();
-        self.create_place_table().unwrap();
-        self.create_user_table().unwrap();
-        self.create_place_user_table().unwrap();
-        self.create_place_user_cooldown_table().unwrap();
-        self.create_user_voxel_table().unwrap();
-        self.create_post_table().unwrap();
-        self.create_comment_table().unwrap();
+    // Brings the schema up to date.
+    pub fn init(&self) -> Result<(), DatabaseError> {
+        for migration in self.migrate()? {
+            println!("Applied migration {} ({})", migration.version, migration.name);
+        }
+
+        Ok(())
     }
 
-    pub fn get_conn(&self) -> Result<MutexGuard<Connection>, DatabaseError> {
+    pub fn get_conn(&self) -> Result<MutexGuard<'_, Connection>, DatabaseError> {
         let conn = self.conn.lock().map_err(|e| DatabaseError::LockError(e.to_string()))?;
         Ok(conn)
     }
@@ -0,0 +1,498 @@
+use chrono::Utc;
+use rusqlite::{params, Connection};
+use crate::database::db::{Database, DatabaseError};
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    step: Step,
+}
+
+enum Step {
+    Sql(&'static str),
+    // For data changes SQL can't express; runs in the migration's transaction.
+    Code(fn(&Connection) -> Result<(), DatabaseError>),
+}
+
+// Append new migrations at the end with the next version; never edit one that has shipped.
+// Version 1 is exactly the schema the old Database::init created, with IF NOT EXISTS so databases
+// from before versioning adopt it as is. No release ever had the later tables, so every version
+// from 2 on is an ordinary migration that runs once on every database.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "baseline",
+        step: Step::Sql("
+            CREATE TABLE IF NOT EXISTS Palette (
+                palette_id INTEGER PRIMARY KEY,
+                colors BLOB NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS Voxel (
+                voxel_id INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                palette_id INTEGER NOT NULL,
+                size_x INTEGER NOT NULL,
+                size_y INTEGER NOT NULL,
+                size_z INTEGER NOT NULL,
+                created_at DATETIME NOT NULL,
+                last_modified_at DATETIME NOT NULL,
+                grid BLOB NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS Place (
+                place_id INTEGER PRIMARY KEY,
+                online INTEGER NOT NULL,
+                cooldown INTEGER NOT NULL,
+                voxel_id INTEGER NOT NULL,
+                FOREIGN KEY (voxel_id) REFERENCES Voxel (id)
+            );
+            CREATE TABLE IF NOT EXISTS User (
+                user_id INTEGER PRIMARY KEY,
+                username TEXT NOT NULL UNIQUE,
+                password_hash TEXT NOT NULL,
+                email TEXT NOT NULL,
+                voxel_id INTEGER NOT NULL,
+                xp INTEGER NOT NULL DEFAULT 0,
+                created_at DATETIME NOT NULL,
+                last_connected_at DATETIME NOT NULL,
+                admin INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE IF NOT EXISTS PlaceUser (
+                place_id INTEGER NOT NULL,
+                user_id INTEGER NOT NULL,
+                x INTEGER NOT NULL,
+                y INTEGER NOT NULL,
+                z INTEGER NOT NULL,
+                PRIMARY KEY (place_id, x, y, z)
+            );
+            CREATE TABLE IF NOT EXISTS PlaceUserCooldown (
+                place_id INTEGER NOT NULL,
+                user_id INTEGER NOT NULL,
+                cooldown INTEGER NOT NULL,
+                PRIMARY KEY (place_id, user_id)
+            );
+            CREATE TABLE IF NOT EXISTS UserVoxel (
+                user_id INTEGER NOT NULL,
+                voxel_id INTEGER NOT NULL,
+                PRIMARY KEY (user_id, voxel_id)
+            );
+            CREATE TABLE IF NOT EXISTS Post (
+                id INTEGER PRIMARY KEY,
+                title TEXT NOT NULL,
+                content TEXT NOT NULL,
+                voxel_id INTEGER NOT NULL,
+                votes INTEGER NOT NULL DEFAULT 0,
+                author_id INTEGER NOT NULL,
+                updated INTEGER NOT NULL DEFAULT 0,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL,
+                FOREIGN KEY (author_id) REFERENCES user(id)
+                FOREIGN KEY (voxel_id) REFERENCES voxel(id)
+            );
+            CREATE TABLE IF NOT EXISTS Comment (
+                comment_id INTEGER PRIMARY KEY,
+                user_id INTEGER NOT NULL,
+                place_id INTEGER,
+                post_id INTEGER,
+                content TEXT NOT NULL,
+                created_at DATETIME NOT NULL,
+                FOREIGN KEY (user_id) REFERENCES User (id),
+                FOREIGN KEY (place_id) REFERENCES Place (id),
+                FOREIGN KEY (post_id) REFERENCES Post (id)
+            );
+        "),
+    },
+    Migration {
+        version: 2,
+        name: "palette_materials",
+        step: Step::Sql("
+            CREATE TABLE IF NOT EXISTS PaletteMaterial (
+                palette_id INTEGER PRIMARY KEY,
+                materials BLOB NOT NULL,
+                FOREIGN KEY (palette_id) REFERENCES Palette (palette_id)
+            );
+        "),
+    },
+    Migration {
+        version: 3,
+        name: "voxel_chunks",
+        step: Step::Sql("
+            CREATE TABLE IF NOT EXISTS VoxelChunk (
+                voxel_id INTEGER NOT NULL,
+                chunk_x INTEGER NOT NULL,
+                chunk_y INTEGER NOT NULL,
+                chunk_z INTEGER NOT NULL,
+                data BLOB NOT NULL,
+                PRIMARY KEY (voxel_id, chunk_x, chunk_y, chunk_z),
+                FOREIGN KEY (voxel_id) REFERENCES Voxel (voxel_id)
+            );
+        "),
+    },
+    // Moves grids still stored whole in the Voxel row into chunks.
+    Migration {
+        version: 4,
+        name: "legacy_grids",
+        step: Step::Code(Database::migrate_legacy_grids),
+    },
+    Migration {
+        version: 5,
+        name: "place_events",
+        step: Step::Sql("
+            CREATE TABLE IF NOT EXISTS PlaceEvent (
+                event_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                place_id INTEGER NOT NULL,
+                user_id INTEGER NOT NULL,
+                x INTEGER NOT NULL,
+                y INTEGER NOT NULL,
+                z INTEGER NOT NULL,
+                old_color INTEGER NOT NULL,
+                new_color INTEGER NOT NULL,
+                created_at DATETIME NOT NULL,
+                FOREIGN KEY (place_id) REFERENCES Place (place_id)
+            );
+            CREATE INDEX IF NOT EXISTS PlaceEventVoxel ON PlaceEvent (place_id, x, y, z);
+            CREATE INDEX IF NOT EXISTS PlaceEventUser ON PlaceEvent (place_id, user_id);
+        "),
+    },
+    Migration {
+        version: 6,
+        name: "place_rollbacks",
+        step: Step::Sql("
+            CREATE TABLE IF NOT EXISTS PlaceRollback (
+                rollback_id INTEGER PRIMARY KEY,
+                place_id INTEGER NOT NULL,
+                admin_id INTEGER NOT NULL,
+                user_id INTEGER NOT NULL,
+                from_time DATETIME,
+                to_time DATETIME,
+                min_x INTEGER,
+                min_y INTEGER,
+                min_z INTEGER,
+                max_x INTEGER,
+                max_y INTEGER,
+                max_z INTEGER,
+                reverted INTEGER NOT NULL,
+                created_at DATETIME NOT NULL,
+                FOREIGN KEY (place_id) REFERENCES Place (place_id)
+            );
+        "),
+    },
+    Migration {
+        version: 7,
+        name: "place_snapshots",
+        step: Step::Sql("
+            CREATE TABLE IF NOT EXISTS PlaceSnapshot (
+                snapshot_id INTEGER PRIMARY KEY,
+                place_id INTEGER NOT NULL,
+                version INTEGER NOT NULL,
+                kind TEXT NOT NULL,
+                size_x INTEGER NOT NULL,
+                size_y INTEGER NOT NULL,
+                size_z INTEGER NOT NULL,
+                grid BLOB NOT NULL,
+                last_event_id INTEGER NOT NULL,
+                created_at DATETIME NOT NULL,
+                UNIQUE (place_id, version),
+                FOREIGN KEY (place_id) REFERENCES Place (place_id)
+            );
+        "),
+    },
+    // The Vote table was defined but never created, so votes can't be tied to users yet.
+    Migration {
+        version: 8,
+        name: "vote",
+        step: Step::Sql("
+            CREATE TABLE Vote (
+                user_id INTEGER NOT NULL,
+                post_id INTEGER NOT NULL,
+                vote INTEGER NOT NULL,
+                PRIMARY KEY (user_id, post_id),
+                FOREIGN KEY (user_id) REFERENCES User (user_id),
+                FOREIGN KEY (post_id) REFERENCES Post (id)
+            );
+        "),
+    },
+    // Roles replace the admin flag, which is kept but no longer read. A NULL place_id grants the
+    // role on every place.
+    Migration {
+        version: 9,
+        name: "roles",
+        step: Step::Sql("
+            CREATE TABLE UserRole (
+                user_id INTEGER NOT NULL,
+                role TEXT NOT NULL,
+                place_id INTEGER,
+                FOREIGN KEY (user_id) REFERENCES User (user_id),
+                FOREIGN KEY (place_id) REFERENCES Place (place_id)
+            );
+            CREATE UNIQUE INDEX UserRoleGrant ON UserRole (user_id, role, IFNULL(place_id, 0));
+            INSERT INTO UserRole (user_id, role, place_id)
+                SELECT user_id, 'admin', NULL FROM User WHERE admin = 1;
+        "),
+    },
+    // Refresh tokens are only stored hashed. The previous hash is kept to notice a rotated token
+    // being used again.
+    Migration {
+        version: 10,
+        name: "sessions",
+        step: Step::Sql("
+            CREATE TABLE Session (
+                session_id INTEGER PRIMARY KEY,
+                user_id INTEGER NOT NULL,
+                token_hash BLOB NOT NULL UNIQUE,
+                previous_token_hash BLOB,
+                created_at DATETIME NOT NULL,
+                last_used_at DATETIME NOT NULL,
+                expires_at DATETIME NOT NULL,
+                FOREIGN KEY (user_id) REFERENCES User (user_id)
+            );
+            CREATE INDEX SessionUser ON Session (user_id);
+            CREATE INDEX SessionPreviousToken ON Session (previous_token_hash);
+        "),
+    },
+];
+
+impl Database {
+    pub fn schema_version(&self) -> Result<i64, DatabaseError> {
+        let conn = self.get_conn()?;
+        Self::create_schema_version_table(&conn)?;
+        let version = conn
+            .query_row("SELECT MAX(version) FROM schema_version", [], |row| row.get::<_, Option<i64>>(0))?
+            .unwrap_or(0);
+        Ok(version)
+    }
+
+    // Applies every migration newer than the database, each in its own transaction, and returns
+    // the ones applied.
+    pub fn migrate(&self) -> Result<Vec<&'static Migration>, DatabaseError> {
+        let current = self.schema_version()?;
+        let latest = MIGRATIONS.last().map_or(0, |migration| migration.version);
+        if current > latest {
+            return Err(DatabaseError::UnknownSchemaVersion(current));
+        }
+
+        let mut applied = Vec::new();
+        let mut conn = self.get_conn()?;
+        for migration in MIGRATIONS.iter().filter(|migration| migration.version > current) {
+            let tx = conn.transaction()?;
+            match migration.step {
+                Step::Sql(sql) => tx.execute_batch(sql)?,
+                Step::Code(apply) => apply(&tx)?,
+            }
+            tx.execute(
+                "INSERT INTO schema_version (version, name, applied_at) VALUES (?, ?, ?)",
+                params![migration.version, migration.name, Utc::now().timestamp()],
+            )?;
+            tx.commit()?;
+            applied.push(migration);
+        }
+
+        Ok(applied)
+    }
+
+    fn create_schema_version_table(conn: &Connection) -> Result<(), DatabaseError> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS schema_version (
+                version INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                applied_at DATETIME NOT NULL
+            )",
+            [],
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grid::GridLayout;
+    use super::*;
+
+    // The schema `init` created before migrations existed.
+    const INIT_SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS Palette (
+            palette_id INTEGER PRIMARY KEY,
+            colors BLOB NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS Voxel (
+            voxel_id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            palette_id INTEGER NOT NULL,
+            size_x INTEGER NOT NULL,
+            size_y INTEGER NOT NULL,
+            size_z INTEGER NOT NULL,
+            created_at DATETIME NOT NULL,
+            last_modified_at DATETIME NOT NULL,
+            grid BLOB NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS Place (
+            place_id INTEGER PRIMARY KEY,
+            online INTEGER NOT NULL,
+            cooldown INTEGER NOT NULL,
+            voxel_id INTEGER NOT NULL,
+            FOREIGN KEY (voxel_id) REFERENCES Voxel (id)
+        );
+        CREATE TABLE IF NOT EXISTS User (
+            user_id INTEGER PRIMARY KEY,
+            username TEXT NOT NULL UNIQUE,
+            password_hash TEXT NOT NULL,
+            email TEXT NOT NULL,
+            voxel_id INTEGER NOT NULL,
+            xp INTEGER NOT NULL DEFAULT 0,
+            created_at DATETIME NOT NULL,
+            last_connected_at DATETIME NOT NULL,
+            admin INTEGER NOT NULL DEFAULT 0
+        );
+        CREATE TABLE IF NOT EXISTS PlaceUser (
+            place_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            x INTEGER NOT NULL,
+            y INTEGER NOT NULL,
+            z INTEGER NOT NULL,
+            PRIMARY KEY (place_id, x, y, z)
+        );
+        CREATE TABLE IF NOT EXISTS PlaceUserCooldown (
+            place_id INTEGER NOT NULL,
+            user_id INTEGER NOT NULL,
+            cooldown INTEGER NOT NULL,
+            PRIMARY KEY (place_id, user_id)
+        );
+        CREATE TABLE IF NOT EXISTS UserVoxel (
+            user_id INTEGER NOT NULL,
+            voxel_id INTEGER NOT NULL,
+            PRIMARY KEY (user_id, voxel_id)
+        );
+        CREATE TABLE IF NOT EXISTS Post (
+            id INTEGER PRIMARY KEY,
+            title TEXT NOT NULL,
+            content TEXT NOT NULL,
+            voxel_id INTEGER NOT NULL,
+            votes INTEGER NOT NULL DEFAULT 0,
+            author_id INTEGER NOT NULL,
+            updated INTEGER NOT NULL DEFAULT 0,
+            created_at INTEGER NOT NULL,
+            updated_at INTEGER NOT NULL,
+            FOREIGN KEY (author_id) REFERENCES user(id)
+            FOREIGN KEY (voxel_id) REFERENCES voxel(id)
+        );
+        CREATE TABLE IF NOT EXISTS Comment (
+            comment_id INTEGER PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            place_id INTEGER,
+            post_id INTEGER,
+            content TEXT NOT NULL,
+            created_at DATETIME NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES User (id),
+            FOREIGN KEY (place_id) REFERENCES Place (id),
+            FOREIGN KEY (post_id) REFERENCES Post (id)
+        );
+        INSERT INTO User VALUES (1, 'root', 'hash', 'root@voxplace', 10, 0, 0, 0, 1);
+        INSERT INTO User VALUES (2, 'player', 'hash', 'player@voxplace', 11, 0, 0, 0, 0);
+        INSERT INTO User VALUES (3, 'moderator', 'hash', 'moderator@voxplace', 12, 0, 0, 0, 1);
+        INSERT INTO Place VALUES (20, 1, 60, 30);
+    ";
+
+    const LEGACY_SIZE: (usize, usize, usize) = (3, 5, 7);
+
+    // A grid saved with the legacy index: cell i holds i + 1.
+    fn fixture() -> Database {
+        let db = Database::open(":memory:").unwrap();
+        let len = LEGACY_SIZE.0 * LEGACY_SIZE.1 * LEGACY_SIZE.2;
+        let legacy: Vec<u8> = (0..len).map(|index| index as u8 + 1).collect();
+        {
+            let conn = db.get_conn().unwrap();
+            conn.execute_batch(INIT_SCHEMA).unwrap();
+            conn.execute(
+                "INSERT INTO Voxel VALUES (30, 'place', 0, ?, ?, ?, 0, 0, ?)",
+                params![LEGACY_SIZE.0, LEGACY_SIZE.1, LEGACY_SIZE.2, Database::compress_grid(&legacy).unwrap()],
+            )
+            .unwrap();
+        }
+        db
+    }
+
+    fn latest() -> i64 {
+        MIGRATIONS.last().unwrap().version
+    }
+
+    #[test]
+    fn versions_count_up_from_one() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, index as i64 + 1);
+        }
+    }
+
+    #[test]
+    fn migrates_the_init_schema() {
+        let db = fixture();
+        assert_eq!(db.schema_version().unwrap(), 0);
+
+        let applied = db.migrate().unwrap();
+        assert_eq!(applied.len(), MIGRATIONS.len());
+        assert_eq!(db.schema_version().unwrap(), latest());
+        assert_eq!(latest(), 10);
+
+        let conn = db.get_conn().unwrap();
+        let admins: Vec<(i64, Option<i64>)> = conn
+            .prepare("SELECT user_id, place_id FROM UserRole WHERE role = 'admin' ORDER BY user_id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(admins, [(1, None), (3, None)]);
+        let legacy_grids: i64 = conn
+            .query_row("SELECT COUNT(*) FROM Voxel WHERE length(grid) > 0", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(legacy_grids, 0);
+        drop(conn);
+
+        let voxel = db.get_voxel(30).unwrap();
+        let layout = GridLayout::new(LEGACY_SIZE);
+        for (x, y, z) in layout.iter() {
+            let expected = layout.legacy_index(x, y, z).map_or(0, |index| index as u8 + 1);
+            assert_eq!(voxel.get(x, y, z), expected);
+        }
+    }
+
+    #[test]
+    fn migrating_again_is_a_no_op() {
+        let db = fixture();
+        db.migrate().unwrap();
+        let chunks = |db: &Database| -> Vec<Vec<u8>> {
+            let conn = db.get_conn().unwrap();
+            let mut stmt = conn.prepare("SELECT data FROM VoxelChunk ORDER BY chunk_x, chunk_y, chunk_z").unwrap();
+            let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+            rows.collect::<Result<_, _>>().unwrap()
+        };
+        let before = chunks(&db);
+
+        assert!(db.migrate().unwrap().is_empty());
+        assert_eq!(db.schema_version().unwrap(), latest());
+        assert_eq!(chunks(&db), before);
+        let roles: i64 = db
+            .get_conn()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM UserRole", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(roles, 2);
+    }
+
+    #[test]
+    fn rejects_a_newer_schema() {
+        let db = fixture();
+        db.migrate().unwrap();
+        db.get_conn()
+            .unwrap()
+            .execute(
+                "INSERT INTO schema_version (version, name, applied_at) VALUES (?, 'future', 0)",
+                params![latest() + 1],
+            )
+            .unwrap();
+
+        match db.migrate() {
+            Err(DatabaseError::UnknownSchemaVersion(version)) => assert_eq!(version, latest() + 1),
+            _ => panic!("expected UnknownSchemaVersion"),
+        }
+    }
+}
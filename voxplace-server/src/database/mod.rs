@@ -1,7 +1,11 @@
 pub mod db;
+pub mod migration;
 pub mod place;
 pub mod user;
 pub mod voxel;
 pub mod palette;
 pub mod post;
 pub mod comment;
+pub mod snapshot;
+pub mod role;
+pub mod session;
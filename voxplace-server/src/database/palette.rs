@@ -1,20 +1,8 @@
 use rusqlite::params;
 use crate::database::db::{Database, DatabaseError};
-use crate::palette::Palette;
+use crate::palette::{Material, Palette};
 
 impl Database {
-    pub fn create_palette_table(&self) -> rusqlite::Result<(), DatabaseError> {
-        self.get_conn()?.execute(
-            "CREATE TABLE IF NOT EXISTS Palette (
-                palette_id INTEGER PRIMARY KEY,
-                colors BLOB NOT NULL
-            )",
-            [],
-        )?;
-
-        Ok(())
-    }
-
     pub fn save_new_palette(&self, palette: Palette) -> rusqlite::Result<(), DatabaseError> {
         let mut bytes: Vec<u8> = Vec::new();
         for color in palette.colors().iter() {
@@ -23,6 +11,14 @@ impl Database {
             bytes.push(color.2);
         }
 
+        let mut material_bytes: Vec<u8> = Vec::new();
+        for material in palette.materials().iter() {
+            material_bytes.push(material.alpha);
+            material_bytes.push(material.emission);
+            material_bytes.push(material.roughness);
+            material_bytes.push(material.metallic);
+        }
+
         let conn = self.get_conn()?;
         let mut stmt = conn.prepare(
             "INSERT OR REPLACE INTO Palette (
@@ -35,6 +31,17 @@ impl Database {
             bytes
         ])?;
 
+        let mut stmt = conn.prepare(
+            "INSERT OR REPLACE INTO PaletteMaterial (
+                palette_id,
+                materials
+            ) VALUES (?, ?)",
+        )?;
+        stmt.execute(params![
+            palette.palette_id(),
+            material_bytes
+        ])?;
+
         Ok(())
     }
 
@@ -52,4 +59,32 @@ impl Database {
         }
         Ok(result)
     }
-}
\ No newline at end of file
+
+    pub fn get_palette_materials(&self, palette_id: i64) -> rusqlite::Result<Vec<Material>, DatabaseError> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT materials FROM PaletteMaterial WHERE palette_id = ?",
+        )?;
+        let mut rows = stmt.query(params![palette_id])?;
+        let materials: Vec<u8> = match rows.next()? {
+            Some(row) => row.get(0)?,
+            None => return Ok(Vec::new()),
+        };
+        let mut result = Vec::new();
+        for i in 0..materials.len() / 4 {
+            result.push(Material {
+                alpha: materials[i * 4],
+                emission: materials[i * 4 + 1],
+                roughness: materials[i * 4 + 2],
+                metallic: materials[i * 4 + 3],
+            });
+        }
+        Ok(result)
+    }
+
+    pub fn get_full_palette(&self, palette_id: i64) -> rusqlite::Result<Palette, DatabaseError> {
+        let colors = self.get_palette(palette_id)?;
+        let materials = self.get_palette_materials(palette_id)?;
+        Ok(Palette::new(palette_id, Some(colors), Some(materials)))
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use crate::database::db::{Database, DatabaseError};
 use crate::place::Place;
 use rusqlite::params;
@@ -12,6 +13,58 @@ pub struct PlaceUserUpdate {
     pub place_id: i64,
 }
 
+#[derive(Debug, Serialize)]
+pub struct PlaceEvent {
+    pub event_id: String,
+    pub place_id: String,
+    pub user_id: String,
+    pub username: Option<String>,
+    pub x: usize,
+    pub y: usize,
+    pub z: usize,
+    pub old_color: u8,
+    pub new_color: u8,
+    pub created_at: i64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct VoxelChange {
+    pub user_id: i64,
+    pub x: usize,
+    pub y: usize,
+    pub z: usize,
+    pub old_color: u8,
+    pub new_color: u8,
+    pub created_at: i64,
+}
+
+pub struct PlaceRollback {
+    pub rollback_id: i64,
+    pub place_id: i64,
+    pub admin_id: i64,
+    pub user_id: i64,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub min: Option<(usize, usize, usize)>,
+    pub max: Option<(usize, usize, usize)>,
+    pub reverted: usize,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlaceRollbackInfo {
+    pub rollback_id: String,
+    pub place_id: String,
+    pub admin_id: String,
+    pub user_id: String,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub min: Option<(usize, usize, usize)>,
+    pub max: Option<(usize, usize, usize)>,
+    pub reverted: usize,
+    pub created_at: i64,
+}
+
 #[derive(Debug, Serialize)]
 pub struct PlaceInfo {
     pub place_id: String,
@@ -22,82 +75,270 @@ pub struct PlaceInfo {
     pub size_x: i64,
     pub size_y: i64,
     pub size_z: i64,
+    pub viewers: usize,
 }
 
 impl Database {
-    pub fn create_place_table(&self) -> Result<(), DatabaseError> {
-        self.get_conn()?.execute(
-            "CREATE TABLE IF NOT EXISTS Place (
-                place_id INTEGER PRIMARY KEY,
-                online INTEGER NOT NULL,
-                cooldown INTEGER NOT NULL,
-                voxel_id INTEGER NOT NULL,
-                FOREIGN KEY (voxel_id) REFERENCES Voxel (id)
-            )",
-            [],
+    pub fn get_voxel_events(
+        &self,
+        place_id: i64,
+        position: (usize, usize, usize),
+    ) -> Result<Vec<PlaceEvent>, DatabaseError> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT
+                E.event_id, E.place_id, E.user_id, U.username,
+                E.x, E.y, E.z, E.old_color, E.new_color, E.created_at
+                FROM PlaceEvent E
+                LEFT JOIN User U ON E.user_id = U.user_id
+                WHERE E.place_id = ? AND E.x = ? AND E.y = ? AND E.z = ?
+                ORDER BY E.event_id",
         )?;
+        let rows = stmt.query(params![place_id, position.0, position.1, position.2])?;
+        Self::read_place_events(rows)
+    }
 
-        Ok(())
+    pub fn get_user_events(
+        &self,
+        place_id: i64,
+        user_id: i64,
+        before: i64,
+        limit: i64,
+    ) -> Result<Vec<PlaceEvent>, DatabaseError> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT
+                E.event_id, E.place_id, E.user_id, U.username,
+                E.x, E.y, E.z, E.old_color, E.new_color, E.created_at
+                FROM PlaceEvent E
+                LEFT JOIN User U ON E.user_id = U.user_id
+                WHERE E.place_id = ? AND E.user_id = ? AND E.event_id < ?
+                ORDER BY E.event_id DESC
+                LIMIT ?",
+        )?;
+        let rows = stmt.query(params![place_id, user_id, before, limit])?;
+        Self::read_place_events(rows)
     }
 
-    pub fn create_place_user_table(&self) -> Result<(), DatabaseError> {
-        self.get_conn()?.execute(
-            "CREATE TABLE IF NOT EXISTS PlaceUser (
-                place_id INTEGER NOT NULL,
-                user_id INTEGER NOT NULL,
-                x INTEGER NOT NULL,
-                y INTEGER NOT NULL,
-                z INTEGER NOT NULL,
-                PRIMARY KEY (place_id, x, y, z)
-            )",
-            [],
+    pub fn get_last_place_event_id(&self, place_id: i64) -> Result<i64, DatabaseError> {
+        let conn = self.get_conn()?;
+        let event_id = conn.query_row(
+            "SELECT COALESCE(MAX(event_id), 0) FROM PlaceEvent WHERE place_id = ?",
+            params![place_id],
+            |row| row.get(0),
+        )?;
+        Ok(event_id)
+    }
+
+    pub fn get_place_changes(&self, place_id: i64) -> Result<Vec<VoxelChange>, DatabaseError> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT user_id, x, y, z, old_color, new_color, created_at
+                FROM PlaceEvent
+                WHERE place_id = ?
+                ORDER BY event_id",
         )?;
+        let rows = stmt.query(params![place_id])?;
+        Self::read_voxel_changes(rows)
+    }
+
+    // Every change, by anyone, to the voxels of a place the given user has ever changed.
+    pub fn get_user_voxel_changes(&self, place_id: i64, user_id: i64) -> Result<Vec<VoxelChange>, DatabaseError> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT user_id, x, y, z, old_color, new_color, created_at
+                FROM PlaceEvent
+                WHERE place_id = ?1 AND (x, y, z) IN (
+                    SELECT x, y, z FROM PlaceEvent WHERE place_id = ?1 AND user_id = ?2
+                )
+                ORDER BY event_id",
+        )?;
+        let rows = stmt.query(params![place_id, user_id])?;
+        Self::read_voxel_changes(rows)
+    }
+
+    pub fn save_place_events(&self, place_id: i64, changes: &[VoxelChange]) -> Result<(), DatabaseError> {
+        let mut conn = self.get_conn()?;
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO PlaceEvent (
+                    place_id,
+                    user_id,
+                    x,
+                    y,
+                    z,
+                    old_color,
+                    new_color,
+                    created_at
+                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            )?;
+            for change in changes {
+                stmt.execute(params![
+                    place_id,
+                    change.user_id,
+                    change.x,
+                    change.y,
+                    change.z,
+                    change.old_color,
+                    change.new_color,
+                    change.created_at,
+                ])?;
+            }
+        }
+        tx.commit()?;
 
         Ok(())
     }
 
-    pub fn create_place_user_cooldown_table(&self) -> Result<(), DatabaseError> {
-        self.get_conn()?.execute(
-            "CREATE TABLE IF NOT EXISTS PlaceUserCooldown (
-                place_id INTEGER NOT NULL,
-                user_id INTEGER NOT NULL,
-                cooldown INTEGER NOT NULL,
-                PRIMARY KEY (place_id, user_id)
-            )",
-            [],
+    fn read_voxel_changes(mut rows: rusqlite::Rows) -> Result<Vec<VoxelChange>, DatabaseError> {
+        let mut changes = Vec::new();
+        while let Some(row) = rows.next()? {
+            changes.push(VoxelChange {
+                user_id: row.get(0)?,
+                x: row.get(1)?,
+                y: row.get(2)?,
+                z: row.get(3)?,
+                old_color: row.get(4)?,
+                new_color: row.get(5)?,
+                created_at: row.get(6)?,
+            });
+        }
+        Ok(changes)
+    }
+
+    pub fn save_place_rollback(&self, rollback: &PlaceRollback) -> Result<(), DatabaseError> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "INSERT INTO PlaceRollback (
+                rollback_id,
+                place_id,
+                admin_id,
+                user_id,
+                from_time,
+                to_time,
+                min_x,
+                min_y,
+                min_z,
+                max_x,
+                max_y,
+                max_z,
+                reverted,
+                created_at
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
         )?;
+        stmt.execute(params![
+            rollback.rollback_id,
+            rollback.place_id,
+            rollback.admin_id,
+            rollback.user_id,
+            rollback.from,
+            rollback.to,
+            rollback.min.map(|min| min.0),
+            rollback.min.map(|min| min.1),
+            rollback.min.map(|min| min.2),
+            rollback.max.map(|max| max.0),
+            rollback.max.map(|max| max.1),
+            rollback.max.map(|max| max.2),
+            rollback.reverted,
+            rollback.created_at,
+        ])?;
 
         Ok(())
     }
 
-    pub fn get_user_cooldown(&self, place_id: i64, user_id: i64) -> Result<i64, DatabaseError> {
+    pub fn get_place_rollbacks(&self, place_id: i64) -> Result<Vec<PlaceRollbackInfo>, DatabaseError> {
         let conn = self.get_conn()?;
         let mut stmt = conn.prepare(
             "SELECT
-                cooldown
-                FROM PlaceUserCooldown
-                WHERE place_id = ? AND user_id = ?",
+                rollback_id, place_id, admin_id, user_id, from_time, to_time,
+                min_x, min_y, min_z, max_x, max_y, max_z, reverted, created_at
+                FROM PlaceRollback
+                WHERE place_id = ?
+                ORDER BY created_at DESC",
         )?;
-        let mut rows = stmt.query(params![place_id, user_id])?;
+        let mut rows = stmt.query(params![place_id])?;
+        let mut rollbacks = Vec::new();
+        while let Some(row) = rows.next()? {
+            let min: Option<usize> = row.get(6)?;
+            let max: Option<usize> = row.get(9)?;
+            rollbacks.push(PlaceRollbackInfo {
+                rollback_id: row.get::<_, i64>(0)?.to_string(),
+                place_id: row.get::<_, i64>(1)?.to_string(),
+                admin_id: row.get::<_, i64>(2)?.to_string(),
+                user_id: row.get::<_, i64>(3)?.to_string(),
+                from: row.get(4)?,
+                to: row.get(5)?,
+                min: match min {
+                    Some(x) => Some((x, row.get(7)?, row.get(8)?)),
+                    None => None,
+                },
+                max: match max {
+                    Some(x) => Some((x, row.get(10)?, row.get(11)?)),
+                    None => None,
+                },
+                reverted: row.get(12)?,
+                created_at: row.get(13)?,
+            });
+        }
+        Ok(rollbacks)
+    }
 
-        if let Some(row) = rows.next()? {
-            let cooldown: i64 = row.get(0)?;
-            return Ok(cooldown);
-        } else {
-            return Err(DatabaseError::NoSuchUser());
+    fn read_place_events(mut rows: rusqlite::Rows) -> Result<Vec<PlaceEvent>, DatabaseError> {
+        let mut events = Vec::new();
+        while let Some(row) = rows.next()? {
+            events.push(PlaceEvent {
+                event_id: row.get::<_, i64>(0)?.to_string(),
+                place_id: row.get::<_, i64>(1)?.to_string(),
+                user_id: row.get::<_, i64>(2)?.to_string(),
+                username: row.get(3)?,
+                x: row.get(4)?,
+                y: row.get(5)?,
+                z: row.get(6)?,
+                old_color: row.get(7)?,
+                new_color: row.get(8)?,
+                created_at: row.get(9)?,
+            });
         }
+        Ok(events)
     }
 
-    pub fn set_user_cooldown(&self, place_id: i64, user_id: i64, cooldown: i64) -> Result<(), DatabaseError> {
+    // Cooldowns still running at `time`, by user.
+    pub fn get_place_cooldowns(&self, place_id: i64, time: i64) -> Result<HashMap<i64, i64>, DatabaseError> {
         let conn = self.get_conn()?;
         let mut stmt = conn.prepare(
-            "INSERT OR REPLACE INTO PlaceUserCooldown (
-                place_id,
+            "SELECT
                 user_id,
                 cooldown
-            ) VALUES (?, ?, ?)",
+                FROM PlaceUserCooldown
+                WHERE place_id = ? AND cooldown > ?",
         )?;
-        stmt.execute(params![place_id, user_id, cooldown])?;
+        let mut rows = stmt.query(params![place_id, time])?;
+
+        let mut cooldowns = HashMap::new();
+        while let Some(row) = rows.next()? {
+            cooldowns.insert(row.get(0)?, row.get(1)?);
+        }
+
+        Ok(cooldowns)
+    }
+
+    pub fn save_user_cooldowns(&self, place_id: i64, cooldowns: &[(i64, i64)]) -> Result<(), DatabaseError> {
+        let mut conn = self.get_conn()?;
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO PlaceUserCooldown (
+                    place_id,
+                    user_id,
+                    cooldown
+                ) VALUES (?, ?, ?)",
+            )?;
+            for (user_id, cooldown) in cooldowns {
+                stmt.execute(params![place_id, user_id, cooldown])?;
+            }
+        }
+        tx.commit()?;
 
         Ok(())
     }
@@ -117,27 +358,31 @@ impl Database {
         Ok(())
     }
 
-    pub fn save_places_users(&self, updates: Vec<PlaceUserUpdate>) -> Result<(), DatabaseError> {
-        let conn = self.get_conn()?;
-        let mut stmt = conn.prepare(
-            "INSERT OR REPLACE INTO PlaceUser (
-                place_id,
-                user_id,
-                x,
-                y,
-                z
-            ) VALUES (?, ?, ?, ?, ?)",
-        )?;
+    pub fn save_places_users(&self, updates: &[PlaceUserUpdate]) -> Result<(), DatabaseError> {
+        let mut conn = self.get_conn()?;
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO PlaceUser (
+                    place_id,
+                    user_id,
+                    x,
+                    y,
+                    z
+                ) VALUES (?, ?, ?, ?, ?)",
+            )?;
 
-        for update in updates {
-            stmt.execute(params![
-                update.place_id,
-                update.user_id,
-                update.x,
-                update.y,
-                update.z,
-            ])?;
+            for update in updates {
+                stmt.execute(params![
+                    update.place_id,
+                    update.user_id,
+                    update.x,
+                    update.y,
+                    update.z,
+                ])?;
+            }
         }
+        tx.commit()?;
 
         Ok(())
     }
@@ -169,11 +414,21 @@ impl Database {
                 size_x: row.get(5)?,
                 size_y: row.get(6)?,
                 size_z: row.get(7)?,
+                viewers: 0,
             });
         }
         Ok(places)
     }
 
+    pub fn place_exists(&self, place_id: i64) -> Result<bool, DatabaseError> {
+        let conn = self.get_conn()?;
+        let exists = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM Place WHERE place_id = ?)",
+            params![place_id],
+            |row| row.get(0),
+        )?;
+        Ok(exists)
+    }
 
     pub fn get_place_user(&self, place_id: i64, x: i64, y: i64, z: i64) -> Result<i64, DatabaseError> {
         let conn = self.get_conn()?;
@@ -15,41 +15,6 @@ pub struct PostInfo {
 }
 
 impl Database {
-    pub fn create_post_table(&self) -> Result<(), DatabaseError> {
-        self.get_conn()?.execute(
-            "CREATE TABLE IF NOT EXISTS Post (
-                id INTEGER PRIMARY KEY,
-                title TEXT NOT NULL,
-                content TEXT NOT NULL,
-                voxel_id INTEGER NOT NULL,
-                votes INTEGER NOT NULL DEFAULT 0,
-                author_id INTEGER NOT NULL,
-                updated INTEGER NOT NULL DEFAULT 0,
-                created_at INTEGER NOT NULL,
-                updated_at INTEGER NOT NULL,
-                FOREIGN KEY (author_id) REFERENCES user(id)
-                FOREIGN KEY (voxel_id) REFERENCES voxel(id)
-            )",
-            [],
-        )?;
-        Ok(())
-    }
-
-    pub fn create_vote_table(&self) -> Result<(), DatabaseError> {
-        self.get_conn()?.execute(
-            "CREATE TABLE IF NOT EXISTS Vote (
-                user_id INTEGER NOT NULL,
-                post_id INTEGER NOT NULL,
-                vote INTEGER NOT NULL,
-                PRIMARY KEY (user_id, post_id),
-                FOREIGN KEY (user_id) REFERENCES user(id),
-                FOREIGN KEY (post_id) REFERENCES post(id)
-            )",
-            [],
-        )?;
-        Ok(())
-    }
-
     pub fn save_new_post(&self, post: Post) -> Result<(), DatabaseError> {
         let conn = self.get_conn()?;
         let mut stmt = conn.prepare(
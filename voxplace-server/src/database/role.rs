@@ -0,0 +1,68 @@
+use rusqlite::params;
+use serde_derive::Serialize;
+use crate::database::db::{Database, DatabaseError};
+use crate::role::Role;
+
+#[derive(Serialize)]
+pub struct RoleGrant {
+    pub role: Role,
+    pub place_id: Option<String>,
+}
+
+impl Database {
+    pub fn get_user_roles(&self, user_id: i64) -> Result<Vec<RoleGrant>, DatabaseError> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare("SELECT role, place_id FROM UserRole WHERE user_id = ?")?;
+        let mut rows = stmt.query(params![user_id])?;
+
+        let mut grants = Vec::new();
+        while let Some(row) = rows.next()? {
+            if let Some(role) = Role::parse(&row.get::<_, String>(0)?) {
+                grants.push(RoleGrant {
+                    role,
+                    place_id: row.get::<_, Option<i64>>(1)?.map(|id| id.to_string()),
+                });
+            }
+        }
+
+        Ok(grants)
+    }
+
+    // The highest role the user holds on the place, counting roles held on every place. Without
+    // a place, only the latter count.
+    pub fn get_role(&self, user_id: i64, place_id: Option<i64>) -> Result<Role, DatabaseError> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT role FROM UserRole WHERE user_id = ? AND (place_id IS NULL OR place_id = ?)",
+        )?;
+        let mut rows = stmt.query(params![user_id, place_id])?;
+
+        let mut role = Role::User;
+        while let Some(row) = rows.next()? {
+            if let Some(granted) = Role::parse(&row.get::<_, String>(0)?) {
+                role = role.max(granted);
+            }
+        }
+
+        Ok(role)
+    }
+
+    pub fn grant_role(&self, user_id: i64, role: Role, place_id: Option<i64>) -> Result<(), DatabaseError> {
+        let conn = self.get_conn()?;
+        conn.execute(
+            "INSERT OR IGNORE INTO UserRole (user_id, role, place_id) VALUES (?, ?, ?)",
+            params![user_id, role.as_str(), place_id],
+        )?;
+        Ok(())
+    }
+
+    // Returns whether the user had the role.
+    pub fn revoke_role(&self, user_id: i64, role: Role, place_id: Option<i64>) -> Result<bool, DatabaseError> {
+        let conn = self.get_conn()?;
+        let removed = conn.execute(
+            "DELETE FROM UserRole WHERE user_id = ? AND role = ? AND place_id IS ?",
+            params![user_id, role.as_str(), place_id],
+        )?;
+        Ok(removed > 0)
+    }
+}
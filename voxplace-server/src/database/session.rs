@@ -0,0 +1,79 @@
+use rusqlite::{params, OptionalExtension};
+use crate::database::db::{Database, DatabaseError};
+
+impl Database {
+    pub fn save_new_session(
+        &self,
+        session_id: i64,
+        user_id: i64,
+        token_hash: &[u8],
+        created_at: i64,
+        expires_at: i64,
+    ) -> Result<(), DatabaseError> {
+        let conn = self.get_conn()?;
+        conn.execute("DELETE FROM Session WHERE expires_at <= ?", params![created_at])?;
+        conn.execute(
+            "INSERT INTO Session (session_id, user_id, token_hash, created_at, last_used_at, expires_at)
+             VALUES (?, ?, ?, ?, ?, ?)",
+            params![session_id, user_id, token_hash, created_at, created_at, expires_at],
+        )?;
+        Ok(())
+    }
+
+    // Swaps the session's refresh token for a new one and returns its user, or None if the token
+    // is unknown or expired. A token that was already swapped means it has leaked, so its session
+    // is ended.
+    pub fn rotate_session(
+        &self,
+        token_hash: &[u8],
+        new_token_hash: &[u8],
+        time: i64,
+        expires_at: i64,
+    ) -> Result<Option<i64>, DatabaseError> {
+        let mut conn = self.get_conn()?;
+        let tx = conn.transaction()?;
+
+        let session = tx
+            .query_row(
+                "SELECT session_id, user_id, expires_at FROM Session WHERE token_hash = ?",
+                params![token_hash],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?)),
+            )
+            .optional()?;
+
+        let user_id = match session {
+            Some((session_id, user_id, session_expires_at)) if session_expires_at > time => {
+                tx.execute(
+                    "UPDATE Session
+                     SET previous_token_hash = token_hash, token_hash = ?, last_used_at = ?, expires_at = ?
+                     WHERE session_id = ?",
+                    params![new_token_hash, time, expires_at, session_id],
+                )?;
+                Some(user_id)
+            }
+            Some((session_id, _, _)) => {
+                tx.execute("DELETE FROM Session WHERE session_id = ?", params![session_id])?;
+                None
+            }
+            None => {
+                tx.execute("DELETE FROM Session WHERE previous_token_hash = ?", params![token_hash])?;
+                None
+            }
+        };
+
+        tx.commit()?;
+        Ok(user_id)
+    }
+
+    pub fn delete_session(&self, token_hash: &[u8]) -> Result<(), DatabaseError> {
+        let conn = self.get_conn()?;
+        conn.execute("DELETE FROM Session WHERE token_hash = ?", params![token_hash])?;
+        Ok(())
+    }
+
+    pub fn delete_user_sessions(&self, user_id: i64) -> Result<(), DatabaseError> {
+        let conn = self.get_conn()?;
+        conn.execute("DELETE FROM Session WHERE user_id = ?", params![user_id])?;
+        Ok(())
+    }
+}
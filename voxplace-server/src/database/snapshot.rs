@@ -0,0 +1,184 @@
+use rusqlite::params;
+use serde_derive::Serialize;
+use crate::database::db::{Database, DatabaseError};
+
+pub struct PlaceSnapshot {
+    pub snapshot_id: i64,
+    pub place_id: i64,
+    pub kind: String,
+    pub grid_size: (usize, usize, usize),
+    pub grid: Vec<u8>,
+    pub last_event_id: i64,
+    pub created_at: i64,
+}
+
+#[derive(Serialize)]
+pub struct PlaceSnapshotInfo {
+    pub snapshot_id: String,
+    pub place_id: String,
+    pub version: i64,
+    pub kind: String,
+    pub last_event_id: String,
+    pub created_at: i64,
+}
+
+impl Database {
+    // Versions count up per place; the grid is stored as a gzipped flat grid.
+    pub fn save_place_snapshot(&self, snapshot: &PlaceSnapshot) -> Result<(), DatabaseError> {
+        let grid = Self::compress_grid(&snapshot.grid)?;
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "INSERT INTO PlaceSnapshot (
+                snapshot_id,
+                place_id,
+                version,
+                kind,
+                size_x,
+                size_y,
+                size_z,
+                grid,
+                last_event_id,
+                created_at
+            ) VALUES (
+                ?1, ?2,
+                (SELECT COALESCE(MAX(version), 0) + 1 FROM PlaceSnapshot WHERE place_id = ?2),
+                ?3, ?4, ?5, ?6, ?7, ?8, ?9
+            )",
+        )?;
+        stmt.execute(params![
+            snapshot.snapshot_id,
+            snapshot.place_id,
+            snapshot.kind,
+            snapshot.grid_size.0,
+            snapshot.grid_size.1,
+            snapshot.grid_size.2,
+            grid,
+            snapshot.last_event_id,
+            snapshot.created_at,
+        ])?;
+
+        Ok(())
+    }
+
+    pub fn get_place_snapshots(&self, place_id: i64) -> Result<Vec<PlaceSnapshotInfo>, DatabaseError> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT snapshot_id, place_id, version, kind, last_event_id, created_at
+                FROM PlaceSnapshot
+                WHERE place_id = ?
+                ORDER BY version DESC",
+        )?;
+        let mut rows = stmt.query(params![place_id])?;
+        let mut snapshots = Vec::new();
+        while let Some(row) = rows.next()? {
+            snapshots.push(PlaceSnapshotInfo {
+                snapshot_id: row.get::<_, i64>(0)?.to_string(),
+                place_id: row.get::<_, i64>(1)?.to_string(),
+                version: row.get(2)?,
+                kind: row.get(3)?,
+                last_event_id: row.get::<_, i64>(4)?.to_string(),
+                created_at: row.get(5)?,
+            });
+        }
+        Ok(snapshots)
+    }
+
+    pub fn get_place_snapshot(&self, place_id: i64, snapshot_id: i64) -> Result<PlaceSnapshot, DatabaseError> {
+        let conn = self.get_conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT kind, size_x, size_y, size_z, grid, last_event_id, created_at
+                FROM PlaceSnapshot
+                WHERE place_id = ? AND snapshot_id = ?",
+        )?;
+        let mut rows = stmt.query(params![place_id, snapshot_id])?;
+
+        if let Some(row) = rows.next()? {
+            let grid: Vec<u8> = row.get(4)?;
+            Ok(PlaceSnapshot {
+                snapshot_id,
+                place_id,
+                kind: row.get(0)?,
+                grid_size: (row.get(1)?, row.get(2)?, row.get(3)?),
+                grid: Self::decompress_grid(&grid)?,
+                last_event_id: row.get(5)?,
+                created_at: row.get(6)?,
+            })
+        } else {
+            Err(DatabaseError::NoSuchSnapshot())
+        }
+    }
+
+    pub fn get_last_snapshot_event_id(&self, place_id: i64) -> Result<Option<i64>, DatabaseError> {
+        let conn = self.get_conn()?;
+        let event_id = conn.query_row(
+            "SELECT MAX(last_event_id) FROM PlaceSnapshot WHERE place_id = ?",
+            params![place_id],
+            |row| row.get(0),
+        )?;
+        Ok(event_id)
+    }
+
+    // Keeps the `keep` most recent snapshots of the given kind for a place, and `keep_id` whatever
+    // its age.
+    pub fn prune_place_snapshots(
+        &self,
+        place_id: i64,
+        kind: &str,
+        keep: usize,
+        keep_id: Option<i64>,
+    ) -> Result<usize, DatabaseError> {
+        let conn = self.get_conn()?;
+        let deleted = conn.execute(
+            "DELETE FROM PlaceSnapshot
+                WHERE place_id = ?1 AND kind = ?2 AND snapshot_id IS NOT ?4 AND snapshot_id NOT IN (
+                    SELECT snapshot_id FROM PlaceSnapshot
+                    WHERE place_id = ?1 AND kind = ?2
+                    ORDER BY version DESC
+                    LIMIT ?3
+                )",
+            params![place_id, kind, keep, keep_id],
+        )?;
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn save(db: &Database, snapshot_id: i64, kind: &str) {
+        let snapshot = PlaceSnapshot {
+            snapshot_id,
+            place_id: 1,
+            kind: kind.to_string(),
+            grid_size: (1, 1, 1),
+            grid: vec![0],
+            last_event_id: 0,
+            created_at: 0,
+        };
+        db.save_place_snapshot(&snapshot).unwrap();
+    }
+
+    fn ids(db: &Database) -> Vec<String> {
+        let mut ids: Vec<_> = db.get_place_snapshots(1).unwrap().into_iter().map(|s| s.snapshot_id).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn prunes_per_kind_and_spares_keep_id() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+
+        save(&db, 1, "manual");
+        save(&db, 2, "restore");
+        save(&db, 3, "restore");
+        save(&db, 4, "restore");
+
+        assert_eq!(db.prune_place_snapshots(1, "restore", 1, Some(2)).unwrap(), 1);
+        assert_eq!(ids(&db), ["1", "2", "4"]);
+
+        assert_eq!(db.prune_place_snapshots(1, "restore", 1, None).unwrap(), 1);
+        assert_eq!(ids(&db), ["1", "4"]);
+    }
+}
@@ -26,25 +26,6 @@ pub struct FullUserProfile {
 }
 
 impl Database {
-    pub fn create_user_table(&self) -> Result<(), DatabaseError> {
-        self.get_conn()?.execute(
-            "CREATE TABLE IF NOT EXISTS User (
-                user_id INTEGER PRIMARY KEY,
-                username TEXT NOT NULL UNIQUE,
-                password_hash TEXT NOT NULL,
-                email TEXT NOT NULL,
-                voxel_id INTEGER NOT NULL,
-                xp INTEGER NOT NULL DEFAULT 0,
-                created_at DATETIME NOT NULL,
-                last_connected_at DATETIME NOT NULL,
-                admin INTEGER NOT NULL DEFAULT 0
-            )",
-            [],
-        )?;
-
-        Ok(())
-    }
-
     pub fn register_user(
         &self,
         user: User,
@@ -93,20 +74,6 @@ impl Database {
         }
     }
 
-    pub fn is_admin(&self, user_id: i64) -> Result<bool, DatabaseError> {
-        let conn = self.get_conn()?;
-        let mut stmt = conn.prepare("SELECT admin FROM User WHERE user_id = ?")?;
-        let mut rows = stmt.query(params![user_id])?;
-
-        if let Some(row) = rows.next()? {
-            let admin: i64 = row.get(0)?;
-
-            Ok(admin == 1)
-        } else {
-            Err(DatabaseError::NoSuchUser())
-        }
-    }
-
     pub fn update_username(&self, user_id: i64, new_username: &str) -> Result<(), DatabaseError> {
         let conn = self.get_conn()?;
         let mut stmt = conn.prepare("UPDATE User SET username = ? WHERE user_id = ?")?;
@@ -1,10 +1,10 @@
 use std::io::{Read, Write};
-use crossbeam::atomic::AtomicCell;
 use flate2::Compression;
 use flate2::write::GzEncoder;
 use crate::database::db::{Database, DatabaseError};
+use crate::grid::{ChunkCoords, ChunkedGrid, GridLayout};
 use crate::voxel::Voxel;
-use rusqlite::params;
+use rusqlite::{params, Connection};
 use serde_derive::Serialize;
 
 #[derive(Serialize)]
@@ -15,38 +15,6 @@ pub struct UserVoxel {
 }
 
 impl Database {
-    pub fn create_voxel_table(&self) -> rusqlite::Result<(), DatabaseError> {
-        self.get_conn()?.execute(
-            "CREATE TABLE IF NOT EXISTS Voxel (
-                voxel_id INTEGER PRIMARY KEY,
-                name TEXT NOT NULL,
-                palette_id INTEGER NOT NULL,
-                size_x INTEGER NOT NULL,
-                size_y INTEGER NOT NULL,
-                size_z INTEGER NOT NULL,
-                created_at DATETIME NOT NULL,
-                last_modified_at DATETIME NOT NULL,
-                grid BLOB NOT NULL
-            )",
-            [],
-        )?;
-
-        Ok(())
-    }
-
-    pub fn create_user_voxel_table(&self) -> rusqlite::Result<(), DatabaseError> {
-        self.get_conn()?.execute(
-            "CREATE TABLE IF NOT EXISTS UserVoxel (
-                user_id INTEGER NOT NULL,
-                voxel_id INTEGER NOT NULL,
-                PRIMARY KEY (user_id, voxel_id)
-            )",
-            [],
-        )?;
-
-        Ok(())
-    }
-
     pub fn save_new_user_voxel(&self, user_id: i64, voxel_id: i64) -> rusqlite::Result<(), DatabaseError> {
         let conn = self.get_conn()?;
         let mut stmt = conn.prepare(
@@ -59,6 +27,18 @@ impl Database {
         Ok(())
     }
 
+    // Voxels created or imported by the user, and their profile voxel.
+    pub fn user_owns_voxel(&self, user_id: i64, voxel_id: i64) -> rusqlite::Result<bool, DatabaseError> {
+        let conn = self.get_conn()?;
+        let owned = conn.query_row(
+            "SELECT EXISTS (SELECT 1 FROM UserVoxel WHERE user_id = ?1 AND voxel_id = ?2)
+                OR EXISTS (SELECT 1 FROM User WHERE user_id = ?1 AND voxel_id = ?2)",
+            params![user_id, voxel_id],
+            |row| row.get(0),
+        )?;
+        Ok(owned)
+    }
+
     pub fn get_user_voxels(&self, user_id: i64) -> rusqlite::Result<Vec<UserVoxel>, DatabaseError> {
         let conn = self.get_conn()?;
         let mut stmt = conn.prepare(
@@ -87,15 +67,17 @@ impl Database {
             .unwrap_or(std::time::Duration::new(0, 0))
             .as_secs() as i64;
 
-        let mut bytes = Vec::new();
-        let grid: Vec<u8> = voxel_object.grid.iter().map(|cell| cell.load()).collect();
-        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-        encoder.write_all(&grid).expect("Failed to write data");
-        let compressed_data = encoder.finish().expect("Failed to finish compression");
-        bytes.extend(compressed_data);
+        let mut chunks = Vec::new();
+        for (coords, chunk) in voxel_object.grid.chunks() {
+            chunk.take_dirty();
+            if let Some(bytes) = chunk.to_bytes() {
+                chunks.push((coords, Self::compress_grid(&bytes)?));
+            }
+        }
 
-        let conn = self.get_conn()?;
-        let mut stmt = conn.prepare(
+        let mut conn = self.get_conn()?;
+        let tx = conn.transaction()?;
+        tx.execute(
             "INSERT OR REPLACE INTO Voxel (
                 voxel_id,
                 name,
@@ -106,19 +88,22 @@ impl Database {
                 created_at,
                 last_modified_at,
                 grid
-            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, X'')",
+            params![
+                voxel_object.id,
+                voxel_object.name,
+                voxel_object.palette_id,
+                voxel_object.grid_size.0,
+                voxel_object.grid_size.1,
+                voxel_object.grid_size.2,
+                timestamp,
+                timestamp,
+            ],
         )?;
-        stmt.execute(params![
-            voxel_object.id,
-            voxel_object.name,
-            voxel_object.palette_id,
-            voxel_object.grid_size.0,
-            voxel_object.grid_size.1,
-            voxel_object.grid_size.2,
-            timestamp,
-            timestamp,
-            bytes
-        ])?;
+        tx.execute("DELETE FROM VoxelChunk WHERE voxel_id = ?", params![voxel_object.id])?;
+        Self::insert_chunks(&tx, voxel_object.id, chunks)?;
+        tx.commit()?;
+
         Ok(())
     }
 
@@ -132,12 +117,11 @@ impl Database {
                 size_x,
                 size_y,
                 size_z,
-                grid,
                 created_at,
                 last_modified_at
                 FROM Voxel WHERE voxel_id = ?1",
         )?;
-        let row: (_, _, _, _, _, _, _, _, _) = stmt.query_row(params![id], |row| {
+        let row: (_, _, _, _, _, _, _, _) = stmt.query_row(params![id], |row| {
             Ok((
                 row.get(0)?,
                 row.get::<_, String>(1)?,
@@ -145,48 +129,186 @@ impl Database {
                 row.get(3)?,
                 row.get(4)?,
                 row.get(5)?,
-                row.get::<_, Vec<u8>>(6)?,
+                row.get(6)?,
                 row.get(7)?,
-                row.get(8)?,
             ))
         })?;
 
-        let compressed_grid = row.6;
-        let grid: Vec<AtomicCell<u8>> = Self::decompress_grid(&compressed_grid)?
-            .into_iter()
-            .map(AtomicCell::new)
-            .collect();
-        let grid = Some(grid);
+        let grid_size = (row.3, row.4, row.5);
+
+        // The legacy_grids migration moved every grid still in the Voxel row to chunks.
+        let grid = ChunkedGrid::new(grid_size);
+        let mut stmt = conn.prepare(
+            "SELECT chunk_x, chunk_y, chunk_z, data FROM VoxelChunk WHERE voxel_id = ?1",
+        )?;
+        let mut rows = stmt.query(params![id])?;
+        while let Some(row) = rows.next()? {
+            let coords = (row.get(0)?, row.get(1)?, row.get(2)?);
+            let data: Vec<u8> = row.get(3)?;
+            if let Some(chunk) = grid.chunk(coords) {
+                chunk.load_bytes(&Self::decompress_grid(&data)?);
+            }
+        }
 
         Ok(Voxel::new(
             row.0,
             &row.1,
             row.2,
-            (row.3, row.4, row.5),
-            grid,
+            grid_size,
+            Some(grid),
+            row.6,
             row.7,
-            row.8,
         ))
     }
 
+    pub fn save_voxel_chunks(
+        &self,
+        id: i64,
+        chunks: Vec<(ChunkCoords, Option<Vec<u8>>)>,
+    ) -> rusqlite::Result<(), DatabaseError> {
+        let mut compressed_chunks = Vec::new();
+        let mut empty_chunks = Vec::new();
+        for (coords, bytes) in chunks {
+            match bytes {
+                Some(bytes) => compressed_chunks.push((coords, Self::compress_grid(&bytes)?)),
+                None => empty_chunks.push(coords),
+            }
+        }
+
+        let timestamp = chrono::Utc::now().timestamp();
+        let mut conn = self.get_conn()?;
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "DELETE FROM VoxelChunk WHERE voxel_id = ? AND chunk_x = ? AND chunk_y = ? AND chunk_z = ?",
+            )?;
+            for (x, y, z) in empty_chunks {
+                stmt.execute(params![id, x, y, z])?;
+            }
+        }
+        Self::insert_chunks(&tx, id, compressed_chunks)?;
+        tx.execute(
+            "UPDATE Voxel SET grid = X'', last_modified_at = ?1 WHERE voxel_id = ?2",
+            params![timestamp, id],
+        )?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
     pub fn save_voxel_grid(&self, id: i64, grid: Vec<u8>) -> rusqlite::Result<(), DatabaseError> {
-        let compressed_data = Self::compress_grid(&grid)?;
+        let grid_size: (usize, usize, usize) = self.get_conn()?.query_row(
+            "SELECT size_x, size_y, size_z FROM Voxel WHERE voxel_id = ?1",
+            params![id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+
+        let len = GridLayout::new(grid_size).len();
+        if grid.len() != len {
+            return Err(DatabaseError::InvalidGridSize(len));
+        }
+
+        let grid = ChunkedGrid::from_bytes(grid_size, &grid);
+        let chunks = grid
+            .chunks()
+            .map(|(coords, chunk)| (coords, chunk.to_bytes()))
+            .collect();
+
+        self.save_voxel_chunks(id, chunks)
+    }
+
+    // Migration: grids stored whole in the Voxel row were written with the legacy index, which
+    // scrambles non-cubic voxels. They are decoded with it, rewritten as chunks and cleared from
+    // the row. One grid is held in memory at a time.
+    pub fn migrate_legacy_grids(conn: &Connection) -> rusqlite::Result<(), DatabaseError> {
+        let ids = conn
+            .prepare("SELECT voxel_id FROM Voxel WHERE length(grid) > 0")?
+            .query_map([], |row| row.get::<_, i64>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for id in ids {
+            let (grid_size, data): ((usize, usize, usize), Vec<u8>) = conn.query_row(
+                "SELECT size_x, size_y, size_z, grid FROM Voxel WHERE voxel_id = ?",
+                params![id],
+                |row| Ok(((row.get(0)?, row.get(1)?, row.get(2)?), row.get(3)?)),
+            )?;
+
+            let grid = ChunkedGrid::from_legacy_bytes(grid_size, &Self::decompress_grid(&data)?);
+            let mut chunks = Vec::new();
+            for (coords, chunk) in grid.chunks() {
+                if let Some(bytes) = chunk.to_bytes() {
+                    chunks.push((coords, Self::compress_grid(&bytes)?));
+                }
+            }
+
+            conn.execute("DELETE FROM VoxelChunk WHERE voxel_id = ?", params![id])?;
+            Self::insert_chunks(conn, id, chunks)?;
+            conn.execute("UPDATE Voxel SET grid = X'' WHERE voxel_id = ?", params![id])?;
+        }
+
+        Ok(())
+    }
+
+    pub fn get_voxel_last_modified_at(&self, id: i64) -> rusqlite::Result<i64, DatabaseError> {
         let conn = self.get_conn()?;
-        conn.prepare("UPDATE Voxel SET grid = ?1 WHERE voxel_id = ?2")?
-            .execute(params![compressed_data, id])?;
+        let last_modified_at = conn
+            .prepare("SELECT last_modified_at FROM Voxel WHERE voxel_id = ?1")?
+            .query_row(params![id], |row| row.get(0))?;
+        Ok(last_modified_at)
+    }
+
+    fn insert_chunks(
+        conn: &Connection,
+        id: i64,
+        chunks: Vec<(ChunkCoords, Vec<u8>)>,
+    ) -> rusqlite::Result<(), DatabaseError> {
+        let mut stmt = conn.prepare(
+            "INSERT OR REPLACE INTO VoxelChunk (
+                voxel_id,
+                chunk_x,
+                chunk_y,
+                chunk_z,
+                data
+            ) VALUES (?, ?, ?, ?, ?)",
+        )?;
+        for ((x, y, z), data) in chunks {
+            stmt.execute(params![id, x, y, z, data])?;
+        }
         Ok(())
     }
 
-    fn compress_grid(grid: &[u8]) -> std::io::Result<Vec<u8>> {
+    pub fn compress_grid(grid: &[u8]) -> std::io::Result<Vec<u8>> {
         let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
         encoder.write_all(grid)?;
         encoder.finish()
     }
 
-    fn decompress_grid(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    pub fn decompress_grid(data: &[u8]) -> std::io::Result<Vec<u8>> {
         let mut decoder = flate2::read::GzDecoder::new(data);
         let mut grid = Vec::new();
         decoder.read_to_end(&mut grid)?;
         Ok(grid)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_voxel_grid_checks_the_length() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+        let voxel = Voxel::new(1, "voxel", 0, (2, 3, 4), Some(ChunkedGrid::new((2, 3, 4))), None, None);
+        db.save_new_voxel(&voxel).unwrap();
+
+        for len in [0, 23, 25] {
+            assert!(matches!(db.save_voxel_grid(1, vec![1; len]), Err(DatabaseError::InvalidGridSize(24))));
+        }
+        assert_eq!(db.get_voxel(1).unwrap().get_grid_bytes(), vec![0; 24]);
+
+        let grid: Vec<u8> = (0..24).collect();
+        db.save_voxel_grid(1, grid.clone()).unwrap();
+        assert_eq!(db.get_voxel(1).unwrap().get_grid_bytes(), grid);
+    }
+}
@@ -0,0 +1,140 @@
+use std::sync::PoisonError;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use rusqlite::ErrorCode;
+use serde_derive::Serialize;
+use serde_json::{json, Value};
+use thiserror::Error;
+use crate::database::db::DatabaseError;
+use crate::place::DrawError;
+
+#[derive(Error, Debug)]
+pub enum ApiError {
+    #[error("{0}")]
+    BadRequest(String),
+
+    #[error("{0} size must be between 1 and {1}")]
+    InvalidSize(&'static str, usize),
+
+    #[error("{0}")]
+    Unauthorized(String),
+
+    #[error("{0}")]
+    Forbidden(String),
+
+    #[error("{0}")]
+    NotFound(String),
+
+    #[error("{0}")]
+    Conflict(String),
+
+    #[error("Cooldown not finished")]
+    Cooldown(i64),
+
+    #[error("{0}")]
+    Internal(String),
+
+    #[error(transparent)]
+    Database(#[from] DatabaseError),
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<Value>,
+}
+
+impl ApiError {
+    fn kind(&self) -> (StatusCode, &'static str) {
+        match self {
+            ApiError::BadRequest(_) => (StatusCode::BAD_REQUEST, "bad_request"),
+            ApiError::InvalidSize(_, _) => (StatusCode::BAD_REQUEST, "invalid_size"),
+            ApiError::Unauthorized(_) => (StatusCode::UNAUTHORIZED, "unauthorized"),
+            ApiError::Forbidden(_) => (StatusCode::FORBIDDEN, "forbidden"),
+            ApiError::NotFound(_) => (StatusCode::NOT_FOUND, "not_found"),
+            ApiError::Conflict(_) => (StatusCode::CONFLICT, "conflict"),
+            ApiError::Cooldown(_) => (StatusCode::TOO_MANY_REQUESTS, "cooldown"),
+            ApiError::Internal(_) => (StatusCode::INTERNAL_SERVER_ERROR, "internal"),
+            ApiError::Database(e) => match e {
+                DatabaseError::NoSuchUser() => (StatusCode::NOT_FOUND, "user_not_found"),
+                DatabaseError::NoSuchPalette() => (StatusCode::NOT_FOUND, "palette_not_found"),
+                DatabaseError::NoSuchPost() => (StatusCode::NOT_FOUND, "post_not_found"),
+                DatabaseError::NoSuchSnapshot() => (StatusCode::NOT_FOUND, "snapshot_not_found"),
+                DatabaseError::NoSuchComment() => (StatusCode::NOT_FOUND, "comment_not_found"),
+                DatabaseError::InvalidPassword(_) => (StatusCode::UNAUTHORIZED, "invalid_credentials"),
+                DatabaseError::InvalidVote() => (StatusCode::BAD_REQUEST, "invalid_vote"),
+                DatabaseError::InvalidGridSize(_) => (StatusCode::BAD_REQUEST, "invalid_grid_size"),
+                DatabaseError::DatabaseError(rusqlite::Error::QueryReturnedNoRows) => {
+                    (StatusCode::NOT_FOUND, "not_found")
+                }
+                DatabaseError::DatabaseError(e) if is_constraint_violation(e) => {
+                    (StatusCode::CONFLICT, "conflict")
+                }
+                _ => (StatusCode::INTERNAL_SERVER_ERROR, "database_error"),
+            },
+        }
+    }
+
+    fn details(&self) -> Option<Value> {
+        match self {
+            ApiError::InvalidSize(_, max) => Some(json!({ "max": max })),
+            ApiError::Cooldown(cooldown) => Some(json!({ "cooldown": cooldown })),
+            _ => None,
+        }
+    }
+
+    // Database messages can carry SQL, so only the ones a client can act on are sent back.
+    fn message(&self) -> String {
+        match self {
+            ApiError::Database(DatabaseError::DatabaseError(rusqlite::Error::QueryReturnedNoRows)) => {
+                "Not found".to_string()
+            }
+            ApiError::Database(DatabaseError::DatabaseError(e)) if is_constraint_violation(e) => {
+                "Already exists".to_string()
+            }
+            ApiError::Database(_) if self.status_code().is_server_error() => "Database error".to_string(),
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.kind().0
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let (status, code) = self.kind();
+        if status.is_server_error() {
+            eprintln!("{}", self);
+        }
+
+        HttpResponse::build(status).json(ErrorBody {
+            code,
+            message: self.message(),
+            details: self.details(),
+        })
+    }
+}
+
+// A poisoned lock means a handler panicked while holding it; there is nothing the client can do.
+impl<T> From<PoisonError<T>> for ApiError {
+    fn from(_: PoisonError<T>) -> Self {
+        ApiError::Internal("Failed to lock state".to_string())
+    }
+}
+
+impl From<DrawError> for ApiError {
+    fn from(e: DrawError) -> Self {
+        match e {
+            DrawError::Cooldown(cooldown) => ApiError::Cooldown(cooldown),
+            DrawError::Rejected(message) => ApiError::BadRequest(message),
+        }
+    }
+}
+
+fn is_constraint_violation(e: &rusqlite::Error) -> bool {
+    matches!(e.sqlite_error_code(), Some(ErrorCode::ConstraintViolation))
+}
@@ -0,0 +1,162 @@
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use actix_web::web::{self, Data};
+use crate::app_state::AppState;
+use crate::database::db::Database;
+use crate::place::Place;
+
+#[derive(Clone, Copy, Debug)]
+pub struct FlushIntervals {
+    pub flush: Duration,
+    pub grid: Duration,
+}
+
+// Writes what draws queued on the place: events, cooldowns and PlaceUser rows, plus the dirty
+// chunks when `save_grid` is set. Each batch is saved in one transaction and queued again if it
+// fails, to be retried on the next flush. Callers lock the place before the database, like every
+// other path holding both.
+pub fn flush_place(db: &Database, place: &Place, save_grid: bool) {
+    let events = place.take_events();
+    if !events.is_empty() {
+        if let Err(e) = db.save_place_events(place.id, &events) {
+            eprintln!("Failed to save place events: {}", e);
+            place.requeue_events(events);
+        }
+    }
+
+    let cooldowns = place.take_cooldowns();
+    if !cooldowns.is_empty() {
+        if let Err(e) = db.save_user_cooldowns(place.id, &cooldowns) {
+            eprintln!("Failed to save cooldowns: {}", e);
+            place.requeue_cooldowns(cooldowns);
+        }
+    }
+
+    let updates = place.get_place_updates();
+    if !updates.is_empty() {
+        if let Err(e) = db.save_places_users(&updates) {
+            eprintln!("Failed to save updates: {}", e);
+            place.requeue_place_updates(updates);
+        }
+    }
+
+    if !save_grid {
+        return;
+    }
+
+    let chunks = place.voxel.grid.take_dirty_chunks();
+    if chunks.is_empty() {
+        return;
+    }
+    let coords: Vec<_> = chunks.iter().map(|(coords, _)| *coords).collect();
+
+    if let Err(e) = db.save_voxel_chunks(place.voxel.id, chunks) {
+        eprintln!("Failed to save voxel chunks: {}", e);
+        for coords in coords {
+            if let Some(chunk) = place.voxel.grid.chunk(coords) {
+                chunk.mark_dirty();
+            }
+        }
+    }
+}
+
+pub fn flush_places(database: &Mutex<Database>, places: &[Arc<RwLock<Place>>], save_grid: bool) {
+    for place in places {
+        let place = place.read().unwrap();
+        let db = database.lock().unwrap();
+        flush_place(&db, &place, save_grid);
+    }
+}
+
+// Everything queued on every place, grids included.
+pub fn flush_all(data: &Data<RwLock<AppState>>) {
+    if let Ok(app_state) = data.read() {
+        let places: Vec<_> = app_state.places.values().cloned().collect();
+        flush_places(&app_state.database, &places, true);
+    }
+}
+
+// Draws only queue their writes; this task saves them every `intervals.flush`, and the grids every
+// `intervals.grid`, on the blocking thread pool.
+pub async fn run_place_flush(data: Data<RwLock<AppState>>, intervals: FlushIntervals) {
+    let mut interval = actix_web::rt::time::interval(intervals.flush);
+    let mut last_grid_flush = Instant::now();
+    loop {
+        interval.tick().await;
+
+        let (database, places) = match data.read() {
+            Ok(app_state) => (
+                app_state.database.clone(),
+                app_state.places.values().cloned().collect::<Vec<_>>(),
+            ),
+            Err(_) => continue,
+        };
+
+        let save_grid = last_grid_flush.elapsed() >= intervals.grid;
+        if save_grid {
+            last_grid_flush = Instant::now();
+        }
+
+        if let Err(e) = web::block(move || flush_places(&database, &places, save_grid)).await {
+            eprintln!("Failed to flush places: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grid::ChunkedGrid;
+    use crate::voxel::Voxel;
+    use super::*;
+
+    fn count(db: &Database, table: &str) -> i64 {
+        let conn = db.get_conn().unwrap();
+        conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn failed_batches_are_saved_by_the_next_flush() {
+        let size = (16, 16, 16);
+        let voxel = Voxel::new(2, "test", 0, size, Some(ChunkedGrid::new(size)), None, None);
+        let place = Place::new(1, true, 60, voxel);
+        assert!(place.draw(10, 0, 0, 0, 1).is_ok());
+        assert!(place.draw(11, 1, 0, 0, 2).is_ok());
+
+        // Nothing can be saved before the tables exist.
+        let db = Database::open(":memory:").unwrap();
+        flush_place(&db, &place, true);
+
+        assert!(place.draw(12, 2, 0, 0, 3).is_ok());
+        db.migrate().unwrap();
+        flush_place(&db, &place, true);
+
+        assert_eq!(count(&db, "PlaceEvent"), 3);
+        assert_eq!(count(&db, "PlaceUserCooldown"), 3);
+        assert_eq!(count(&db, "PlaceUser"), 3);
+        assert_eq!(count(&db, "VoxelChunk"), 1);
+
+        let conn = db.get_conn().unwrap();
+        let users: Vec<i64> = conn
+            .prepare("SELECT user_id FROM PlaceEvent ORDER BY event_id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(users, [10, 11, 12]);
+    }
+
+    #[test]
+    fn newer_cooldowns_win_over_requeued_ones() {
+        let size = (16, 16, 16);
+        let voxel = Voxel::new(2, "test", 0, size, Some(ChunkedGrid::new(size)), None, None);
+        let place = Place::new(1, true, 60, voxel);
+        place.requeue_cooldowns(vec![(10, 100)]);
+        place.requeue_cooldowns(vec![(10, 50), (11, 70)]);
+
+        let mut cooldowns = place.take_cooldowns();
+        cooldowns.sort();
+        assert_eq!(cooldowns, [(10, 100), (11, 70)]);
+    }
+}
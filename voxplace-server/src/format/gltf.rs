@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+use byteorder::{LittleEndian, WriteBytesExt};
+use serde_json::{json, Value};
+use crate::mesh::Mesh;
+use crate::palette::{Material, Palette};
+
+const GLB_MAGIC: u32 = 0x4654_6C67;
+const GLB_VERSION: u32 = 2;
+const CHUNK_JSON: u32 = 0x4E4F_534A;
+const CHUNK_BIN: u32 = 0x004E_4942;
+
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+const FLOAT: u32 = 5126;
+const UNSIGNED_INT: u32 = 5125;
+
+#[derive(Default)]
+struct Primitive {
+    positions: Vec<[f32; 3]>,
+    normals: Vec<[f32; 3]>,
+    indices: Vec<u32>,
+}
+
+pub fn encode_glb(name: &str, mesh: &Mesh, palette: &Palette) -> std::io::Result<Vec<u8>> {
+    let mut primitives: BTreeMap<u8, Primitive> = BTreeMap::new();
+    for quad in mesh.quads.iter() {
+        let primitive = primitives.entry(quad.color).or_default();
+        let base = primitive.positions.len() as u32;
+        for corner in quad.corners.iter() {
+            primitive.positions.push(*corner);
+            primitive.normals.push(quad.normal);
+        }
+        primitive.indices.extend([base, base + 1, base + 2, base, base + 2, base + 3]);
+    }
+
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut buffer_views = Vec::new();
+    let mut accessors = Vec::new();
+    let mut materials = Vec::new();
+    let mut mesh_primitives = Vec::new();
+
+    for (color, primitive) in primitives.iter() {
+        let position_view = buffer_views.len();
+        let offset = buffer.len();
+        for position in primitive.positions.iter() {
+            for value in position.iter() {
+                buffer.write_f32::<LittleEndian>(*value)?;
+            }
+        }
+        buffer_views.push(json!({
+            "buffer": 0,
+            "byteOffset": offset,
+            "byteLength": buffer.len() - offset,
+            "target": ARRAY_BUFFER,
+        }));
+
+        let normal_view = buffer_views.len();
+        let offset = buffer.len();
+        for normal in primitive.normals.iter() {
+            for value in normal.iter() {
+                buffer.write_f32::<LittleEndian>(*value)?;
+            }
+        }
+        buffer_views.push(json!({
+            "buffer": 0,
+            "byteOffset": offset,
+            "byteLength": buffer.len() - offset,
+            "target": ARRAY_BUFFER,
+        }));
+
+        let index_view = buffer_views.len();
+        let offset = buffer.len();
+        for index in primitive.indices.iter() {
+            buffer.write_u32::<LittleEndian>(*index)?;
+        }
+        buffer_views.push(json!({
+            "buffer": 0,
+            "byteOffset": offset,
+            "byteLength": buffer.len() - offset,
+            "target": ELEMENT_ARRAY_BUFFER,
+        }));
+
+        let mut min = [f32::MAX; 3];
+        let mut max = [f32::MIN; 3];
+        for position in primitive.positions.iter() {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(position[axis]);
+                max[axis] = max[axis].max(position[axis]);
+            }
+        }
+
+        let position_accessor = accessors.len();
+        accessors.push(json!({
+            "bufferView": position_view,
+            "componentType": FLOAT,
+            "count": primitive.positions.len(),
+            "type": "VEC3",
+            "min": min,
+            "max": max,
+        }));
+        let normal_accessor = accessors.len();
+        accessors.push(json!({
+            "bufferView": normal_view,
+            "componentType": FLOAT,
+            "count": primitive.normals.len(),
+            "type": "VEC3",
+        }));
+        let index_accessor = accessors.len();
+        accessors.push(json!({
+            "bufferView": index_view,
+            "componentType": UNSIGNED_INT,
+            "count": primitive.indices.len(),
+            "type": "SCALAR",
+        }));
+
+        let material_index = materials.len();
+        materials.push(encode_material(*color, palette));
+
+        mesh_primitives.push(json!({
+            "attributes": {
+                "POSITION": position_accessor,
+                "NORMAL": normal_accessor,
+            },
+            "indices": index_accessor,
+            "material": material_index,
+        }));
+    }
+
+    let mut node = json!({ "name": name });
+    let mut document = json!({
+        "asset": { "version": "2.0", "generator": "voxplace" },
+        "scene": 0,
+        "scenes": [{ "nodes": [0] }],
+    });
+
+    if !mesh_primitives.is_empty() {
+        node["mesh"] = json!(0);
+        document["meshes"] = json!([{ "name": name, "primitives": mesh_primitives }]);
+        document["materials"] = Value::Array(materials);
+        document["accessors"] = Value::Array(accessors);
+        document["bufferViews"] = Value::Array(buffer_views);
+        document["buffers"] = json!([{ "byteLength": buffer.len() }]);
+    }
+    document["nodes"] = json!([node]);
+
+    let mut json_chunk = serde_json::to_vec(&document)?;
+    while !json_chunk.len().is_multiple_of(4) {
+        json_chunk.push(b' ');
+    }
+    while !buffer.len().is_multiple_of(4) {
+        buffer.push(0);
+    }
+
+    let mut total_length = 12 + 8 + json_chunk.len();
+    if !buffer.is_empty() {
+        total_length += 8 + buffer.len();
+    }
+
+    let mut bytes = Vec::with_capacity(total_length);
+    bytes.write_u32::<LittleEndian>(GLB_MAGIC)?;
+    bytes.write_u32::<LittleEndian>(GLB_VERSION)?;
+    bytes.write_u32::<LittleEndian>(total_length as u32)?;
+    bytes.write_u32::<LittleEndian>(json_chunk.len() as u32)?;
+    bytes.write_u32::<LittleEndian>(CHUNK_JSON)?;
+    bytes.extend(json_chunk);
+    if !buffer.is_empty() {
+        bytes.write_u32::<LittleEndian>(buffer.len() as u32)?;
+        bytes.write_u32::<LittleEndian>(CHUNK_BIN)?;
+        bytes.extend(buffer);
+    }
+
+    Ok(bytes)
+}
+
+fn encode_material(color: u8, palette: &Palette) -> Value {
+    let index = color as usize - 1;
+    let (r, g, b) = palette.colors().get(index).copied().unwrap_or((255, 0, 255));
+    let material = palette.materials().get(index).copied().unwrap_or_default();
+
+    let base_color = [
+        srgb_to_linear(r),
+        srgb_to_linear(g),
+        srgb_to_linear(b),
+        material.alpha as f32 / 255.0,
+    ];
+    let emission = material.emission as f32 / 255.0;
+
+    let mut value = json!({
+        "name": format!("color_{}", color),
+        "pbrMetallicRoughness": {
+            "baseColorFactor": base_color,
+            "metallicFactor": material.metallic as f32 / 255.0,
+            "roughnessFactor": material.roughness as f32 / 255.0,
+        },
+        "emissiveFactor": [base_color[0] * emission, base_color[1] * emission, base_color[2] * emission],
+    });
+    if material.alpha < Material::default().alpha {
+        value["alphaMode"] = json!("BLEND");
+    }
+
+    value
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
@@ -0,0 +1,5 @@
+pub mod vxl;
+pub mod vox;
+pub mod gltf;
+pub mod obj;
+pub mod stl;
@@ -0,0 +1,76 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use crate::mesh::{Mesh, Quad};
+use crate::palette::Palette;
+
+pub fn encode_obj(mesh: &Mesh, mtl_name: &str, scale: f32) -> String {
+    let mut groups: BTreeMap<u8, Vec<&Quad>> = BTreeMap::new();
+    for quad in mesh.quads.iter() {
+        groups.entry(quad.color).or_default().push(quad);
+    }
+
+    let mut obj = String::new();
+    let _ = writeln!(obj, "# voxplace");
+    let _ = writeln!(obj, "mtllib {}", mtl_name);
+
+    let normals = [
+        [1.0, 0.0, 0.0],
+        [-1.0, 0.0, 0.0],
+        [0.0, 1.0, 0.0],
+        [0.0, -1.0, 0.0],
+        [0.0, 0.0, 1.0],
+        [0.0, 0.0, -1.0],
+    ];
+    for normal in normals.iter() {
+        let _ = writeln!(obj, "vn {} {} {}", normal[0], normal[1], normal[2]);
+    }
+
+    let mut vertex_count = 0;
+    for (color, quads) in groups.iter() {
+        let _ = writeln!(obj, "usemtl color_{}", color);
+        for quad in quads.iter() {
+            for corner in quad.corners.iter() {
+                let _ = writeln!(obj, "v {} {} {}", corner[0] * scale, corner[1] * scale, corner[2] * scale);
+            }
+            let normal = normals.iter().position(|normal| *normal == quad.normal).unwrap_or(0) + 1;
+            let _ = writeln!(
+                obj,
+                "f {}//{n} {}//{n} {}//{n} {}//{n}",
+                vertex_count + 1,
+                vertex_count + 2,
+                vertex_count + 3,
+                vertex_count + 4,
+                n = normal,
+            );
+            vertex_count += 4;
+        }
+    }
+
+    obj
+}
+
+pub fn encode_mtl(mesh: &Mesh, palette: &Palette) -> String {
+    let mut colors: Vec<u8> = mesh.quads.iter().map(|quad| quad.color).collect();
+    colors.sort_unstable();
+    colors.dedup();
+
+    let mut mtl = String::new();
+    let _ = writeln!(mtl, "# voxplace");
+    for color in colors {
+        let index = color as usize - 1;
+        let (r, g, b) = palette.colors().get(index).copied().unwrap_or((255, 0, 255));
+        let material = palette.materials().get(index).copied().unwrap_or_default();
+        let (r, g, b) = (r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0);
+        let emission = material.emission as f32 / 255.0;
+
+        let _ = writeln!(mtl, "newmtl color_{}", color);
+        let _ = writeln!(mtl, "Kd {:.4} {:.4} {:.4}", r, g, b);
+        let _ = writeln!(mtl, "Ke {:.4} {:.4} {:.4}", r * emission, g * emission, b * emission);
+        let _ = writeln!(mtl, "d {:.4}", material.alpha as f32 / 255.0);
+        let _ = writeln!(mtl, "Pr {:.4}", material.roughness as f32 / 255.0);
+        let _ = writeln!(mtl, "Pm {:.4}", material.metallic as f32 / 255.0);
+        let _ = writeln!(mtl, "illum 2");
+    }
+
+    mtl
+}
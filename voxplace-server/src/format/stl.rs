@@ -0,0 +1,31 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+use crate::mesh::Mesh;
+
+// STL is Z-up, so a voxplace (x, y, z) is written as (x, size_z - z, y) to keep the model upright
+// and right-handed. `scale` is the size of a voxel in millimetres.
+pub fn encode_stl(mesh: &Mesh, size_z: usize, scale: f32) -> std::io::Result<Vec<u8>> {
+    let convert = |point: [f32; 3]| [point[0] * scale, (size_z as f32 - point[2]) * scale, point[1] * scale];
+
+    let mut header = [0u8; 80];
+    let title = b"voxplace";
+    header[..title.len()].copy_from_slice(title);
+
+    let mut bytes = Vec::new();
+    bytes.extend(header);
+    bytes.write_u32::<LittleEndian>(mesh.quads.len() as u32 * 2)?;
+
+    for (quad, triangle) in mesh.triangles() {
+        let normal = [quad.normal[0], -quad.normal[2], quad.normal[1]];
+        for value in normal {
+            bytes.write_f32::<LittleEndian>(value)?;
+        }
+        for point in triangle {
+            for value in convert(point) {
+                bytes.write_f32::<LittleEndian>(value)?;
+            }
+        }
+        bytes.write_u16::<LittleEndian>(0)?;
+    }
+
+    Ok(bytes)
+}
@@ -0,0 +1,437 @@
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Write};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use thiserror::Error;
+use crate::palette::{Material, Palette};
+use crate::voxel::Voxel;
+
+// MagicaVoxel is Z-up while voxplace uses Y as the vertical axis. Both are right-handed, so a
+// voxplace (x, y, z) maps to the MagicaVoxel (x, size_z - 1 - z, y) and back.
+
+pub const MAGIC: &[u8; 4] = b"VOX ";
+pub const VERSION: i32 = 150;
+const MAX_SIZE: usize = 256;
+
+struct Chunk {
+    id: [u8; 4],
+    content: Vec<u8>,
+    children: Vec<u8>,
+}
+
+#[derive(Error, Debug)]
+pub enum VoxError {
+    #[error("Error during IO: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("Unexpected end of file")]
+    UnexpectedEof(),
+
+    #[error("Invalid magic number")]
+    InvalidMagic(),
+
+    #[error("Missing chunk: {0}")]
+    MissingChunk(&'static str),
+
+    #[error("Invalid chunk: {0}")]
+    InvalidChunk(String),
+
+    #[error("Invalid grid size: {0:?}")]
+    InvalidGridSize((usize, usize, usize)),
+
+    #[error("Palette too large: {0} colors")]
+    PaletteTooLarge(usize),
+}
+
+pub fn encode(voxel: &Voxel, palette: &Palette) -> Result<Vec<u8>, VoxError> {
+    let (size_x, size_y, size_z) = voxel.grid_size;
+    if size_x > MAX_SIZE || size_y > MAX_SIZE || size_z > MAX_SIZE {
+        return Err(VoxError::InvalidGridSize(voxel.grid_size));
+    }
+
+    let colors = palette.colors();
+    let materials = palette.materials();
+    if colors.len() > 255 {
+        return Err(VoxError::PaletteTooLarge(colors.len()));
+    }
+
+    let mut size = Vec::new();
+    size.write_i32::<LittleEndian>(size_x as i32)?;
+    size.write_i32::<LittleEndian>(size_z as i32)?;
+    size.write_i32::<LittleEndian>(size_y as i32)?;
+
+    let mut voxels = Vec::new();
+    let mut count = 0;
+    for (x, y, z) in voxel.grid.layout().iter() {
+        let color = voxel.get(x, y, z);
+        if color == 0 {
+            continue;
+        }
+        voxels.write_all(&[x as u8, (size_z - 1 - z) as u8, y as u8, color])?;
+        count += 1;
+    }
+    let mut xyzi = Vec::new();
+    xyzi.write_i32::<LittleEndian>(count)?;
+    xyzi.extend(voxels);
+
+    let mut rgba = Vec::new();
+    for i in 0..256 {
+        match (colors.get(i), materials.get(i)) {
+            (Some(color), Some(material)) => rgba.write_all(&[color.0, color.1, color.2, material.alpha])?,
+            _ => rgba.write_all(&[0, 0, 0, 255])?,
+        }
+    }
+
+    let mut children = Vec::new();
+    write_chunk(&mut children, b"SIZE", &size)?;
+    write_chunk(&mut children, b"XYZI", &xyzi)?;
+    write_chunk(&mut children, b"RGBA", &rgba)?;
+
+    for (i, material) in materials.iter().enumerate() {
+        if *material == Material::default() {
+            continue;
+        }
+        write_chunk(&mut children, b"MATL", &encode_material(i as i32 + 1, material)?)?;
+    }
+
+    let mut bytes = Vec::new();
+    bytes.write_all(MAGIC)?;
+    bytes.write_i32::<LittleEndian>(VERSION)?;
+    bytes.write_all(b"MAIN")?;
+    bytes.write_i32::<LittleEndian>(0)?;
+    bytes.write_i32::<LittleEndian>(children.len() as i32)?;
+    bytes.extend(children);
+
+    Ok(bytes)
+}
+
+// Only the first model of the file is imported, scene graph transforms are ignored.
+pub fn decode(
+    data: &[u8],
+    name: &str,
+    voxel_id: i64,
+    palette_id: i64,
+    max_size: usize,
+) -> Result<(Voxel, Palette), VoxError> {
+    let max_size = MAX_SIZE.min(max_size) as i32;
+
+    let mut cursor = Cursor::new(data);
+
+    let mut magic = [0; 4];
+    read_exact(&mut cursor, &mut magic)?;
+    if &magic != MAGIC {
+        return Err(VoxError::InvalidMagic());
+    }
+    read_i32(&mut cursor)?;
+
+    let main = read_chunk(&mut cursor)?;
+    if &main.id != b"MAIN" {
+        return Err(VoxError::MissingChunk("MAIN"));
+    }
+    let children = main.children;
+
+    let mut size: Option<(usize, usize, usize)> = None;
+    let mut xyzi: Option<Vec<[u8; 4]>> = None;
+    let mut rgba: Option<Vec<[u8; 4]>> = None;
+    let mut matl: HashMap<u8, Material> = HashMap::new();
+
+    let mut cursor = Cursor::new(children.as_slice());
+    while (cursor.position() as usize) < children.len() {
+        let chunk = read_chunk(&mut cursor)?;
+        let mut content = Cursor::new(chunk.content.as_slice());
+        match &chunk.id {
+            b"SIZE" if size.is_none() => {
+                let x = read_i32(&mut content)?;
+                let y = read_i32(&mut content)?;
+                let z = read_i32(&mut content)?;
+                if x <= 0 || y <= 0 || z <= 0 || x > max_size || y > max_size || z > max_size {
+                    return Err(VoxError::InvalidGridSize((x as usize, y as usize, z as usize)));
+                }
+                size = Some((x as usize, y as usize, z as usize));
+            }
+            b"XYZI" if xyzi.is_none() => {
+                let count = read_i32(&mut content)?;
+                if count < 0 {
+                    return Err(VoxError::InvalidChunk("XYZI".to_string()));
+                }
+                let mut voxels = Vec::new();
+                for _ in 0..count {
+                    let mut entry = [0; 4];
+                    read_exact(&mut content, &mut entry)?;
+                    voxels.push(entry);
+                }
+                xyzi = Some(voxels);
+            }
+            b"RGBA" => {
+                let mut entries = Vec::new();
+                for _ in 0..256 {
+                    let mut entry = [0; 4];
+                    read_exact(&mut content, &mut entry)?;
+                    entries.push(entry);
+                }
+                rgba = Some(entries);
+            }
+            b"MATL" => {
+                let (index, material) = decode_material(&mut content)?;
+                if (1..=255).contains(&index) {
+                    matl.insert(index as u8, material);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let size = size.ok_or(VoxError::MissingChunk("SIZE"))?;
+    let xyzi = xyzi.ok_or(VoxError::MissingChunk("XYZI"))?;
+    let rgba = rgba.unwrap_or_else(default_palette);
+
+    let grid_size = (size.0, size.2, size.1);
+    let voxel = Voxel::new(
+        voxel_id,
+        name,
+        palette_id,
+        grid_size,
+        Some(Voxel::generate_empty_grid(grid_size)),
+        None,
+        None,
+    );
+
+    let mut remap: HashMap<u8, u8> = HashMap::new();
+    let mut colors = Vec::new();
+    let mut materials = Vec::new();
+    for [x, y, z, index] in xyzi {
+        let (x, y, z) = (x as usize, y as usize, z as usize);
+        if index == 0 || x >= size.0 || y >= size.1 || z >= size.2 {
+            continue;
+        }
+
+        let color = match remap.get(&index) {
+            Some(&color) => color,
+            None => {
+                let [r, g, b, a] = rgba[index as usize - 1];
+                let material = matl.get(&index).copied().unwrap_or_default();
+                colors.push((r, g, b));
+                materials.push(Material { alpha: a, ..material });
+                let color = colors.len() as u8;
+                remap.insert(index, color);
+                color
+            }
+        };
+
+        voxel.set(x, z, size.1 - 1 - y, color);
+    }
+
+    let palette = Palette::new(palette_id, Some(colors), Some(materials));
+
+    Ok((voxel, palette))
+}
+
+fn encode_material(index: i32, material: &Material) -> Result<Vec<u8>, VoxError> {
+    let kind = if material.emission > 0 {
+        "_emit"
+    } else if material.metallic > 0 {
+        "_metal"
+    } else {
+        "_diffuse"
+    };
+
+    let entries = [
+        ("_type", kind.to_string()),
+        ("_rough", format!("{:.3}", material.roughness as f32 / 255.0)),
+        ("_metal", format!("{:.3}", material.metallic as f32 / 255.0)),
+        ("_emit", format!("{:.3}", material.emission as f32 / 255.0)),
+        ("_alpha", format!("{:.3}", material.alpha as f32 / 255.0)),
+    ];
+
+    let mut bytes = Vec::new();
+    bytes.write_i32::<LittleEndian>(index)?;
+    bytes.write_i32::<LittleEndian>(entries.len() as i32)?;
+    for (key, value) in entries.iter() {
+        write_string(&mut bytes, key)?;
+        write_string(&mut bytes, value)?;
+    }
+
+    Ok(bytes)
+}
+
+fn decode_material(cursor: &mut Cursor<&[u8]>) -> Result<(i32, Material), VoxError> {
+    let index = read_i32(cursor)?;
+    let count = read_i32(cursor)?;
+
+    let mut material = Material::default();
+    for _ in 0..count {
+        let key = read_string(cursor)?;
+        let value = read_string(cursor)?;
+        let value = match value.parse::<f32>() {
+            Ok(value) => (value.clamp(0.0, 1.0) * 255.0).round() as u8,
+            Err(_) => continue,
+        };
+        match key.as_str() {
+            "_rough" => material.roughness = value,
+            "_metal" => material.metallic = value,
+            "_emit" => material.emission = value,
+            _ => {}
+        }
+    }
+
+    Ok((index, material))
+}
+
+// MagicaVoxel's built-in palette, used when a file has no RGBA chunk: a 6x6x6 color cube
+// followed by red, green, blue and gray ramps.
+fn default_palette() -> Vec<[u8; 4]> {
+    let levels = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+    let ramp = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+
+    let mut palette = Vec::new();
+    for &r in levels.iter() {
+        for &g in levels.iter() {
+            for &b in levels.iter() {
+                if r == 0 && g == 0 && b == 0 {
+                    continue;
+                }
+                palette.push([r, g, b, 0xff]);
+            }
+        }
+    }
+    for &v in ramp.iter() {
+        palette.push([v, 0, 0, 0xff]);
+    }
+    for &v in ramp.iter() {
+        palette.push([0, v, 0, 0xff]);
+    }
+    for &v in ramp.iter() {
+        palette.push([0, 0, v, 0xff]);
+    }
+    for &v in ramp.iter() {
+        palette.push([v, v, v, 0xff]);
+    }
+    palette.push([0, 0, 0, 0]);
+
+    palette
+}
+
+fn write_chunk(bytes: &mut Vec<u8>, id: &[u8; 4], content: &[u8]) -> Result<(), VoxError> {
+    bytes.write_all(id)?;
+    bytes.write_i32::<LittleEndian>(content.len() as i32)?;
+    bytes.write_i32::<LittleEndian>(0)?;
+    bytes.write_all(content)?;
+    Ok(())
+}
+
+fn write_string(bytes: &mut Vec<u8>, value: &str) -> Result<(), VoxError> {
+    bytes.write_i32::<LittleEndian>(value.len() as i32)?;
+    bytes.write_all(value.as_bytes())?;
+    Ok(())
+}
+
+fn read_chunk(cursor: &mut Cursor<&[u8]>) -> Result<Chunk, VoxError> {
+    let mut id = [0; 4];
+    read_exact(cursor, &mut id)?;
+    let content_size = read_i32(cursor)?;
+    let children_size = read_i32(cursor)?;
+    if content_size < 0 || children_size < 0 {
+        return Err(VoxError::InvalidChunk(String::from_utf8_lossy(&id).to_string()));
+    }
+
+    let remaining = cursor.get_ref().len() - cursor.position() as usize;
+    if content_size as usize + children_size as usize > remaining {
+        return Err(VoxError::UnexpectedEof());
+    }
+
+    let mut content = vec![0; content_size as usize];
+    read_exact(cursor, &mut content)?;
+    let mut children = vec![0; children_size as usize];
+    read_exact(cursor, &mut children)?;
+
+    Ok(Chunk { id, content, children })
+}
+
+fn read_string(cursor: &mut Cursor<&[u8]>) -> Result<String, VoxError> {
+    let length = read_i32(cursor)?;
+    if length < 0 {
+        return Err(VoxError::InvalidChunk("MATL".to_string()));
+    }
+    let mut bytes = vec![0; length as usize];
+    read_exact(cursor, &mut bytes)?;
+    Ok(String::from_utf8_lossy(&bytes).to_string())
+}
+
+fn read_exact(cursor: &mut Cursor<&[u8]>, buf: &mut [u8]) -> Result<(), VoxError> {
+    cursor.read_exact(buf).map_err(map_eof)
+}
+
+fn read_i32(cursor: &mut Cursor<&[u8]>) -> Result<i32, VoxError> {
+    cursor.read_i32::<LittleEndian>().map_err(map_eof)
+}
+
+fn map_eof(e: std::io::Error) -> VoxError {
+    if e.kind() == std::io::ErrorKind::UnexpectedEof {
+        VoxError::UnexpectedEof()
+    } else {
+        VoxError::IoError(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grid::ChunkedGrid;
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let voxel = Voxel::new(1, "test", 0, (3, 5, 7), Some(ChunkedGrid::new((3, 5, 7))), None, None);
+        // Colors appear in grid order, so the decoder numbers them the same way.
+        voxel.set(0, 0, 1, 1);
+        voxel.set(1, 4, 6, 2);
+        voxel.set(2, 0, 0, 3);
+        voxel.set(2, 3, 0, 1);
+        let colors = vec![(255, 0, 0), (0, 255, 0), (0, 0, 255)];
+        let palette = Palette::new(0, Some(colors.clone()), None);
+
+        let bytes = encode(&voxel, &palette).unwrap();
+        let (decoded, decoded_palette) = decode(&bytes, "test", 2, 2, 128).unwrap();
+        assert_eq!(decoded.grid_size, (3, 5, 7));
+        assert_eq!(decoded.get_grid_bytes(), voxel.get_grid_bytes());
+        assert_eq!(*decoded_palette.colors(), colors);
+    }
+
+    // MagicaVoxel (x, y, z) with z up is voxplace (x, z, size_y - 1 - y) with y up.
+    #[test]
+    fn maps_z_up_to_y_up() {
+        let mut size = Vec::new();
+        for side in [2, 3, 4] {
+            size.write_i32::<LittleEndian>(side).unwrap();
+        }
+        let mut xyzi = Vec::new();
+        xyzi.write_i32::<LittleEndian>(1).unwrap();
+        xyzi.write_all(&[1, 2, 3, 5]).unwrap();
+
+        let mut children = Vec::new();
+        write_chunk(&mut children, b"SIZE", &size).unwrap();
+        write_chunk(&mut children, b"XYZI", &xyzi).unwrap();
+        let mut data = Vec::new();
+        data.write_all(MAGIC).unwrap();
+        data.write_i32::<LittleEndian>(VERSION).unwrap();
+        data.write_all(b"MAIN").unwrap();
+        data.write_i32::<LittleEndian>(0).unwrap();
+        data.write_i32::<LittleEndian>(children.len() as i32).unwrap();
+        data.extend(children);
+
+        let (voxel, palette) = decode(&data, "test", 1, 1, 128).unwrap();
+        assert_eq!(voxel.grid_size, (2, 4, 3));
+        let filled: Vec<_> = voxel.grid.layout().iter().filter(|&(x, y, z)| voxel.get(x, y, z) != 0).collect();
+        assert_eq!(filled, [(1, 3, 0)]);
+        // Without an RGBA chunk, index 5 comes from the default palette.
+        let [r, g, b, _] = default_palette()[4];
+        assert_eq!(*palette.colors(), [(r, g, b)]);
+
+        // Exporting puts it back where it was.
+        let bytes = encode(&voxel, &palette).unwrap();
+        let main = read_chunk(&mut Cursor::new(&bytes[8..])).unwrap();
+        let mut children = Cursor::new(main.children.as_slice());
+        let size = read_chunk(&mut children).unwrap();
+        let xyzi = read_chunk(&mut children).unwrap();
+        assert_eq!(size.content, [2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0]);
+        assert_eq!(xyzi.content, [1, 0, 0, 0, 1, 2, 3, 1]);
+    }
+}
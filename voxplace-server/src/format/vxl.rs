@@ -0,0 +1,236 @@
+use std::io::{Cursor, Read, Write};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use thiserror::Error;
+use crate::config::grid_size_allowed;
+use crate::grid::{ChunkedGrid, GridLayout};
+use crate::palette::{Material, Palette};
+use crate::voxel::Voxel;
+
+pub const MAGIC: &[u8; 4] = b"VXL ";
+pub const VERSION: [u8; 4] = [0, 1, 0, 0];
+
+#[derive(Error, Debug)]
+pub enum VxlError {
+    #[error("Error during IO: {0}")]
+    IoError(#[from] std::io::Error),
+
+    #[error("Unexpected end of file")]
+    UnexpectedEof(),
+
+    #[error("Invalid magic number")]
+    InvalidMagic(),
+
+    #[error("Unsupported version: {0:?}")]
+    UnsupportedVersion([u8; 4]),
+
+    #[error("Invalid name")]
+    InvalidName(),
+
+    #[error("Invalid grid size: {0:?}")]
+    InvalidGridSize((usize, usize, usize)),
+
+    #[error("Palette too large: {0} colors")]
+    PaletteTooLarge(usize),
+
+    #[error("Invalid grid length: expected {0} bytes, got {1}")]
+    InvalidGridLength(usize, usize),
+
+    #[error("Invalid palette index: {0}")]
+    InvalidPaletteIndex(u8),
+}
+
+pub fn encode(voxel: &Voxel, palette: &Palette, gzip: bool) -> Result<Vec<u8>, VxlError> {
+    let (size_x, size_y, size_z) = voxel.grid_size;
+    if size_x > u16::MAX as usize || size_y > u16::MAX as usize || size_z > u16::MAX as usize {
+        return Err(VxlError::InvalidGridSize(voxel.grid_size));
+    }
+
+    let colors = palette.colors();
+    let materials = palette.materials();
+    if colors.len() > u8::MAX as usize {
+        return Err(VxlError::PaletteTooLarge(colors.len()));
+    }
+
+    let mut name_end = voxel.name.len().min(u8::MAX as usize);
+    while !voxel.name.is_char_boundary(name_end) {
+        name_end -= 1;
+    }
+    let name = &voxel.name[..name_end];
+
+    let mut bytes = Vec::new();
+    bytes.write_all(MAGIC)?;
+    bytes.write_u8(name.len() as u8)?;
+    bytes.write_all(name.as_bytes())?;
+    bytes.write_all(&VERSION)?;
+    bytes.write_u8(gzip as u8)?;
+    bytes.write_u8(colors.len() as u8)?;
+    bytes.write_u16::<LittleEndian>(size_x as u16)?;
+    bytes.write_u16::<LittleEndian>(size_y as u16)?;
+    bytes.write_u16::<LittleEndian>(size_z as u16)?;
+
+    for (color, material) in colors.iter().zip(materials.iter()) {
+        bytes.write_all(&[
+            color.0,
+            color.1,
+            color.2,
+            material.alpha,
+            material.emission,
+            material.roughness,
+            material.metallic,
+        ])?;
+    }
+
+    let grid = voxel.get_grid_bytes();
+    if gzip {
+        let mut encoder = GzEncoder::new(bytes, Compression::default());
+        encoder.write_all(&grid)?;
+        bytes = encoder.finish()?;
+    } else {
+        bytes.extend(grid);
+    }
+
+    Ok(bytes)
+}
+
+// Grids larger than `max_size` on any side are rejected from the header, before anything is
+// allocated or inflated.
+pub fn decode(data: &[u8], voxel_id: i64, palette_id: i64, max_size: usize) -> Result<(Voxel, Palette), VxlError> {
+    let mut cursor = Cursor::new(data);
+
+    let mut magic = [0; 4];
+    read_exact(&mut cursor, &mut magic)?;
+    if &magic != MAGIC {
+        return Err(VxlError::InvalidMagic());
+    }
+
+    let name_length = read_u8(&mut cursor)? as usize;
+    let mut name = vec![0; name_length];
+    read_exact(&mut cursor, &mut name)?;
+    let name = String::from_utf8(name).map_err(|_| VxlError::InvalidName())?;
+
+    let mut version = [0; 4];
+    read_exact(&mut cursor, &mut version)?;
+    if version != VERSION {
+        return Err(VxlError::UnsupportedVersion(version));
+    }
+
+    let gzip = read_u8(&mut cursor)? != 0;
+    let palette_size = read_u8(&mut cursor)? as usize;
+    let grid_size = (
+        read_u16(&mut cursor)? as usize,
+        read_u16(&mut cursor)? as usize,
+        read_u16(&mut cursor)? as usize,
+    );
+    if !grid_size_allowed(grid_size, max_size) {
+        return Err(VxlError::InvalidGridSize(grid_size));
+    }
+
+    let mut colors = Vec::with_capacity(palette_size);
+    let mut materials = Vec::with_capacity(palette_size);
+    for _ in 0..palette_size {
+        let mut entry = [0; 7];
+        read_exact(&mut cursor, &mut entry)?;
+        colors.push((entry[0], entry[1], entry[2]));
+        materials.push(Material {
+            alpha: entry[3],
+            emission: entry[4],
+            roughness: entry[5],
+            metallic: entry[6],
+        });
+    }
+
+    let grid_length = GridLayout::new(grid_size).len();
+    let mut grid = Vec::new();
+    if gzip {
+        GzDecoder::new(cursor)
+            .take(grid_length as u64 + 1)
+            .read_to_end(&mut grid)?;
+    } else {
+        cursor.read_to_end(&mut grid)?;
+    }
+    if grid.len() != grid_length {
+        return Err(VxlError::InvalidGridLength(grid_length, grid.len()));
+    }
+    if let Some(&index) = grid.iter().find(|&&index| index as usize > palette_size) {
+        return Err(VxlError::InvalidPaletteIndex(index));
+    }
+
+    let grid = ChunkedGrid::from_bytes(grid_size, &grid);
+    let voxel = Voxel::new(voxel_id, &name, palette_id, grid_size, Some(grid), None, None);
+    let palette = Palette::new(palette_id, Some(colors), Some(materials));
+
+    Ok((voxel, palette))
+}
+
+fn read_exact(cursor: &mut Cursor<&[u8]>, buf: &mut [u8]) -> Result<(), VxlError> {
+    cursor.read_exact(buf).map_err(map_eof)
+}
+
+fn read_u8(cursor: &mut Cursor<&[u8]>) -> Result<u8, VxlError> {
+    cursor.read_u8().map_err(map_eof)
+}
+
+fn read_u16(cursor: &mut Cursor<&[u8]>) -> Result<u16, VxlError> {
+    cursor.read_u16::<LittleEndian>().map_err(map_eof)
+}
+
+fn map_eof(e: std::io::Error) -> VxlError {
+    if e.kind() == std::io::ErrorKind::UnexpectedEof {
+        VxlError::UnexpectedEof()
+    } else {
+        VxlError::IoError(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(gzip: bool, size: (u16, u16, u16)) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(MAGIC);
+        bytes.push(0);
+        bytes.extend(VERSION);
+        bytes.push(gzip as u8);
+        bytes.push(0);
+        for side in [size.0, size.1, size.2] {
+            bytes.extend(side.to_le_bytes());
+        }
+        bytes
+    }
+
+    #[test]
+    fn rejects_oversized_header_before_inflating() {
+        // A gzip stream that would inflate to far more than the limit.
+        let mut encoder = GzEncoder::new(header(true, (u16::MAX, u16::MAX, u16::MAX)), Compression::best());
+        encoder.write_all(&vec![0; 16 * 1024 * 1024]).unwrap();
+        let data = encoder.finish().unwrap();
+
+        match decode(&data, 1, 1, 128) {
+            Err(VxlError::InvalidGridSize(size)) => assert_eq!(size, (65535, 65535, 65535)),
+            other => panic!("expected InvalidGridSize, got {:?}", other.map(|_| ())),
+        }
+
+        let mut data = header(false, (129, 1, 1));
+        data.extend(vec![0; 129]);
+        assert!(matches!(decode(&data, 1, 1, 128), Err(VxlError::InvalidGridSize(_))));
+    }
+
+    #[test]
+    fn round_trips() {
+        let voxel = Voxel::new(1, "test", 0, (3, 5, 7), Some(ChunkedGrid::new((3, 5, 7))), None, None);
+        voxel.set(2, 4, 6, 3);
+        let palette = Palette::new(0, None, None);
+
+        for gzip in [false, true] {
+            let bytes = encode(&voxel, &palette, gzip).unwrap();
+            let (decoded, _) = decode(&bytes, 2, 2, 128).unwrap();
+            assert_eq!(decoded.grid_size, (3, 5, 7));
+            assert_eq!(decoded.name, "test");
+            assert_eq!(decoded.get_grid_bytes(), voxel.get_grid_bytes());
+        }
+    }
+}
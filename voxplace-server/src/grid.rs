@@ -0,0 +1,480 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::OnceLock;
+use byteorder::{LittleEndian, WriteBytesExt};
+use crossbeam::atomic::AtomicCell;
+use rand::{Rng, thread_rng};
+
+pub const CHUNK_SIZE: usize = 16;
+pub const CHUNK_VOLUME: usize = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
+
+pub type ChunkCoords = (usize, usize, usize);
+
+// Flat layout of a grid: x is the outermost and z the innermost axis, so the cell (x, y, z) lives at
+// x * size_y * size_z + y * size_z + z. The API and .vxl files use this order; grids stored before it
+// was fixed are read through legacy_index instead.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct GridLayout {
+    size: (usize, usize, usize),
+}
+
+impl GridLayout {
+    pub fn new(size: (usize, usize, usize)) -> Self {
+        Self { size }
+    }
+
+    pub fn size(&self) -> (usize, usize, usize) {
+        self.size
+    }
+
+    pub fn len(&self) -> usize {
+        self.size.0 * self.size.1 * self.size.2
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, x: usize, y: usize, z: usize) -> bool {
+        x < self.size.0 && y < self.size.1 && z < self.size.2
+    }
+
+    pub fn index(&self, x: usize, y: usize, z: usize) -> Option<usize> {
+        if !self.contains(x, y, z) {
+            return None;
+        }
+        Some((x * self.size.1 + y) * self.size.2 + z)
+    }
+
+    pub fn coords(&self, index: usize) -> Option<(usize, usize, usize)> {
+        if index >= self.len() {
+            return None;
+        }
+        let (_, size_y, size_z) = self.size;
+        Some((index / (size_y * size_z), (index / size_z) % size_y, index % size_z))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, usize, usize)> {
+        let layout = *self;
+        (0..self.len()).filter_map(move |index| layout.coords(index))
+    }
+
+    // Grids used to be indexed with x * size_x * size_x + y * size_y + z, which only matches the
+    // layout above for cubic grids. Kept to read grids stored before the fix; cells it aliased end up
+    // at every coordinate that used to read them, which is what the old server showed.
+    pub fn legacy_index(&self, x: usize, y: usize, z: usize) -> Option<usize> {
+        if !self.contains(x, y, z) {
+            return None;
+        }
+        let index = x * self.size.0 * self.size.0 + y * self.size.1 + z;
+        if index < self.len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+}
+
+// Cells are only allocated once a chunk receives its first non-empty voxel, so empty regions of a
+// grid cost a few bytes each. The count of non-empty cells is kept so emptiness checks don't scan.
+pub struct Chunk {
+    cells: OnceLock<Box<[AtomicCell<u8>]>>,
+    filled: AtomicU32,
+    dirty: AtomicBool,
+    version: AtomicU32,
+}
+
+impl Chunk {
+    pub fn empty() -> Self {
+        Self {
+            cells: OnceLock::new(),
+            filled: AtomicU32::new(0),
+            dirty: AtomicBool::new(false),
+            version: AtomicU32::new(0),
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    pub fn get(&self, index: usize) -> u8 {
+        match self.cells.get() {
+            Some(cells) => cells[index].load(),
+            None => 0,
+        }
+    }
+
+    pub fn set(&self, index: usize, color: u8) {
+        let cells = match self.cells.get() {
+            Some(cells) => cells,
+            None if color == 0 => return,
+            None => self.cells.get_or_init(|| (0..CHUNK_VOLUME).map(|_| AtomicCell::new(0)).collect()),
+        };
+        // Swapping tells which writer changed the cell's emptiness, even with concurrent writes.
+        match (cells[index].swap(color), color) {
+            (0, 0) => {}
+            (0, _) => {
+                self.filled.fetch_add(1, Ordering::AcqRel);
+            }
+            (_, 0) => {
+                self.filled.fetch_sub(1, Ordering::AcqRel);
+            }
+            _ => {}
+        }
+        self.dirty.store(true, Ordering::Release);
+        self.version.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filled.load(Ordering::Acquire) == 0
+    }
+
+    pub fn to_bytes(&self) -> Option<Vec<u8>> {
+        if self.is_empty() {
+            return None;
+        }
+        self.cells.get().map(|cells| cells.iter().map(|cell| cell.load()).collect())
+    }
+
+    pub fn load_bytes(&self, bytes: &[u8]) {
+        for (index, &color) in bytes.iter().take(CHUNK_VOLUME).enumerate() {
+            self.set(index, color);
+        }
+        self.dirty.store(false, Ordering::Release);
+        self.version.store(0, Ordering::Release);
+    }
+
+    pub fn take_dirty(&self) -> bool {
+        self.dirty.swap(false, Ordering::AcqRel)
+    }
+
+    pub fn mark_dirty(&self) {
+        self.dirty.store(true, Ordering::Release);
+    }
+}
+
+// Chunk versions only live in memory and restart at 0 whenever a grid is loaded, so each grid gets
+// a random epoch. Cached chunks are only comparable by version within the same epoch.
+pub struct ChunkedGrid {
+    layout: GridLayout,
+    chunk_counts: (usize, usize, usize),
+    chunks: Vec<Chunk>,
+    epoch: u32,
+}
+
+impl ChunkedGrid {
+    pub fn new(size: (usize, usize, usize)) -> Self {
+        let chunk_counts = (
+            size.0.div_ceil(CHUNK_SIZE),
+            size.1.div_ceil(CHUNK_SIZE),
+            size.2.div_ceil(CHUNK_SIZE),
+        );
+        let chunks = (0..chunk_counts.0 * chunk_counts.1 * chunk_counts.2)
+            .map(|_| Chunk::empty())
+            .collect();
+
+        Self {
+            layout: GridLayout::new(size),
+            chunk_counts,
+            chunks,
+            epoch: thread_rng().gen(),
+        }
+    }
+
+    pub fn from_bytes(size: (usize, usize, usize), bytes: &[u8]) -> Self {
+        let grid = Self::new(size);
+        for ((x, y, z), &color) in grid.layout.iter().zip(bytes) {
+            grid.set(x, y, z, color);
+        }
+        grid
+    }
+
+    pub fn from_legacy_bytes(size: (usize, usize, usize), bytes: &[u8]) -> Self {
+        let grid = Self::new(size);
+        for (x, y, z) in grid.layout.iter() {
+            let color = grid.layout.legacy_index(x, y, z).and_then(|index| bytes.get(index));
+            if let Some(&color) = color {
+                grid.set(x, y, z, color);
+            }
+        }
+        grid
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.layout.iter().map(|(x, y, z)| self.get(x, y, z)).collect()
+    }
+
+    pub fn layout(&self) -> GridLayout {
+        self.layout
+    }
+
+    // Out of bounds cells read as empty and ignore writes.
+    pub fn get(&self, x: usize, y: usize, z: usize) -> u8 {
+        match self.locate(x, y, z) {
+            Some((chunk, index)) => self.chunks[chunk].get(index),
+            None => 0,
+        }
+    }
+
+    pub fn set(&self, x: usize, y: usize, z: usize, color: u8) {
+        if let Some((chunk, index)) = self.locate(x, y, z) {
+            self.chunks[chunk].set(index, color);
+        }
+    }
+
+    pub fn chunk_counts(&self) -> (usize, usize, usize) {
+        self.chunk_counts
+    }
+
+    pub fn epoch(&self) -> u32 {
+        self.epoch
+    }
+
+    // Every write bumps a chunk version, so within an epoch the sum changes with the contents.
+    pub fn version(&self) -> u64 {
+        self.chunks.iter().map(|chunk| chunk.version() as u64).sum()
+    }
+
+    pub fn chunk(&self, coords: ChunkCoords) -> Option<&Chunk> {
+        let (cx, cy, cz) = coords;
+        if cx >= self.chunk_counts.0 || cy >= self.chunk_counts.1 || cz >= self.chunk_counts.2 {
+            return None;
+        }
+        self.chunks.get(self.chunk_index(cx, cy, cz))
+    }
+
+    pub fn chunks(&self) -> impl Iterator<Item = (ChunkCoords, &Chunk)> {
+        let (_, count_y, count_z) = self.chunk_counts;
+        self.chunks.iter().enumerate().map(move |(i, chunk)| {
+            ((i / (count_y * count_z), (i / count_z) % count_y, i % count_z), chunk)
+        })
+    }
+
+    pub fn take_dirty_chunks(&self) -> Vec<(ChunkCoords, Option<Vec<u8>>)> {
+        self.chunks()
+            .filter(|(_, chunk)| chunk.take_dirty())
+            .map(|(coords, chunk)| (coords, chunk.to_bytes()))
+            .collect()
+    }
+
+    // Chunk stream: the grid's u32 epoch and a u32 chunk count, then for every chunk its coordinates (3 * u16), its version
+    // (u32), an empty flag (u8) and, unless empty, its CHUNK_VOLUME cells with x as the outermost
+    // and z as the innermost axis. All integers are little-endian.
+    pub fn encode_chunks(&self, coords: &[ChunkCoords]) -> std::io::Result<Vec<u8>> {
+        let chunks: Vec<_> = coords
+            .iter()
+            .filter_map(|&coords| self.chunk(coords).map(|chunk| (coords, chunk)))
+            .collect();
+
+        let mut bytes = Vec::new();
+        bytes.write_u32::<LittleEndian>(self.epoch)?;
+        bytes.write_u32::<LittleEndian>(chunks.len() as u32)?;
+        for ((cx, cy, cz), chunk) in chunks {
+            let version = chunk.version();
+            let cells = chunk.to_bytes();
+            bytes.write_u16::<LittleEndian>(cx as u16)?;
+            bytes.write_u16::<LittleEndian>(cy as u16)?;
+            bytes.write_u16::<LittleEndian>(cz as u16)?;
+            bytes.write_u32::<LittleEndian>(version)?;
+            match cells {
+                Some(cells) => {
+                    bytes.write_u8(0)?;
+                    bytes.extend(cells);
+                }
+                None => bytes.write_u8(1)?,
+            }
+        }
+        Ok(bytes)
+    }
+
+    pub fn snapshot(&self) -> Self {
+        let grid = Self::new(self.layout.size());
+        for (chunk, copy) in self.chunks.iter().zip(grid.chunks.iter()) {
+            if let Some(bytes) = chunk.to_bytes() {
+                copy.load_bytes(&bytes);
+            }
+        }
+        grid
+    }
+
+    fn locate(&self, x: usize, y: usize, z: usize) -> Option<(usize, usize)> {
+        if !self.layout.contains(x, y, z) {
+            return None;
+        }
+        let chunk = self.chunk_index(x / CHUNK_SIZE, y / CHUNK_SIZE, z / CHUNK_SIZE);
+        let index = ((x % CHUNK_SIZE) * CHUNK_SIZE + y % CHUNK_SIZE) * CHUNK_SIZE + z % CHUNK_SIZE;
+        Some((chunk, index))
+    }
+
+    fn chunk_index(&self, cx: usize, cy: usize, cz: usize) -> usize {
+        (cx * self.chunk_counts.1 + cy) * self.chunk_counts.2 + cz
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use super::*;
+
+    // Every small size, plus non-cubic ones that cross chunk boundaries.
+    fn sizes() -> Vec<(usize, usize, usize)> {
+        let mut sizes = vec![(3, 5, 7), (7, 5, 3), (17, 1, 33), (16, 16, 16), (33, 17, 2)];
+        for x in 1..=5 {
+            for y in 1..=5 {
+                for z in 1..=5 {
+                    sizes.push((x, y, z));
+                }
+            }
+        }
+        sizes
+    }
+
+    #[test]
+    fn index_and_coords_round_trip() {
+        for size in sizes() {
+            let layout = GridLayout::new(size);
+            assert_eq!(layout.len(), size.0 * size.1 * size.2);
+            for index in 0..layout.len() {
+                let (x, y, z) = layout.coords(index).unwrap();
+                assert!(layout.contains(x, y, z));
+                assert_eq!(layout.index(x, y, z), Some(index), "{:?} {}", size, index);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_bounds() {
+        let mut rng = StdRng::seed_from_u64(8);
+        for size in sizes() {
+            let layout = GridLayout::new(size);
+            assert_eq!(layout.coords(layout.len()), None);
+            assert_eq!(layout.coords(usize::MAX), None);
+            for _ in 0..100 {
+                let x = rng.gen_range(0..size.0 * 2);
+                let y = rng.gen_range(0..size.1 * 2);
+                let z = rng.gen_range(0..size.2 * 2);
+                let inside = x < size.0 && y < size.1 && z < size.2;
+                assert_eq!(layout.contains(x, y, z), inside);
+                assert_eq!(layout.index(x, y, z).is_some(), inside);
+                if !inside {
+                    assert_eq!(layout.legacy_index(x, y, z), None);
+                }
+            }
+            assert_eq!(layout.index(usize::MAX, 0, 0), None);
+        }
+    }
+
+    #[test]
+    fn iterates_with_z_innermost() {
+        for size in sizes() {
+            let layout = GridLayout::new(size);
+            let cells: Vec<_> = layout.iter().collect();
+            assert_eq!(cells.len(), layout.len());
+            for (index, &(x, y, z)) in cells.iter().enumerate() {
+                assert_eq!(layout.index(x, y, z), Some(index));
+            }
+            // Ordering tuples compares x first and z last, so the order is strictly increasing.
+            assert!(cells.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+    }
+
+    #[test]
+    fn flat_bytes_round_trip_through_chunks() {
+        let mut rng = StdRng::seed_from_u64(8);
+        for size in sizes() {
+            let bytes: Vec<u8> = (0..size.0 * size.1 * size.2).map(|_| rng.gen()).collect();
+            let grid = ChunkedGrid::from_bytes(size, &bytes);
+            assert_eq!(grid.to_bytes(), bytes);
+            for ((x, y, z), &color) in grid.layout().iter().zip(&bytes) {
+                assert_eq!(grid.get(x, y, z), color);
+            }
+        }
+    }
+
+    #[test]
+    fn migrates_legacy_grids() {
+        let mut rng = StdRng::seed_from_u64(8);
+        for size in sizes() {
+            let layout = GridLayout::new(size);
+            let legacy: Vec<u8> = (0..layout.len()).map(|_| rng.gen()).collect();
+            let grid = ChunkedGrid::from_legacy_bytes(size, &legacy);
+
+            let cubic = size.0 == size.1 && size.1 == size.2;
+            for (x, y, z) in layout.iter() {
+                let old = x * size.0 * size.0 + y * size.1 + z;
+                let expected = legacy.get(old).copied().unwrap_or(0);
+                assert_eq!(grid.get(x, y, z), expected, "{:?} ({}, {}, {})", size, x, y, z);
+                if cubic {
+                    assert_eq!(layout.legacy_index(x, y, z), layout.index(x, y, z));
+                }
+            }
+            if cubic {
+                assert_eq!(grid.to_bytes(), legacy);
+            }
+        }
+
+        // On (3, 5, 7) the old formula reads (0, 1, 0) from 5 where the new one reads 7.
+        let layout = GridLayout::new((3, 5, 7));
+        assert_eq!(layout.legacy_index(0, 1, 0), Some(5));
+        assert_eq!(layout.index(0, 1, 0), Some(7));
+        assert_eq!(layout.legacy_index(2, 4, 6), Some(2 * 9 + 4 * 5 + 6));
+        assert_eq!(layout.index(2, 4, 6), Some(104));
+    }
+
+    #[test]
+    fn chunk_tracks_filled_cells() {
+        let chunk = Chunk::empty();
+        assert!(chunk.is_empty());
+        chunk.set(0, 0);
+        assert!(chunk.is_empty());
+
+        chunk.set(0, 3);
+        chunk.set(7, 3);
+        chunk.set(7, 5);
+        assert!(!chunk.is_empty());
+        chunk.set(0, 0);
+        assert!(!chunk.is_empty());
+        chunk.set(7, 0);
+        assert!(chunk.is_empty());
+        assert_eq!(chunk.to_bytes(), None);
+
+        chunk.load_bytes(&[1; CHUNK_VOLUME]);
+        assert!(!chunk.is_empty());
+        chunk.load_bytes(&[0; CHUNK_VOLUME]);
+        assert!(chunk.is_empty());
+    }
+
+    #[test]
+    fn concurrent_writes_keep_the_count() {
+        let chunk = Chunk::empty();
+        std::thread::scope(|scope| {
+            for thread in 0..8u8 {
+                let chunk = &chunk;
+                scope.spawn(move || {
+                    for round in 0..1000usize {
+                        let index = (round * 31 + thread as usize) % 64;
+                        chunk.set(index, if round % 3 == 0 { 0 } else { thread + 1 });
+                    }
+                });
+            }
+        });
+        let filled = (0..CHUNK_VOLUME).filter(|&index| chunk.get(index) != 0).count();
+        assert_eq!(chunk.filled.load(Ordering::Acquire) as usize, filled);
+    }
+
+    #[test]
+    fn chunk_stream_starts_with_the_epoch() {
+        let grid = ChunkedGrid::new((20, 20, 20));
+        grid.set(1, 2, 3, 4);
+        let bytes = grid.encode_chunks(&[(0, 0, 0)]).unwrap();
+        assert_eq!(bytes[0..4], grid.epoch().to_le_bytes());
+        assert_eq!(bytes[4..8], 1u32.to_le_bytes());
+        assert_eq!(bytes[14..18], 1u32.to_le_bytes());
+
+        // A reloaded grid restarts its versions, under a new epoch.
+        let reloaded = grid.snapshot();
+        assert_eq!(reloaded.chunk((0, 0, 0)).unwrap().version(), 0);
+        assert_ne!(reloaded.epoch(), grid.epoch());
+    }
+}
@@ -0,0 +1,20 @@
+pub mod app_state;
+pub mod auth;
+pub mod config;
+pub mod database;
+pub mod error;
+pub mod flush;
+pub mod format;
+pub mod grid;
+pub mod mesh;
+pub mod place;
+pub mod render;
+pub mod snapshot;
+pub mod timelapse;
+pub mod voxel;
+pub mod websocket;
+pub mod palette;
+pub mod post;
+pub mod comment;
+pub mod role;
+pub mod user;
@@ -1,49 +1,105 @@
-mod app_state;
-mod database;
-mod place;
-mod voxel;
-mod websocket;
-mod palette;
-mod post;
-mod comment;
-mod user;
-
-use crate::app_state::AppState;
-use crate::database::db::Database;
+use voxplace_server::app_state::AppState;
+use voxplace_server::database::db::Database;
 use actix_cors::Cors;
-use actix_web::web::Data;
+use actix_web::web::{Data, JsonConfig, PathConfig, PayloadConfig, QueryConfig};
 use actix_web::{App, HttpServer};
+use clap::Parser;
 use std::sync::RwLock;
-use crate::comment::{create_comment, get_place_comments, get_post_comments};
-use crate::palette::get_palette;
-use crate::place::{create_place, draw_voxel_http, get_cooldown, get_grid, get_places_info, get_username, ws_index};
-use crate::post::{create_post, get_new_posts, get_post, get_top_posts, vote_post};
-use crate::user::{check_admin, edit_user, get_top_users, get_user_profile, login_user, register_user};
-use crate::voxel::{create_voxel, get_user_voxels, get_voxel, save_voxel};
+use voxplace_server::config::{Cli, Command, Config};
+use voxplace_server::error::ApiError;
+use voxplace_server::flush::{flush_all, run_place_flush};
+use voxplace_server::comment::{create_comment, delete_comment, get_place_comments, get_post_comments};
+use voxplace_server::palette::get_palette;
+use voxplace_server::place::{create_place, get_rollbacks, rollback_user, create_timelapse, get_timelapse, draw_voxel_http, get_chunk_manifest, get_chunks, get_cooldown, get_user_history, get_voxel_history, get_grid, export_place, get_place_mesh, get_places_info, get_username, ws_index};
+use voxplace_server::snapshot::{create_snapshot, diff_snapshot, get_snapshots, restore_snapshot, run_snapshot_schedule};
+use voxplace_server::websocket::run_update_batching;
+use voxplace_server::role::{get_user_roles, grant_role, revoke_role};
+use voxplace_server::post::{create_post, get_new_posts, get_post, get_top_posts, vote_post};
+use voxplace_server::user::{check_admin, edit_user, get_top_users, get_user_profile, login_user, logout_user, logout_user_everywhere, refresh_session, register_user};
+use voxplace_server::voxel::{create_voxel, export_voxel, export_voxel_vox, export_voxel_vxl, get_user_voxels, get_voxel, get_voxel_mesh, get_voxel_thumbnail, import_voxel_vox, import_voxel_vxl, save_voxel};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let db = Database::new();
+    let mut cli = Cli::parse();
+    let config = match Config::load(&mut cli) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let db = match Database::open(&config.database_path) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!("Failed to open {}: {}", config.database_path, e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Err(e) = db.init() {
+        eprintln!("Failed to migrate {}: {}", config.database_path, e);
+        std::process::exit(1);
+    }
+
+    if let Some(Command::Migrate) = cli.command {
+        match db.schema_version() {
+            Ok(version) => println!("{} is at schema version {}", config.database_path, version),
+            Err(e) => eprintln!("Failed to read schema version: {}", e),
+        }
+        return Ok(());
+    }
+
+    let bind_address = config.bind_address.clone();
+    let flush_intervals = config.flush;
+    let cors_origins = config.cors_origins.clone();
+    let any_origin = config.allows_any_origin();
 
-    let app_state = Data::new(RwLock::new(AppState::new(db.unwrap())));
+    let app_state = AppState::new(db, config.clone());
+    // Extractors authenticate with these rather than through the app state lock.
+    let database = Data::from(app_state.database.clone());
+    let config = Data::new(config);
+    let app_state = Data::new(RwLock::new(app_state));
 
-    println!("Starting server on port 8000");
+    actix_web::rt::spawn(run_snapshot_schedule(app_state.clone()));
+    actix_web::rt::spawn(run_update_batching(app_state.clone()));
+    actix_web::rt::spawn(run_place_flush(app_state.clone(), flush_intervals));
+    let flush_state = app_state.clone();
+
+    println!("Starting server on {}", bind_address);
 
     HttpServer::new(move || {
+        let cors = if any_origin {
+            Cors::default().allow_any_origin()
+        } else {
+            cors_origins
+                .iter()
+                .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+        };
+
         App::new()
             .wrap(
-                Cors::default()
-                    .allow_any_origin()
-                    .allow_any_method()
+                cors.allow_any_method()
                     .allow_any_header()
+                    .expose_headers(vec!["X-Place-Seq"])
                     .max_age(3600),
             )
             .app_data(app_state.clone())
+            .app_data(config.clone())
+            .app_data(database.clone())
+            .app_data(PayloadConfig::new(64 * 1024 * 1024))
+            // Malformed bodies, paths and queries get the same JSON errors as the handlers.
+            .app_data(JsonConfig::default().error_handler(|e, _| ApiError::BadRequest(e.to_string()).into()))
+            .app_data(PathConfig::default().error_handler(|e, _| ApiError::BadRequest(e.to_string()).into()))
+            .app_data(QueryConfig::default().error_handler(|e, _| ApiError::BadRequest(e.to_string()).into()))
             .service(get_grid)
             .service(ws_index)
             .service(draw_voxel_http)
             .service(register_user)
             .service(login_user)
+            .service(refresh_session)
+            .service(logout_user)
+            .service(logout_user_everywhere)
             .service(get_places_info)
             .service(get_username)
             .service(check_admin)
@@ -62,11 +118,43 @@ async fn main() -> std::io::Result<()> {
             .service(get_post_comments)
             .service(get_place_comments)
             .service(create_comment)
+            .service(delete_comment)
             .service(get_post)
             .service(get_new_posts)
             .service(vote_post)
+            .service(export_voxel_vxl)
+            .service(import_voxel_vxl)
+            .service(export_voxel_vox)
+            .service(import_voxel_vox)
+            .service(get_voxel_mesh)
+            .service(get_place_mesh)
+            .service(export_voxel)
+            .service(export_place)
+            .service(get_voxel_thumbnail)
+            .service(get_chunk_manifest)
+            .service(get_chunks)
+            .service(get_voxel_history)
+            .service(get_user_history)
+            .service(create_timelapse)
+            .service(get_timelapse)
+            .service(rollback_user)
+            .service(get_rollbacks)
+            .service(create_snapshot)
+            .service(get_snapshots)
+            .service(diff_snapshot)
+            .service(restore_snapshot)
+            .service(get_user_roles)
+            .service(grant_role)
+            .service(revoke_role)
     })
-    .bind("0.0.0.0:8000")?
+    .bind(bind_address)?
     .run()
-    .await
+    .await?;
+
+    // The server stops gracefully on SIGINT and SIGTERM; whatever draws queued since the last
+    // flush is written before exiting.
+    flush_all(&flush_state);
+    println!("Flushed all places");
+
+    Ok(())
 }
@@ -0,0 +1,199 @@
+use crate::voxel::Voxel;
+
+// A voxel (x, y, z) covers the unit cube from (x, y, z) to (x + 1, y + 1, z + 1), so a mesh
+// spans from the origin to the grid size.
+
+pub struct Quad {
+    pub color: u8,
+    pub normal: [f32; 3],
+    pub corners: [[f32; 3]; 4],
+}
+
+pub struct Mesh {
+    pub quads: Vec<Quad>,
+}
+
+impl Mesh {
+    pub fn greedy(voxel: &Voxel) -> Self {
+        Self::build(voxel, true, None)
+    }
+
+    // Unit quads on the outer surface only: faces facing enclosed cavities are culled and no
+    // quads are merged, so the result is watertight and free of T-junctions.
+    pub fn surface(voxel: &Voxel) -> Self {
+        let exterior = Self::exterior(voxel);
+        Self::build(voxel, false, Some(&exterior))
+    }
+
+    pub fn triangles(&self) -> impl Iterator<Item = (&Quad, [[f32; 3]; 3])> {
+        self.quads.iter().flat_map(|quad| {
+            let [a, b, c, d] = quad.corners;
+            [(quad, [a, b, c]), (quad, [a, c, d])]
+        })
+    }
+
+    fn exterior(voxel: &Voxel) -> Vec<bool> {
+        let layout = voxel.grid.layout();
+        let (size_x, size_y, size_z) = layout.size();
+
+        let mut exterior = vec![false; layout.len()];
+        let mut stack = Vec::new();
+        for (x, y, z) in layout.iter() {
+            let on_border = x == 0 || y == 0 || z == 0
+                || x == size_x - 1 || y == size_y - 1 || z == size_z - 1;
+            if on_border && voxel.get(x, y, z) == 0 {
+                exterior[layout.index(x, y, z).unwrap()] = true;
+                stack.push((x, y, z));
+            }
+        }
+
+        while let Some((x, y, z)) = stack.pop() {
+            let neighbors = [
+                (x.wrapping_sub(1), y, z),
+                (x + 1, y, z),
+                (x, y.wrapping_sub(1), z),
+                (x, y + 1, z),
+                (x, y, z.wrapping_sub(1)),
+                (x, y, z + 1),
+            ];
+            for (nx, ny, nz) in neighbors {
+                let i = match layout.index(nx, ny, nz) {
+                    Some(i) => i,
+                    None => continue,
+                };
+                if !exterior[i] && voxel.get(nx, ny, nz) == 0 {
+                    exterior[i] = true;
+                    stack.push((nx, ny, nz));
+                }
+            }
+        }
+
+        exterior
+    }
+
+    fn build(voxel: &Voxel, merge: bool, exterior: Option<&[bool]>) -> Self {
+        let layout = voxel.grid.layout();
+        let size = [voxel.grid_size.0, voxel.grid_size.1, voxel.grid_size.2];
+        let color_at = |position: [usize; 3]| voxel.get(position[0], position[1], position[2]);
+        let is_open = |position: [usize; 3]| match exterior {
+            Some(exterior) => exterior[layout.index(position[0], position[1], position[2]).unwrap()],
+            None => color_at(position) == 0,
+        };
+
+        let mut quads = Vec::new();
+
+        for axis in 0..3 {
+            let u = (axis + 1) % 3;
+            let v = (axis + 2) % 3;
+            let mut mask = vec![0u8; size[u] * size[v]];
+
+            for front in [false, true] {
+                for layer in 0..=size[axis] {
+                    for j in 0..size[v] {
+                        for i in 0..size[u] {
+                            let mut position = [0; 3];
+                            position[u] = i;
+                            position[v] = j;
+
+                            let (behind, behind_open) = if layer > 0 {
+                                position[axis] = layer - 1;
+                                (color_at(position), is_open(position))
+                            } else {
+                                (0, true)
+                            };
+                            let (ahead, ahead_open) = if layer < size[axis] {
+                                position[axis] = layer;
+                                (color_at(position), is_open(position))
+                            } else {
+                                (0, true)
+                            };
+
+                            mask[j * size[u] + i] = match front {
+                                true if behind != 0 && ahead_open => behind,
+                                false if ahead != 0 && behind_open => ahead,
+                                _ => 0,
+                            };
+                        }
+                    }
+
+                    let mut j = 0;
+                    while j < size[v] {
+                        let mut i = 0;
+                        while i < size[u] {
+                            let color = mask[j * size[u] + i];
+                            if color == 0 {
+                                i += 1;
+                                continue;
+                            }
+
+                            let mut width = 1;
+                            let mut height = 1;
+                            if merge {
+                                while i + width < size[u] && mask[j * size[u] + i + width] == color {
+                                    width += 1;
+                                }
+                                'grow: while j + height < size[v] {
+                                    for k in 0..width {
+                                        if mask[(j + height) * size[u] + i + k] != color {
+                                            break 'grow;
+                                        }
+                                    }
+                                    height += 1;
+                                }
+                            }
+
+                            for row in j..j + height {
+                                for k in i..i + width {
+                                    mask[row * size[u] + k] = 0;
+                                }
+                            }
+
+                            quads.push(Self::quad(axis, u, v, layer, i, j, width, height, front, color));
+                            i += width;
+                        }
+                        j += 1;
+                    }
+                }
+            }
+        }
+
+        Self { quads }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn quad(
+        axis: usize,
+        u: usize,
+        v: usize,
+        layer: usize,
+        i: usize,
+        j: usize,
+        width: usize,
+        height: usize,
+        front: bool,
+        color: u8,
+    ) -> Quad {
+        let corner = |du: usize, dv: usize| {
+            let mut point = [0.0; 3];
+            point[axis] = layer as f32;
+            point[u] = (i + du) as f32;
+            point[v] = (j + dv) as f32;
+            point
+        };
+
+        let mut normal = [0.0; 3];
+        normal[axis] = if front { 1.0 } else { -1.0 };
+
+        let corners = if front {
+            [corner(0, 0), corner(width, 0), corner(width, height), corner(0, height)]
+        } else {
+            [corner(0, 0), corner(0, height), corner(width, height), corner(width, 0)]
+        };
+
+        Quad {
+            color,
+            normal,
+            corners,
+        }
+    }
+}
@@ -1,19 +1,54 @@
 use std::sync::RwLock;
-use actix_web::{get, HttpResponse, Responder};
+use actix_web::{get, HttpResponse};
 use actix_web::web::{Data, Path};
 use crate::app_state::AppState;
+use crate::error::ApiError;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Material {
+    pub alpha: u8,
+    pub emission: u8,
+    pub roughness: u8,
+    pub metallic: u8,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            alpha: 255,
+            emission: 0,
+            roughness: 255,
+            metallic: 0,
+        }
+    }
+}
 
 pub struct Palette {
     palette_id: i64,
     colors: Vec<(u8, u8, u8)>,
+    materials: Vec<Material>,
 }
 
 impl Palette {
-    pub fn new(palette_id: i64, colors: Option<Vec<(u8, u8, u8)>>) -> Self {
+    pub fn new(
+        palette_id: i64,
+        colors: Option<Vec<(u8, u8, u8)>>,
+        materials: Option<Vec<Material>>,
+    ) -> Self {
+        let mut palette = Self::with_colors(palette_id, colors);
+        if let Some(materials) = materials {
+            palette.materials = materials;
+        }
+        palette.materials.resize(palette.colors.len(), Material::default());
+        palette
+    }
+
+    fn with_colors(palette_id: i64, colors: Option<Vec<(u8, u8, u8)>>) -> Self {
         if let Some(colors) = colors {
             Self {
                 palette_id,
-                colors
+                colors,
+                materials: Vec::new(),
             }
         } else {
             let colors: Vec<(u8, u8, u8)> = vec![
@@ -53,6 +88,7 @@ impl Palette {
             Self {
                 palette_id,
                 colors,
+                materials: Vec::new(),
             }
         }
     }
@@ -64,37 +100,36 @@ impl Palette {
     pub fn colors(&self) -> &Vec<(u8, u8, u8)> {
         &self.colors
     }
+
+    pub fn materials(&self) -> &Vec<Material> {
+        &self.materials
+    }
+
+    pub fn same_entries(&self, other: &Palette) -> bool {
+        self.colors == other.colors && self.materials == other.materials
+    }
 }
 
 #[get("/api/palette/get/{id}")]
 async fn get_palette(
     data: Data<RwLock<AppState>>,
     path: Path<String>
-) -> impl Responder {
-    let id = match path.into_inner().parse::<i64>() {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid voxel"),
-    };
+) -> Result<HttpResponse, ApiError> {
+    let id = path
+        .into_inner()
+        .parse::<i64>()
+        .map_err(|_| ApiError::BadRequest("Invalid palette".to_string()))?;
 
-    let app_state = match data.read() {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read app state"),
-    };
+    let app_state = data.read()?;
 
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read database"),
-    };
+    let db = app_state.database.lock()?;
 
-    let palette = match db.get_palette(id) {
-        Ok(palette) => palette,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read palette"),
-    };
+    let palette = db.get_palette(id)?;
 
     let palette_hex: Vec<String> = palette
         .iter()
         .map(|&(r, g, b)| format!("#{:02x}{:02x}{:02x}", r, g, b))
         .collect();
 
-    HttpResponse::Ok().json(palette_hex)
-}
\ No newline at end of file
+    Ok(HttpResponse::Ok().json(palette_hex))
+}
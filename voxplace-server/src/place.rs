@@ -1,26 +1,41 @@
+use std::collections::HashMap;
 use std::io::Write;
-use crate::voxel::Voxel;
-use std::sync::{Arc, RwLock};
-use actix_web::{get, web, Error, HttpRequest, HttpResponse, post, Responder};
+use crate::voxel::{export_mesh, ExportQuery, Voxel};
+use std::sync::{Arc, Mutex, RwLock};
+use actix_web::{get, web, HttpRequest, HttpResponse, post};
 use actix_web::http::header;
-use actix_web::web::{Data, Json, Path};
+use actix_web::web::{Data, Json, Path, Query};
 use chrono::Utc;
 use flate2::Compression;
 use flate2::write::GzEncoder;
 use rand::{Rng, thread_rng};
 use serde_derive::{Deserialize, Serialize};
 use crate::app_state::AppState;
-use crate::database::place::PlaceUserUpdate;
-use crate::user::{check_user, check_user_admin};
+use crate::auth::{AdminUser, AuthUser};
+use crate::config::grid_size_allowed;
+use crate::database::db::DatabaseError;
+use crate::database::place::{PlaceRollback, PlaceUserUpdate, VoxelChange};
+use crate::error::ApiError;
+use crate::flush::flush_place;
+use crate::format::gltf;
+use crate::grid::{ChunkCoords, CHUNK_SIZE};
+use crate::mesh::Mesh;
+use crate::palette::Palette;
+use crate::role::Role;
+use crate::timelapse::{build_timelapse, frame_cutoffs, TimelapseJob, TimelapseOptions};
 use crate::websocket::PlaceWebSocketConnection;
 
+const MAX_CHUNKS_PER_REQUEST: usize = 4096;
+
 pub struct Place {
     pub id: i64,
     pub online: bool,
     pub cooldown: i64,
     pub voxel: Arc<Voxel>,
+    cooldowns: Mutex<HashMap<i64, i64>>,
     pending_updates: RwLock<Vec<PlaceUserUpdate>>,
-    last_grid_update: i64,
+    pending_events: Mutex<Vec<VoxelChange>>,
+    pending_cooldowns: Mutex<HashMap<i64, i64>>,
 }
 
 impl Place {
@@ -30,11 +45,58 @@ impl Place {
             online,
             cooldown,
             voxel: Arc::new(voxel),
+            cooldowns: Mutex::new(HashMap::new()),
             pending_updates: RwLock::new(Vec::new()),
-            last_grid_update: 0,
+            pending_events: Mutex::new(Vec::new()),
+            pending_cooldowns: Mutex::new(HashMap::new()),
         }
     }
 
+    pub fn load_cooldowns(&self, cooldowns: HashMap<i64, i64>) {
+        self.cooldowns.lock().unwrap().extend(cooldowns);
+    }
+
+    pub fn user_cooldown(&self, user_id: i64) -> i64 {
+        self.cooldowns.lock().unwrap().get(&user_id).copied().unwrap_or(0)
+    }
+
+    // Cooldowns are checked and set in memory, under a lock held for the whole draw so a user's
+    // concurrent draws can't both pass. The event, cooldown and PlaceUser row are queued for
+    // `flush_place`. Returns when the user's new cooldown ends.
+    pub fn draw(&self, user_id: i64, x: usize, y: usize, z: usize, color: u8) -> Result<i64, DrawError> {
+        let time = Utc::now().timestamp();
+
+        let mut cooldowns = self.cooldowns.lock().unwrap();
+        let user_cooldown = cooldowns.get(&user_id).copied().unwrap_or(0);
+        if user_cooldown > time {
+            return Err(DrawError::Cooldown(user_cooldown));
+        }
+
+        let old_color = match self.voxel.draw_voxel(x, y, z, color) {
+            Ok(old_color) => old_color,
+            Err(e) => return Err(DrawError::Rejected(e)),
+        };
+
+        let cooldown = time + self.cooldown;
+        cooldowns.retain(|_, cooldown| *cooldown > time);
+        cooldowns.insert(user_id, cooldown);
+        drop(cooldowns);
+
+        self.pending_cooldowns.lock().unwrap().insert(user_id, cooldown);
+        self.pending_events.lock().unwrap().push(VoxelChange {
+            user_id,
+            x,
+            y,
+            z,
+            old_color,
+            new_color: color,
+            created_at: time,
+        });
+        self.add_place_update(x, y, z, user_id);
+
+        Ok(cooldown)
+    }
+
     pub fn add_place_update(&self, x: usize, y: usize, z: usize, user_id: i64) {
         let voxel_update = PlaceUserUpdate {
             x,
@@ -52,12 +114,29 @@ impl Place {
         updates
     }
 
-    pub fn last_grid_update(&self) -> i64 {
-        self.last_grid_update
+    pub fn take_events(&self) -> Vec<VoxelChange> {
+        self.pending_events.lock().unwrap().drain(..).collect()
+    }
+
+    pub fn take_cooldowns(&self) -> Vec<(i64, i64)> {
+        self.pending_cooldowns.lock().unwrap().drain().collect()
+    }
+
+    // Puts back writes that failed to save, ahead of anything queued since. A cooldown queued
+    // since is newer, so it wins over the one put back.
+    pub fn requeue_place_updates(&self, updates: Vec<PlaceUserUpdate>) {
+        self.pending_updates.write().unwrap().splice(0..0, updates);
     }
 
-    pub fn set_last_grid_update(&mut self, last_grid_update: i64) {
-        self.last_grid_update = last_grid_update;
+    pub fn requeue_events(&self, events: Vec<VoxelChange>) {
+        self.pending_events.lock().unwrap().splice(0..0, events);
+    }
+
+    pub fn requeue_cooldowns(&self, cooldowns: Vec<(i64, i64)>) {
+        let mut pending_cooldowns = self.pending_cooldowns.lock().unwrap();
+        for (user_id, cooldown) in cooldowns {
+            pending_cooldowns.entry(user_id).or_insert(cooldown);
+        }
     }
 }
 
@@ -73,7 +152,7 @@ struct DrawRequest {
 struct CreatePlaceRequest {
     name: String,
     size: (usize, usize, usize),
-    cooldown: usize,
+    cooldown: Option<usize>,
 }
 
 #[derive(Serialize)]
@@ -82,6 +161,49 @@ struct DrawResponse {
     cooldown: i64,
 }
 
+#[derive(Deserialize)]
+struct ChunksRequest {
+    chunks: Option<Vec<ChunkCoords>>,
+    min: Option<(usize, usize, usize)>,
+    max: Option<(usize, usize, usize)>,
+}
+
+#[derive(Serialize)]
+struct ChunkManifest {
+    epoch: u32,
+    chunk_size: usize,
+    chunk_counts: (usize, usize, usize),
+    chunks: Vec<(usize, usize, usize, u32)>,
+}
+
+#[derive(Deserialize)]
+struct VoxelHistoryQuery {
+    x: usize,
+    y: usize,
+    z: usize,
+}
+
+#[derive(Deserialize)]
+struct UserHistoryQuery {
+    before: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct RollbackRequest {
+    user_id: String,
+    from: Option<i64>,
+    to: Option<i64>,
+    min: Option<(usize, usize, usize)>,
+    max: Option<(usize, usize, usize)>,
+}
+
+#[derive(Serialize)]
+struct RollbackResponse {
+    rollback_id: String,
+    reverted: usize,
+}
+
 #[derive(Deserialize)]
 struct UsernameRequest {
     x: usize,
@@ -93,170 +215,119 @@ struct UsernameRequest {
 async fn create_place(
     data: Data<RwLock<AppState>>,
     json: Json<CreatePlaceRequest>,
-    req: HttpRequest,
-) -> impl Responder {
-    {
-        let user_id = match check_user(req) {
-            Ok(user_id) => user_id,
-            Err(res) => return res,
-        };
+    admin: AdminUser,
+) -> Result<HttpResponse, ApiError> {
+    let voxel_id = thread_rng().gen::<i64>();
+    let place_id = thread_rng().gen::<i64>();
 
-        let is_admin = match check_user_admin(user_id, &data) {
-            Ok(is_admin) => is_admin,
-            Err(res) => return res,
-        };
+    let mut app_state = data.write()?;
 
-        if !is_admin {
-            return HttpResponse::Unauthorized().body("You are not an admin");
-        }
+    if !grid_size_allowed(json.size, app_state.config.max_place_size) {
+        return Err(ApiError::InvalidSize("Place", app_state.config.max_place_size));
     }
 
-    let voxel_id = thread_rng().gen::<i64>();
-    let place_id = thread_rng().gen::<i64>();
-
-    let mut app_state = match data.write() {
-        Ok(app_state) => app_state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock app state"),
-    };
+    let cooldown = json.cooldown.map(|cooldown| cooldown as i64).unwrap_or(app_state.config.default_cooldown);
 
     let voxel = Voxel::new(voxel_id, &json.name, 0, json.size, None, None, None);
 
-    let place = Place::new(place_id, true, json.cooldown as i64, voxel);
+    let place = Place::new(place_id, true, cooldown, voxel);
     app_state.add_place(place);
 
-    HttpResponse::Ok().json("ok")
+    // The creator keeps the place if they stop being an admin.
+    app_state.database.lock()?.grant_role(admin.id, Role::PlaceOwner, Some(place_id))?;
+
+    Ok(HttpResponse::Ok().json("ok"))
+}
+
+pub enum DrawError {
+    Cooldown(i64),
+    Rejected(String),
+}
+
+impl DrawError {
+    pub fn message(&self) -> String {
+        match self {
+            DrawError::Cooldown(_) => "Cooldown not finished".to_string(),
+            DrawError::Rejected(e) => e.clone(),
+        }
+    }
+}
+
+pub fn parse_place_id(id: &str) -> Result<i64, ApiError> {
+    id.parse::<i64>().map_err(|_| ApiError::BadRequest("Invalid place".to_string()))
+}
+
+// Only places that are loaded, i.e. online, can be found here.
+pub fn no_such_place() -> ApiError {
+    ApiError::NotFound("No such place".to_string())
 }
 
 #[post("/api/place/draw/{id}")]
 async fn draw_voxel_http(
     data: Data<RwLock<AppState>>,
-    req: HttpRequest,
+    user: AuthUser,
     json: Json<DrawRequest>,
     path: Path<String>,
-) -> impl Responder {
-    let id = match path.into_inner().parse::<i64>() {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid place"),
-    };
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_place_id(&path.into_inner())?;
 
-    let mut app_state = match data.write() {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read app state"),
-    };
+    let user_id = user.id;
 
-    let place = match app_state.places.get(&id) {
-        Some(place) => place,
-        None => return HttpResponse::BadRequest().body("Invalid place"),
+    // The app state is only read to find the place; the draw itself locks nothing else.
+    let (place, username) = {
+        let app_state = data.read()?;
+        (app_state.places.get(&id).cloned(), app_state.username(user_id))
     };
 
-    let user_id = match check_user(req) {
-        Ok(id) => id,
-        Err(res) => return res,
-    };
-
-    let time = Utc::now().timestamp();
-
-    let username;
-    let cooldown;
-
-    {
-        let db = match app_state.database.lock() {
-            Ok(db) => db,
-            Err(_) => return HttpResponse::InternalServerError().body("Failed to read database"),
-        };
-
-        let user_cooldown = db.get_user_cooldown(id, user_id).unwrap_or(0);
-
-        if user_cooldown > time {
-            return HttpResponse::BadRequest().body("Cooldown not finished");
-        }
-
-        let place = place.read().unwrap();
-
-        match db.set_user_cooldown(id, user_id, time + place.cooldown) {
-            Ok(_) => (),
-            Err(_) => return HttpResponse::InternalServerError().body("Failed to set cooldown"),
-        }
-
-        username = match db.get_username(user_id) {
-            Ok(username) => username,
-            Err(_) => return HttpResponse::InternalServerError().body("Failed to get username"),
-        };
-
-        cooldown = place.cooldown;
-
-        match place.voxel.draw_voxel(json.x, json.y, json.z, json.color) {
-            Ok(_) => (),
-            Err(e) => return HttpResponse::BadRequest().body(e),
-        };
-
-        place.add_place_update(json.x, json.y, json.z, user_id);
-    }
-
-    app_state.places_users_updates();
-
-    app_state.update_place_grid(id);
+    let place = place.ok_or_else(no_such_place)?;
+    let username = username.ok_or(ApiError::Database(DatabaseError::NoSuchUser()))?;
 
-    let response = DrawResponse {
-        username,
-        cooldown: cooldown + time,
-    };
+    let cooldown = place.read()?.draw(user_id, json.x, json.y, json.z, json.color)?;
 
-    HttpResponse::Ok().json(response)
+    Ok(HttpResponse::Ok().json(DrawResponse { username, cooldown }))
 }
 
 #[get("/api/place/cooldown/{id}")]
 async fn get_cooldown(
     data: Data<RwLock<AppState>>,
-    req: HttpRequest,
+    user: AuthUser,
     path: Path<String>,
-) -> impl Responder {
-    let id = match path.into_inner().parse::<i64>() {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid place"),
-    };
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_place_id(&path.into_inner())?;
 
-    let user_id = match check_user(req) {
-        Ok(id) => id,
-        Err(res) => return res,
-    };
+    let user_id = user.id;
 
-    let app_state = match data.read() {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read app state"),
-    };
+    let app_state = data.read()?;
 
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read database"),
+    let cooldown = match app_state.places.get(&id) {
+        Some(place) => place.read()?.user_cooldown(user_id),
+        None => 0,
     };
 
-    let cooldown = db.get_user_cooldown(id, user_id).unwrap_or(0);
-
-    HttpResponse::Ok().json(cooldown)
+    Ok(HttpResponse::Ok().json(cooldown))
 }
 
 
 #[get("/api/place/infos")]
 async fn get_places_info(
     data: Data<RwLock<AppState>>
-) -> impl Responder {
-    let app_state = match data.read() {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read app state"),
-    };
-
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read database"),
-    };
-
-    let places_infos = match db.get_places_infos() {
-        Ok(places_infos) => places_infos,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to get places infos"),
-    };
+) -> Result<HttpResponse, ApiError> {
+    let app_state = data.read()?;
+
+    let mut places_infos = app_state.database.lock()?.get_places_infos()?;
+
+    for place_info in places_infos.iter_mut() {
+        let place = place_info
+            .place_id
+            .parse::<i64>()
+            .ok()
+            .and_then(|id| app_state.places.get(&id));
+        if let Some(place) = place {
+            place_info.viewers = place.read()?.voxel.viewers();
+        }
+    }
 
-    HttpResponse::Ok().json(places_infos)
+    Ok(HttpResponse::Ok().json(places_infos))
 }
 
 #[post("/api/place/username/{id}")]
@@ -264,21 +335,12 @@ async fn get_username(
     data: Data<RwLock<AppState>>,
     json: Json<UsernameRequest>,
     path: Path<String>,
-) -> impl Responder {
-    let id = match path.into_inner().parse::<i64>() {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid place"),
-    };
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_place_id(&path.into_inner())?;
 
-    let app_state = match data.read() {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read app state"),
-    };
+    let app_state = data.read()?;
 
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to get database"),
-    };
+    let db = app_state.database.lock()?;
 
     let user_id = match db.get_place_user(
         id,
@@ -287,15 +349,269 @@ async fn get_username(
         json.z as i64,
     ) {
         Ok(user_id) => user_id,
-        Err(_) => return HttpResponse::Ok().json("Empty / Server"),
+        Err(_) => return Ok(HttpResponse::Ok().json("Empty / Server")),
     };
 
     let username = match db.get_username(user_id) {
         Ok(username) => username,
-        Err(_) => return HttpResponse::Ok().json(user_id.to_string()),
+        Err(_) => return Ok(HttpResponse::Ok().json(user_id.to_string())),
+    };
+
+    Ok(HttpResponse::Ok().json(username))
+}
+
+#[get("/api/place/history/{id}/voxel")]
+async fn get_voxel_history(
+    data: Data<RwLock<AppState>>,
+    query: Query<VoxelHistoryQuery>,
+    path: Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_place_id(&path.into_inner())?;
+
+    let app_state = data.read()?;
+
+    let db = app_state.database.lock()?;
+
+    Ok(HttpResponse::Ok().json(db.get_voxel_events(id, (query.x, query.y, query.z))?))
+}
+
+#[get("/api/place/history/{id}/user/{user_id}")]
+async fn get_user_history(
+    data: Data<RwLock<AppState>>,
+    query: Query<UserHistoryQuery>,
+    path: Path<(String, String)>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let (id, user_id) = path.into_inner();
+    let id = parse_place_id(&id)?;
+    user.require(Role::Moderator, Some(id))?;
+    let user_id = user_id
+        .parse::<i64>()
+        .map_err(|_| ApiError::BadRequest("Invalid user".to_string()))?;
+
+    let before = query.before.unwrap_or(i64::MAX);
+    let limit = query.limit.unwrap_or(100).clamp(1, 1000);
+
+    let app_state = data.read()?;
+
+    let db = app_state.database.lock()?;
+
+    Ok(HttpResponse::Ok().json(db.get_user_events(id, user_id, before, limit)?))
+}
+
+#[post("/api/place/timelapse/{id}")]
+async fn create_timelapse(
+    data: Data<RwLock<AppState>>,
+    json: Json<TimelapseOptions>,
+    path: Path<String>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_place_id(&path.into_inner())?;
+    user.require(Role::PlaceOwner, Some(id))?;
+
+    let app_state = data.read()?;
+
+    // Held for writing so no draw lands between the flush and the copy of the grid.
+    let place = app_state.places.get(&id).ok_or_else(no_such_place)?.write()?;
+
+    // Queued draws are flushed and the grid is taken before the log, so that every change it
+    // contains is in the log; changes logged in between are undone to the state the grid has.
+    let (voxel, palette, changes) = {
+        let db = app_state.database.lock()?;
+        flush_place(&db, &place, false);
+        let voxel = place.voxel.snapshot();
+        let palette = db.get_full_palette(voxel.palette_id)?;
+        let changes = db.get_place_changes(id)?;
+        (voxel, palette, changes)
     };
+    drop(place);
+
+    let options = json.into_inner();
+    let cutoffs = frame_cutoffs(&options, &changes)
+        .ok_or_else(|| ApiError::BadRequest("Invalid time range".to_string()))?;
+
+    {
+        let mut timelapses = app_state.timelapses.lock()?;
+        if let Some(TimelapseJob::Running) = timelapses.get(&id) {
+            return Err(ApiError::Conflict("Timelapse already running".to_string()));
+        }
+        timelapses.insert(id, TimelapseJob::Running);
+    }
+
+    drop(app_state);
 
-    HttpResponse::Ok().json(username)
+    let data = data.clone();
+    actix_web::rt::spawn(async move {
+        let job = match web::block(move || build_timelapse(voxel, &palette, &changes, &cutoffs, options)).await {
+            Ok(Ok(bytes)) => TimelapseJob::Done(web::Bytes::from(bytes)),
+            Ok(Err(e)) => TimelapseJob::Failed(e.to_string()),
+            Err(e) => TimelapseJob::Failed(e.to_string()),
+        };
+
+        if let Ok(app_state) = data.read() {
+            if let Ok(mut timelapses) = app_state.timelapses.lock() {
+                timelapses.insert(id, job);
+            }
+        }
+    });
+
+    Ok(HttpResponse::Accepted().json("ok"))
+}
+
+#[get("/api/place/timelapse/{id}")]
+async fn get_timelapse(
+    data: Data<RwLock<AppState>>,
+    path: Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_place_id(&path.into_inner())?;
+
+    let app_state = data.read()?;
+
+    let timelapses = app_state.timelapses.lock()?;
+
+    match timelapses.get(&id) {
+        Some(TimelapseJob::Done(bytes)) => Ok(HttpResponse::Ok()
+            .content_type("image/apng")
+            .append_header((
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"timelapse-{}.png\"", id),
+            ))
+            .body(bytes.clone())),
+        Some(TimelapseJob::Running) => Ok(HttpResponse::Accepted().body("Timelapse is still running")),
+        Some(TimelapseJob::Failed(e)) => Err(ApiError::Internal(format!("Failed to build timelapse: {}", e))),
+        None => Err(ApiError::NotFound("No timelapse for this place".to_string())),
+    }
+}
+
+// `changes` holds every change to the voxels the user touched, oldest first. Walking each voxel's
+// changes newest first, the user's matching edits are peeled off until someone else's edit (or an
+// edit outside the window) is reached; voxels someone else has drawn over since are left alone.
+fn plan_rollback(changes: &[VoxelChange], user_id: i64, request: &RollbackRequest) -> Vec<VoxelChange> {
+    let in_window = |change: &VoxelChange| {
+        request.from.is_none_or(|from| change.created_at >= from)
+            && request.to.is_none_or(|to| change.created_at <= to)
+    };
+    let in_box = |change: &VoxelChange| {
+        request.min.is_none_or(|min| change.x >= min.0 && change.y >= min.1 && change.z >= min.2)
+            && request.max.is_none_or(|max| change.x < max.0 && change.y < max.1 && change.z < max.2)
+    };
+
+    let mut by_voxel: HashMap<(usize, usize, usize), Vec<&VoxelChange>> = HashMap::new();
+    for change in changes.iter().filter(|change| in_box(change)) {
+        by_voxel.entry((change.x, change.y, change.z)).or_default().push(change);
+    }
+
+    let mut reverts = Vec::new();
+    for ((x, y, z), voxel_changes) in by_voxel {
+        let mut target = None;
+        for change in voxel_changes.iter().rev() {
+            if change.user_id != user_id || !in_window(change) {
+                break;
+            }
+            target = Some(change.old_color);
+        }
+
+        let current = voxel_changes.last().map(|change| change.new_color).unwrap_or(0);
+        if let Some(target) = target {
+            if target != current {
+                reverts.push(VoxelChange {
+                    user_id,
+                    x,
+                    y,
+                    z,
+                    old_color: current,
+                    new_color: target,
+                    created_at: 0,
+                });
+            }
+        }
+    }
+    reverts
+}
+
+#[post("/api/place/rollback/{id}")]
+async fn rollback_user(
+    data: Data<RwLock<AppState>>,
+    json: Json<RollbackRequest>,
+    path: Path<String>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_place_id(&path.into_inner())?;
+    user.require(Role::Moderator, Some(id))?;
+    let admin_id = user.id;
+
+    let user_id = json
+        .user_id
+        .parse::<i64>()
+        .map_err(|_| ApiError::BadRequest("Invalid user".to_string()))?;
+
+    let app_state = data.read()?;
+
+    let place = app_state.places.get(&id).ok_or_else(no_such_place)?.clone();
+
+    // Held for writing so no draw can land between reading the log and applying the rollback.
+    let place = place.write()?;
+
+    let db = app_state.database.lock()?;
+
+    // Queued draws are written first so the log is complete.
+    flush_place(&db, &place, false);
+
+    let changes = db.get_user_voxel_changes(id, user_id)?;
+
+    let time = Utc::now().timestamp();
+    let rollback_id = thread_rng().gen::<i64>();
+    let mut reverts = plan_rollback(&changes, user_id, &json);
+
+    for revert in reverts.iter_mut() {
+        revert.user_id = admin_id;
+        revert.old_color = place.voxel.get(revert.x, revert.y, revert.z);
+        revert.created_at = time;
+        place.voxel.overwrite_voxel(revert.x, revert.y, revert.z, revert.new_color);
+        place.add_place_update(revert.x, revert.y, revert.z, admin_id);
+    }
+
+    if let Err(e) = db.save_place_events(id, &reverts) {
+        eprintln!("Failed to save place events: {}", e);
+    }
+
+    let rollback = PlaceRollback {
+        rollback_id,
+        place_id: id,
+        admin_id,
+        user_id,
+        from: json.from,
+        to: json.to,
+        min: json.min,
+        max: json.max,
+        reverted: reverts.len(),
+        created_at: time,
+    };
+
+    db.save_place_rollback(&rollback)?;
+
+    flush_place(&db, &place, true);
+
+    Ok(HttpResponse::Ok().json(RollbackResponse {
+        rollback_id: rollback_id.to_string(),
+        reverted: reverts.len(),
+    }))
+}
+
+#[get("/api/place/rollbacks/{id}")]
+async fn get_rollbacks(
+    data: Data<RwLock<AppState>>,
+    path: Path<String>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_place_id(&path.into_inner())?;
+    user.require(Role::Moderator, Some(id))?;
+
+    let app_state = data.read()?;
+
+    let db = app_state.database.lock()?;
+
+    Ok(HttpResponse::Ok().json(db.get_place_rollbacks(id)?))
 }
 
 #[get("/api/place/ws/{id}")]
@@ -304,16 +620,21 @@ async fn ws_index(
     stream: web::Payload,
     data: web::Data<RwLock<AppState>>,
     path: web::Path<String>,
-) -> Result<HttpResponse, Error> {
-    let id = path.into_inner().parse::<i64>().map_err(|_| actix_web::error::ErrorBadRequest("Invalid place"))?;
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_place_id(&path.into_inner())?;
+
+    let app_state = data.read()?;
 
-    let app_state = data.read().map_err(|_| actix_web::error::ErrorInternalServerError("Failed to read app state"))?;
+    let place = app_state.places.get(&id).ok_or_else(no_such_place)?;
 
-    let place = app_state.places.get(&id).ok_or_else(|| actix_web::error::ErrorBadRequest("Invalid place"))?;
+    let voxel = place.read()?.voxel.clone();
 
-    let ws_connection = PlaceWebSocketConnection::new(place.clone());
+    let ws_connection = PlaceWebSocketConnection::new(place.clone(), voxel, id, data.clone());
 
-    let (response, session, msg_stream) = actix_ws::handle(&req, stream)?;
+    drop(app_state);
+
+    let (response, session, msg_stream) = actix_ws::handle(&req, stream)
+        .map_err(|e| ApiError::BadRequest(e.to_string()))?;
 
     actix_web::rt::spawn(async move {
         ws_connection.run(session, msg_stream).await;
@@ -322,42 +643,201 @@ async fn ws_index(
     Ok(response)
 }
 
-#[get("/api/place/all/{id}")]
-async fn get_grid(
+// The last mesh of each place is kept with the grid version it was built from, so it is only
+// rebuilt once the place has been drawn on.
+#[get("/api/place/mesh/{id}.glb")]
+async fn get_place_mesh(
     data: Data<RwLock<AppState>>,
-    path: Path<String>
-) -> impl Responder {
-    let id = match path.into_inner().parse::<i64>() {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid place"),
-    };
+    path: Path<String>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_place_id(&path.into_inner())?;
+
+    let etag = {
+        let app_state = data.read()?;
+
+        let place = app_state.places.get(&id).ok_or_else(no_such_place)?.read()?;
+        let etag = format!("\"{}-{}-{}\"", id, place.voxel.grid.epoch(), place.voxel.grid.version());
+
+        let not_modified = req
+            .headers()
+            .get(header::IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value == etag);
+        if not_modified {
+            return Ok(HttpResponse::NotModified().append_header((header::ETAG, etag)).finish());
+        }
+
+        let cached = match app_state.place_meshes.lock() {
+            Ok(meshes) => meshes.get(&id).filter(|(cached, _)| *cached == etag).map(|(_, bytes)| bytes.clone()),
+            Err(_) => None,
+        };
+        if let Some(bytes) = cached {
+            return Ok(place_mesh_response(bytes, etag));
+        }
 
-    let app_state = match data.read() {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read app state"),
+        etag
     };
 
-    let place = match app_state.places.get(&id) {
-        Some(place) => place,
-        None => return HttpResponse::BadRequest().body("Invalid place"),
+    let block_data = data.clone();
+    let bytes = web::block(move || {
+        let (voxel, palette) = load_place_voxel(&block_data, id)?;
+        let mesh = Mesh::greedy(&voxel);
+        gltf::encode_glb(&voxel.name, &mesh, &palette)
+            .map_err(|_| ApiError::Internal("Failed to encode mesh".to_string()))
+    })
+    .await
+    .map_err(|_| ApiError::Internal("Failed to encode mesh".to_string()))??;
+    let bytes = web::Bytes::from(bytes);
+
+    if let Ok(app_state) = data.read() {
+        if let Ok(mut meshes) = app_state.place_meshes.lock() {
+            meshes.insert(id, (etag.clone(), bytes.clone()));
+        }
+    }
+
+    Ok(place_mesh_response(bytes, etag))
+}
+
+fn place_mesh_response(bytes: web::Bytes, etag: String) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("model/gltf-binary")
+        .append_header((header::ETAG, etag))
+        .append_header((header::CACHE_CONTROL, "no-cache"))
+        .body(bytes)
+}
+
+#[get("/api/place/export/{id}")]
+async fn export_place(
+    data: Data<RwLock<AppState>>,
+    path: Path<String>,
+    query: Query<ExportQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_place_id(&path.into_inner())?;
+
+    export_mesh(move || load_place_voxel(&data, id), query.into_inner()).await
+}
+
+// A copy of the live grid, so meshing doesn't hold the place.
+fn load_place_voxel(data: &Data<RwLock<AppState>>, id: i64) -> Result<(Voxel, Palette), ApiError> {
+    let app_state = data.read()?;
+
+    let voxel = app_state.places.get(&id).ok_or_else(no_such_place)?.read()?.voxel.snapshot();
+
+    let palette = app_state.database.lock()?.get_full_palette(voxel.palette_id)?;
+
+    Ok((voxel, palette))
+}
+
+#[get("/api/place/chunks/{id}")]
+async fn get_chunk_manifest(
+    data: Data<RwLock<AppState>>,
+    path: Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_place_id(&path.into_inner())?;
+
+    let app_state = data.read()?;
+
+    let place = app_state.places.get(&id).ok_or_else(no_such_place)?.read()?;
+    let grid = &place.voxel.grid;
+
+    let chunks = grid
+        .chunks()
+        .filter(|(_, chunk)| !chunk.is_empty())
+        .map(|((cx, cy, cz), chunk)| (cx, cy, cz, chunk.version()))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(ChunkManifest {
+        epoch: grid.epoch(),
+        chunk_size: CHUNK_SIZE,
+        chunk_counts: grid.chunk_counts(),
+        chunks,
+    }))
+}
+
+#[post("/api/place/chunks/{id}")]
+async fn get_chunks(
+    data: Data<RwLock<AppState>>,
+    json: Json<ChunksRequest>,
+    path: Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_place_id(&path.into_inner())?;
+
+    let app_state = data.read()?;
+
+    let place = app_state.places.get(&id).ok_or_else(no_such_place)?.read()?;
+    let grid = &place.voxel.grid;
+    let (count_x, count_y, count_z) = grid.chunk_counts();
+    let too_many = || ApiError::BadRequest("Too many chunks requested".to_string());
+
+    let coords: Vec<ChunkCoords> = match (&json.chunks, json.min, json.max) {
+        (Some(chunks), None, None) => chunks.clone(),
+        (None, Some(min), Some(max)) => {
+            let from = (min.0 / CHUNK_SIZE, min.1 / CHUNK_SIZE, min.2 / CHUNK_SIZE);
+            let to = (
+                max.0.div_ceil(CHUNK_SIZE).min(count_x),
+                max.1.div_ceil(CHUNK_SIZE).min(count_y),
+                max.2.div_ceil(CHUNK_SIZE).min(count_z),
+            );
+            let mut coords = Vec::new();
+            for cx in from.0..to.0 {
+                for cy in from.1..to.1 {
+                    for cz in from.2..to.2 {
+                        coords.push((cx, cy, cz));
+                        if coords.len() > MAX_CHUNKS_PER_REQUEST {
+                            return Err(too_many());
+                        }
+                    }
+                }
+            }
+            coords
+        }
+        _ => return Err(ApiError::BadRequest("Expected either chunks or min and max".to_string())),
     };
 
-    let place = place.read().unwrap();
+    if coords.len() > MAX_CHUNKS_PER_REQUEST {
+        return Err(too_many());
+    }
+
+    let bytes = grid
+        .encode_chunks(&coords)
+        .map_err(|_| ApiError::Internal("Failed to encode chunks".to_string()))?;
 
-    let grid: Vec<u8> = place.voxel.grid.iter().map(|cell| cell.load()).collect();
+    drop(place);
+    drop(app_state);
 
+    let compress_error = |_| ApiError::Internal("Failed to compress data".to_string());
     let mut e = GzEncoder::new(Vec::new(), Compression::default());
-    match e.write_all(&grid) {
-        Ok(_) => (),
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to compress data"),
-    };
+    e.write_all(&bytes).map_err(compress_error)?;
+    let compressed_data = e.finish().map_err(compress_error)?;
 
-    let compressed_data = match e.finish() {
-        Ok(data) => data,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to compress data"),
-    };
+    Ok(HttpResponse::Ok()
+        .append_header((header::CONTENT_ENCODING, "gzip"))
+        .body(compressed_data))
+}
 
-    HttpResponse::Ok()
+#[get("/api/place/all/{id}")]
+async fn get_grid(
+    data: Data<RwLock<AppState>>,
+    path: Path<String>
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_place_id(&path.into_inner())?;
+
+    let app_state = data.read()?;
+
+    let place = app_state.places.get(&id).ok_or_else(no_such_place)?.read()?;
+
+    // Read before the grid: every update missing from it comes at or after this number.
+    let seq = place.voxel.next_seq();
+    let grid = place.voxel.get_grid_bytes();
+
+    let compress_error = |_| ApiError::Internal("Failed to compress data".to_string());
+    let mut e = GzEncoder::new(Vec::new(), Compression::default());
+    e.write_all(&grid).map_err(compress_error)?;
+    let compressed_data = e.finish().map_err(compress_error)?;
+
+    Ok(HttpResponse::Ok()
         .append_header((header::CONTENT_ENCODING, "gzip"))
-        .body(compressed_data)
-}
\ No newline at end of file
+        .append_header(("X-Place-Seq", seq.to_string()))
+        .body(compressed_data))
+}
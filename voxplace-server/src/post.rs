@@ -1,25 +1,29 @@
 use std::sync::RwLock;
-use actix_web::{get, HttpRequest, HttpResponse, post, Responder};
+use actix_web::{get, HttpResponse, post};
 use actix_web::web::{Data, Json, Path};
 use chrono::Utc;
 use rand::{Rng, thread_rng};
 use serde_derive::Deserialize;
 use crate::app_state::AppState;
-use crate::user::check_user;
+use crate::auth::{AuthUser, OptionalUser};
+use crate::error::ApiError;
 
 pub struct Post {
     pub id: i64,
     pub title: String,
     pub content: String,
     pub voxel_id: i64,
+    #[allow(dead_code)]
     pub votes: i64,
     pub author_id: i64,
+    #[allow(dead_code)]
     pub updated: bool,
     pub created_at: i64,
     pub updated_at: i64,
 }
 
 impl Post {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: i64,
         title: &str,
@@ -62,17 +66,14 @@ struct VoteRequest {
 async fn create_post(
     data: Data<RwLock<AppState>>,
     json: Json<CreatePostRequest>,
-    req: HttpRequest,
-) -> impl Responder {
-    let user_id = match check_user(req) {
-        Ok(user_id) => user_id,
-        Err(res) => return res,
-    };
-
-    let voxel_id = match json.voxel_id.parse::<i64>() {
-        Ok(voxel_id) => voxel_id,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid voxel id"),
-    };
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = user.id;
+
+    let voxel_id = json
+        .voxel_id
+        .parse::<i64>()
+        .map_err(|_| ApiError::BadRequest("Invalid voxel id".to_string()))?;
 
     let post_id = thread_rng().gen::<i64>();
 
@@ -80,62 +81,33 @@ async fn create_post(
 
     let post = Post::new(post_id, &json.title, &json.content, voxel_id, 0, user_id, false, time, time);
 
-    let app_state = match data.write() {
-        Ok(app_state) => app_state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock app state"),
-    };
+    let app_state = data.write()?;
 
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock database"),
-    };
+    let db = app_state.database.lock()?;
 
-    match db.save_new_post(post) {
-        Ok(_) => HttpResponse::Ok().json("Post created"),
-        Err(e) => HttpResponse::InternalServerError().body(format!("Failed to create post : {}", e)),
-    }
+    db.save_new_post(post)?;
+
+    Ok(HttpResponse::Ok().json("Post created"))
 }
 
 #[get("/api/post/top/{user_id}/{limit}")]
 async fn get_top_posts(
     data: Data<RwLock<AppState>>,
     path: Path<(String, i64)>,
-    req: HttpRequest,
-) -> impl Responder {
+    user: OptionalUser,
+) -> Result<HttpResponse, ApiError> {
     let (user_id, limit) = path.into_inner();
 
-    let user_id = if user_id == "me" {
-        match check_user(req) {
-            Ok(uid) => uid,
-            Err(res) => return res,
-        }
-    } else {
-        match user_id.parse::<i64>() {
-            Ok(uid) => uid,
-            Err(_) => return HttpResponse::BadRequest().body("Invalid user id"),
-        }
-    };
+    let user_id = parse_user_id(&user_id, user)?;
 
-    let app_state = match data.read() {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read app state"),
-    };
+    let app_state = data.read()?;
 
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock database"),
-    };
+    let db = app_state.database.lock()?;
 
     if user_id == 0 {
-        match db.get_top_posts(limit) {
-            Ok(posts) => HttpResponse::Ok().json(posts),
-            Err(_) => HttpResponse::InternalServerError().body("Failed to get top posts"),
-        }
+        Ok(HttpResponse::Ok().json(db.get_top_posts(limit)?))
     } else {
-        match db.get_top_user_posts(user_id, limit) {
-            Ok(posts) => HttpResponse::Ok().json(posts),
-            Err(_) => HttpResponse::InternalServerError().body("Failed to get top posts"),
-        }
+        Ok(HttpResponse::Ok().json(db.get_top_user_posts(user_id, limit)?))
     }
 }
 
@@ -143,42 +115,20 @@ async fn get_top_posts(
 async fn get_new_posts(
     data: Data<RwLock<AppState>>,
     path: Path<(String, i64)>,
-    req: HttpRequest,
-) -> impl Responder {
+    user: OptionalUser,
+) -> Result<HttpResponse, ApiError> {
     let (user_id, limit) = path.into_inner();
 
-    let user_id = if user_id == "me" {
-        match check_user(req) {
-            Ok(uid) => uid,
-            Err(res) => return res,
-        }
-    } else {
-        match user_id.parse::<i64>() {
-            Ok(uid) => uid,
-            Err(_) => return HttpResponse::BadRequest().body("Invalid user id"),
-        }
-    };
+    let user_id = parse_user_id(&user_id, user)?;
 
-    let app_state = match data.read() {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read app state"),
-    };
+    let app_state = data.read()?;
 
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock database"),
-    };
+    let db = app_state.database.lock()?;
 
     if user_id == 0 {
-        match db.get_new_posts(limit) {
-            Ok(posts) => HttpResponse::Ok().json(posts),
-            Err(_) => HttpResponse::InternalServerError().body("Failed to get new posts"),
-        }
+        Ok(HttpResponse::Ok().json(db.get_new_posts(limit)?))
     } else {
-        match db.get_new_user_posts(user_id, limit) {
-            Ok(posts) => HttpResponse::Ok().json(posts),
-            Err(_) => HttpResponse::InternalServerError().body("Failed to get new posts"),
-        }
+        Ok(HttpResponse::Ok().json(db.get_new_user_posts(user_id, limit)?))
     }
 }
 
@@ -186,57 +136,41 @@ async fn get_new_posts(
 async fn vote_post(
     data: Data<RwLock<AppState>>,
     json: Json<VoteRequest>,
-    req: HttpRequest,
-) -> impl Responder {
-    /*let user_id = match check_user(req) {
-        Ok(user_id) => user_id,
-        Err(res) => return res,
-    };*/
-    match check_user(req) {
-        Ok(_) => (),
-        Err(res) => return res,
-    }
+    _user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let post_id = json
+        .post_id
+        .parse::<i64>()
+        .map_err(|_| ApiError::BadRequest("Invalid post id".to_string()))?;
 
-    let post_id = match json.post_id.parse::<i64>() {
-        Ok(post_id) => post_id,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid post id"),
-    };
+    let app_state = data.write()?;
 
-    let app_state = match data.write() {
-        Ok(app_state) => app_state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock app state"),
-    };
+    let db = app_state.database.lock()?;
 
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock database"),
-    };
+    db.vote_post(post_id, json.vote)?;
 
-    match db.vote_post(post_id, json.vote) {
-        Ok(_) => HttpResponse::Ok().json("Voted"),
-        Err(e) => HttpResponse::InternalServerError().body(format!("Failed to vote post : {}", e)),
-    }
+    Ok(HttpResponse::Ok().json("Voted"))
 }
 
 #[get("/api/post/{post_id}")]
 async fn get_post(
     data: Data<RwLock<AppState>>,
     path: Path<i64>,
-) -> impl Responder {
+) -> Result<HttpResponse, ApiError> {
     let post_id = path.into_inner();
 
-    let app_state = match data.read() {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read app state"),
-    };
+    let app_state = data.read()?;
 
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock database"),
-    };
+    let db = app_state.database.lock()?;
 
-    match db.get_post(post_id) {
-        Ok(post) => HttpResponse::Ok().json(post),
-        Err(_) => HttpResponse::InternalServerError().body("Failed to get post"),
+    Ok(HttpResponse::Ok().json(db.get_post(post_id)?))
+}
+
+// "me" is the requesting user; 0 means every user.
+fn parse_user_id(user_id: &str, user: OptionalUser) -> Result<i64, ApiError> {
+    if user_id == "me" {
+        Ok(user.required()?.id)
+    } else {
+        user_id.parse::<i64>().map_err(|_| ApiError::BadRequest("Invalid user id".to_string()))
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use actix_web::web::Bytes;
+use crate::mesh::Mesh;
+use crate::palette::Palette;
+use crate::voxel::Voxel;
+
+const SUPERSAMPLING: usize = 2;
+const ELEVATION: f32 = 35.264;
+const LIGHT: [f32; 3] = [-0.4, 0.8, 0.45];
+const AMBIENT: f32 = 0.45;
+
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<[u8; 4]>,
+}
+
+impl Image {
+    pub fn encode_png(&self) -> Result<Vec<u8>, png::EncodingError> {
+        let mut bytes = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut bytes, self.width as u32, self.height as u32);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder.write_header()?;
+            writer.write_image_data(self.pixels.as_flattened())?;
+        }
+        Ok(bytes)
+    }
+}
+
+// Animated PNG looping forever over `frame_count` square frames of `size` pixels. Frames are
+// pulled one at a time so only the current one is held in memory.
+pub fn encode_apng(
+    size: usize,
+    frame_count: usize,
+    delay_ms: u16,
+    mut next_frame: impl FnMut() -> Image,
+) -> Result<Vec<u8>, png::EncodingError> {
+    let mut bytes = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut bytes, size as u32, size as u32);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_animated(frame_count as u32, 0)?;
+        encoder.set_frame_delay(delay_ms, 1000)?;
+        let mut writer = encoder.write_header()?;
+        for _ in 0..frame_count {
+            writer.write_image_data(next_frame().pixels.as_flattened())?;
+        }
+    }
+    Ok(bytes)
+}
+
+// Orthographic isometric view of the voxel, turned by `angle` degrees around the vertical axis and
+// framed on its content.
+pub fn render(voxel: &Voxel, palette: &Palette, size: usize, angle: f32) -> Image {
+    render_view(voxel, palette, size, angle, false)
+}
+
+// Same view framed on the whole grid instead, so renders of a changing voxel line up.
+pub fn render_grid(voxel: &Voxel, palette: &Palette, size: usize, angle: f32) -> Image {
+    render_view(voxel, palette, size, angle, true)
+}
+
+fn render_view(voxel: &Voxel, palette: &Palette, size: usize, angle: f32, frame_grid: bool) -> Image {
+    let mesh = Mesh::greedy(voxel);
+    let scaled = size * SUPERSAMPLING;
+
+    let (sin_yaw, cos_yaw) = angle.to_radians().sin_cos();
+    let (sin_pitch, cos_pitch) = ELEVATION.to_radians().sin_cos();
+    let center = [
+        voxel.grid_size.0 as f32 / 2.0,
+        voxel.grid_size.1 as f32 / 2.0,
+        voxel.grid_size.2 as f32 / 2.0,
+    ];
+    let to_view = |point: [f32; 3]| {
+        let x = point[0] - center[0];
+        let y = point[1] - center[1];
+        let z = point[2] - center[2];
+        let x1 = x * cos_yaw - z * sin_yaw;
+        let z1 = x * sin_yaw + z * cos_yaw;
+        let y2 = y * cos_pitch - z1 * sin_pitch;
+        let z2 = y * sin_pitch + z1 * cos_pitch;
+        [x1, y2, z2]
+    };
+    let rotate = |normal: [f32; 3]| {
+        let x1 = normal[0] * cos_yaw - normal[2] * sin_yaw;
+        let z1 = normal[0] * sin_yaw + normal[2] * cos_yaw;
+        let y2 = normal[1] * cos_pitch - z1 * sin_pitch;
+        let z2 = normal[1] * sin_pitch + z1 * cos_pitch;
+        [x1, y2, z2]
+    };
+
+    let grid_corners: Vec<[f32; 3]> = (0..8)
+        .map(|i| [
+            if i & 1 == 0 { 0.0 } else { voxel.grid_size.0 as f32 },
+            if i & 2 == 0 { 0.0 } else { voxel.grid_size.1 as f32 },
+            if i & 4 == 0 { 0.0 } else { voxel.grid_size.2 as f32 },
+        ])
+        .collect();
+    let framed_points: Box<dyn Iterator<Item = &[f32; 3]>> = if frame_grid {
+        Box::new(grid_corners.iter())
+    } else {
+        Box::new(mesh.quads.iter().flat_map(|quad| quad.corners.iter()))
+    };
+
+    let mut min = [f32::MAX; 2];
+    let mut max = [f32::MIN; 2];
+    for corner in framed_points {
+        let point = to_view(*corner);
+        min[0] = min[0].min(point[0]);
+        min[1] = min[1].min(point[1]);
+        max[0] = max[0].max(point[0]);
+        max[1] = max[1].max(point[1]);
+    }
+
+    let extent = (max[0] - min[0]).max(max[1] - min[1]).max(1.0);
+    let margin = scaled as f32 * 0.05;
+    let scale = (scaled as f32 - 2.0 * margin) / extent;
+    let offset = [
+        scaled as f32 / 2.0 - (min[0] + max[0]) / 2.0 * scale,
+        scaled as f32 / 2.0 + (min[1] + max[1]) / 2.0 * scale,
+    ];
+    let to_screen = |point: [f32; 3]| [point[0] * scale + offset[0], offset[1] - point[1] * scale, point[2]];
+
+    let light_length = (LIGHT[0] * LIGHT[0] + LIGHT[1] * LIGHT[1] + LIGHT[2] * LIGHT[2]).sqrt();
+    let light = [LIGHT[0] / light_length, LIGHT[1] / light_length, LIGHT[2] / light_length];
+
+    let mut color_buffer = vec![[0.0f32; 4]; scaled * scaled];
+    let mut depth_buffer = vec![f32::MIN; scaled * scaled];
+
+    for quad in mesh.quads.iter() {
+        let normal = rotate(quad.normal);
+        if normal[2] <= 0.0 {
+            continue;
+        }
+
+        let index = quad.color as usize - 1;
+        let (r, g, b) = palette.colors().get(index).copied().unwrap_or((255, 0, 255));
+        let material = palette.materials().get(index).copied().unwrap_or_default();
+        let diffuse = (normal[0] * light[0] + normal[1] * light[1] + normal[2] * light[2]).max(0.0);
+        let shade = (AMBIENT + (1.0 - AMBIENT) * diffuse + material.emission as f32 / 255.0).min(1.0);
+        let color = [
+            r as f32 / 255.0 * shade,
+            g as f32 / 255.0 * shade,
+            b as f32 / 255.0 * shade,
+            material.alpha as f32 / 255.0,
+        ];
+
+        let [a, b, c, d] = quad.corners.map(|corner| to_screen(to_view(corner)));
+        for triangle in [[a, b, c], [a, c, d]] {
+            rasterize(triangle, color, scaled, &mut color_buffer, &mut depth_buffer);
+        }
+    }
+
+    let mut pixels = Vec::with_capacity(size * size);
+    for y in 0..size {
+        for x in 0..size {
+            let mut sum = [0.0f32; 4];
+            for sy in 0..SUPERSAMPLING {
+                for sx in 0..SUPERSAMPLING {
+                    let sample = color_buffer[(y * SUPERSAMPLING + sy) * scaled + x * SUPERSAMPLING + sx];
+                    sum[0] += sample[0] * sample[3];
+                    sum[1] += sample[1] * sample[3];
+                    sum[2] += sample[2] * sample[3];
+                    sum[3] += sample[3];
+                }
+            }
+            let samples = (SUPERSAMPLING * SUPERSAMPLING) as f32;
+            let alpha = sum[3] / samples;
+            let channel = |value: f32| {
+                if sum[3] > 0.0 {
+                    (value / sum[3] * 255.0).round().clamp(0.0, 255.0) as u8
+                } else {
+                    0
+                }
+            };
+            pixels.push([
+                channel(sum[0]),
+                channel(sum[1]),
+                channel(sum[2]),
+                (alpha * 255.0).round() as u8,
+            ]);
+        }
+    }
+
+    Image {
+        width: size,
+        height: size,
+        pixels,
+    }
+}
+
+fn rasterize(
+    triangle: [[f32; 3]; 3],
+    color: [f32; 4],
+    size: usize,
+    color_buffer: &mut [[f32; 4]],
+    depth_buffer: &mut [f32],
+) {
+    let [a, b, c] = triangle;
+    let area = edge(a, b, c);
+    if area.abs() < f32::EPSILON {
+        return;
+    }
+
+    let min_x = a[0].min(b[0]).min(c[0]).floor().max(0.0) as usize;
+    let min_y = a[1].min(b[1]).min(c[1]).floor().max(0.0) as usize;
+    let max_x = (a[0].max(b[0]).max(c[0]).ceil() as usize).min(size);
+    let max_y = (a[1].max(b[1]).max(c[1]).ceil() as usize).min(size);
+
+    for y in min_y..max_y {
+        for x in min_x..max_x {
+            let point = [x as f32 + 0.5, y as f32 + 0.5, 0.0];
+            let w0 = edge(b, c, point) / area;
+            let w1 = edge(c, a, point) / area;
+            let w2 = edge(a, b, point) / area;
+            if w0 < 0.0 || w1 < 0.0 || w2 < 0.0 {
+                continue;
+            }
+
+            let depth = w0 * a[2] + w1 * b[2] + w2 * c[2];
+            let index = y * size + x;
+            if depth > depth_buffer[index] {
+                depth_buffer[index] = depth;
+                color_buffer[index] = color;
+            }
+        }
+    }
+}
+
+fn edge(a: [f32; 3], b: [f32; 3], point: [f32; 3]) -> f32 {
+    (b[0] - a[0]) * (point[1] - a[1]) - (b[1] - a[1]) * (point[0] - a[0])
+}
+
+const THUMBNAIL_CACHE_CAPACITY: usize = 1024;
+const THUMBNAIL_SIZES: [usize; 5] = [64, 128, 256, 512, 1024];
+const THUMBNAIL_ANGLE_STEP: i32 = 15;
+
+// Requests are snapped to a few sizes and angles, so varying them can't create unbounded keys.
+pub fn thumbnail_size(size: usize) -> usize {
+    THUMBNAIL_SIZES
+        .iter()
+        .copied()
+        .find(|&bucket| bucket >= size)
+        .unwrap_or(THUMBNAIL_SIZES[THUMBNAIL_SIZES.len() - 1])
+}
+
+pub fn thumbnail_angle(angle: f32) -> i32 {
+    let step = THUMBNAIL_ANGLE_STEP as f32;
+    ((angle / step).round() as i32 * THUMBNAIL_ANGLE_STEP).rem_euclid(360)
+}
+
+struct CachedThumbnail {
+    last_modified_at: i64,
+    png: Bytes,
+    last_used: u64,
+}
+
+// Evicts the least recently used thumbnail when full.
+#[derive(Default)]
+pub struct ThumbnailCache {
+    entries: HashMap<(i64, usize, i32), CachedThumbnail>,
+    clock: u64,
+}
+
+impl ThumbnailCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&mut self, key: (i64, usize, i32), last_modified_at: i64) -> Option<Bytes> {
+        self.clock += 1;
+        match self.entries.get_mut(&key) {
+            Some(entry) if entry.last_modified_at == last_modified_at => {
+                entry.last_used = self.clock;
+                Some(entry.png.clone())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, key: (i64, usize, i32), last_modified_at: i64, png: Bytes) {
+        self.clock += 1;
+        if self.entries.len() >= THUMBNAIL_CACHE_CAPACITY && !self.entries.contains_key(&key) {
+            // A linear scan is negligible next to the render that precedes every insert.
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries.insert(key, CachedThumbnail {
+            last_modified_at,
+            png,
+            last_used: self.clock,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantizes_sizes_and_angles() {
+        assert_eq!(thumbnail_size(1), 64);
+        assert_eq!(thumbnail_size(64), 64);
+        assert_eq!(thumbnail_size(65), 128);
+        assert_eq!(thumbnail_size(300), 512);
+        assert_eq!(thumbnail_size(usize::MAX), 1024);
+
+        assert_eq!(thumbnail_angle(45.0), 45);
+        assert_eq!(thumbnail_angle(52.0), 45);
+        assert_eq!(thumbnail_angle(-10.0), 345);
+        assert_eq!(thumbnail_angle(1e9), thumbnail_angle(1e9 + 1.0));
+        let angles: std::collections::HashSet<i32> = (-720..720).map(|a| thumbnail_angle(a as f32)).collect();
+        assert_eq!(angles.len(), 24);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = ThumbnailCache::new();
+        for id in 0..THUMBNAIL_CACHE_CAPACITY as i64 {
+            cache.insert((id, 64, 0), 0, Bytes::from_static(b"png"));
+        }
+        // Touching the oldest entry makes the second one the least recently used.
+        assert!(cache.get((0, 64, 0), 0).is_some());
+        cache.insert((-1, 64, 0), 0, Bytes::from_static(b"png"));
+
+        assert_eq!(cache.entries.len(), THUMBNAIL_CACHE_CAPACITY);
+        assert!(cache.get((0, 64, 0), 0).is_some());
+        assert!(cache.get((1, 64, 0), 0).is_none());
+        assert!(cache.get((-1, 64, 0), 0).is_some());
+        assert!(cache.get((0, 64, 0), 1).is_none());
+    }
+}
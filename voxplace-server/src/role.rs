@@ -0,0 +1,138 @@
+use std::sync::RwLock;
+use actix_web::{get, HttpResponse, post};
+use actix_web::web::{Data, Json, Path};
+use serde_derive::{Deserialize, Serialize};
+use crate::app_state::AppState;
+use crate::auth::{AuthUser, OptionalUser};
+use crate::error::ApiError;
+use crate::place::{no_such_place, parse_place_id};
+
+// Ordered by privilege: a role can do everything the ones before it can. Every account has the
+// user role, so it is never stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    User,
+    Moderator,
+    PlaceOwner,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::PlaceOwner => "place_owner",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn parse(role: &str) -> Option<Role> {
+        match role {
+            "user" => Some(Role::User),
+            "moderator" => Some(Role::Moderator),
+            "place_owner" => Some(Role::PlaceOwner),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct RoleRequest {
+    user_id: String,
+    role: Role,
+    place_id: Option<String>,
+}
+
+// Place owners manage the moderators of their place; every other grant takes an admin.
+fn check_role_request(user: &AuthUser, request: &RoleRequest) -> Result<(i64, Option<i64>), ApiError> {
+    let user_id = request
+        .user_id
+        .parse::<i64>()
+        .map_err(|_| ApiError::BadRequest("Invalid user".to_string()))?;
+
+    let place_id = match &request.place_id {
+        Some(id) => Some(parse_place_id(id)?),
+        None => None,
+    };
+
+    match (request.role, place_id) {
+        (Role::User, _) => return Err(ApiError::BadRequest("Every account has the user role".to_string())),
+        (Role::Admin, Some(_)) => return Err(ApiError::BadRequest("Admin can't be limited to a place".to_string())),
+        (Role::PlaceOwner, None) => return Err(ApiError::BadRequest("Place owner needs a place".to_string())),
+        _ => {}
+    }
+
+    let required = match (request.role, place_id) {
+        (Role::Moderator, Some(_)) => Role::PlaceOwner,
+        _ => Role::Admin,
+    };
+    user.require(required, place_id)?;
+
+    Ok((user_id, place_id))
+}
+
+#[get("/api/role/user/{user_id}")]
+async fn get_user_roles(
+    data: Data<RwLock<AppState>>,
+    path: Path<String>,
+    user: OptionalUser,
+) -> Result<HttpResponse, ApiError> {
+    let path = path.into_inner();
+    let user_id = if path == "me" {
+        user.required()?.id
+    } else {
+        path.parse::<i64>().map_err(|_| ApiError::BadRequest("Invalid user".to_string()))?
+    };
+
+    let app_state = data.read()?;
+
+    let db = app_state.database.lock()?;
+
+    Ok(HttpResponse::Ok().json(db.get_user_roles(user_id)?))
+}
+
+#[post("/api/role/grant")]
+async fn grant_role(
+    data: Data<RwLock<AppState>>,
+    json: Json<RoleRequest>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let (user_id, place_id) = check_role_request(&user, &json)?;
+
+    let app_state = data.read()?;
+
+    let db = app_state.database.lock()?;
+
+    db.get_username(user_id)?;
+    if let Some(place_id) = place_id {
+        if !db.place_exists(place_id)? {
+            return Err(no_such_place());
+        }
+    }
+
+    db.grant_role(user_id, json.role, place_id)?;
+
+    Ok(HttpResponse::Ok().json("Role granted"))
+}
+
+#[post("/api/role/revoke")]
+async fn revoke_role(
+    data: Data<RwLock<AppState>>,
+    json: Json<RoleRequest>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let (user_id, place_id) = check_role_request(&user, &json)?;
+
+    let app_state = data.read()?;
+
+    let db = app_state.database.lock()?;
+
+    if !db.revoke_role(user_id, json.role, place_id)? {
+        return Err(ApiError::NotFound("The user doesn't have this role".to_string()));
+    }
+
+    Ok(HttpResponse::Ok().json("Role revoked"))
+}
@@ -0,0 +1,318 @@
+use std::sync::{Mutex, RwLock};
+use std::time::Duration;
+use actix_web::{get, post, HttpResponse};
+use actix_web::web::{self, Data, Path, Query};
+use chrono::Utc;
+use rand::{Rng, thread_rng};
+use serde_derive::{Deserialize, Serialize};
+use crate::app_state::AppState;
+use crate::auth::AuthUser;
+use crate::database::db::{Database, DatabaseError};
+use crate::database::place::VoxelChange;
+use crate::database::snapshot::PlaceSnapshot;
+use crate::error::ApiError;
+use crate::flush::flush_place;
+use crate::grid::GridLayout;
+use crate::place::{no_such_place, parse_place_id, Place};
+use crate::role::Role;
+use crate::websocket::ServerMessage;
+
+const SNAPSHOT_INTERVAL: u64 = 60 * 60;
+const KEEP_SCHEDULED_SNAPSHOTS: usize = 48;
+const KEEP_MANUAL_SNAPSHOTS: usize = 20;
+const KEEP_RESTORE_SNAPSHOTS: usize = 20;
+const MAX_DIFF_CHANGES: usize = 10000;
+
+#[derive(Deserialize)]
+struct DiffQuery {
+    against: Option<String>,
+}
+
+#[derive(Serialize)]
+struct VoxelDiff {
+    x: usize,
+    y: usize,
+    z: usize,
+    from: u8,
+    to: u8,
+}
+
+#[derive(Serialize)]
+struct DiffResponse {
+    count: usize,
+    changes: Vec<VoxelDiff>,
+}
+
+// A poisoned lock fails this place only.
+pub fn take_snapshot(database: &Mutex<Database>, place: &RwLock<Place>, kind: &str) -> Result<Option<i64>, DatabaseError> {
+    let place = place.read().map_err(|e| DatabaseError::LockError(e.to_string()))?;
+
+    let db = database.lock().map_err(|e| DatabaseError::LockError(e.to_string()))?;
+
+    save_snapshot(&db, &place, kind, None)
+}
+
+// Scheduled snapshots are skipped while nothing has been drawn since the last snapshot; the other
+// kinds are always taken. Each kind is pruned to its own retention count afterwards, never
+// removing `keep_id`.
+fn save_snapshot(db: &Database, place: &Place, kind: &str, keep_id: Option<i64>) -> Result<Option<i64>, DatabaseError> {
+    let place_id = place.id;
+
+    // Queued draws are written first so the event id matches the grid.
+    flush_place(db, place, false);
+
+    let last_event_id = db.get_last_place_event_id(place_id)?;
+    if kind == "scheduled" && db.get_last_snapshot_event_id(place_id)? == Some(last_event_id) {
+        return Ok(None);
+    }
+
+    let (grid_size, grid) = (place.voxel.grid_size, place.voxel.get_grid_bytes());
+
+    let snapshot = PlaceSnapshot {
+        snapshot_id: thread_rng().gen::<i64>(),
+        place_id,
+        kind: kind.to_string(),
+        grid_size,
+        grid,
+        last_event_id,
+        created_at: Utc::now().timestamp(),
+    };
+    db.save_place_snapshot(&snapshot)?;
+
+    let keep = match kind {
+        "scheduled" => KEEP_SCHEDULED_SNAPSHOTS,
+        "restore" => KEEP_RESTORE_SNAPSHOTS,
+        _ => KEEP_MANUAL_SNAPSHOTS,
+    };
+    db.prune_place_snapshots(place_id, kind, keep, keep_id)?;
+
+    Ok(Some(snapshot.snapshot_id))
+}
+
+// Places are snapshotted one at a time on the blocking thread pool, without holding the state.
+pub async fn run_snapshot_schedule(data: Data<RwLock<AppState>>) {
+    let mut interval = actix_web::rt::time::interval(Duration::from_secs(SNAPSHOT_INTERVAL));
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+
+        let (database, places) = match data.read() {
+            Ok(app_state) => (
+                app_state.database.clone(),
+                app_state.places.iter().map(|(&id, place)| (id, place.clone())).collect::<Vec<_>>(),
+            ),
+            Err(_) => continue,
+        };
+
+        for (place_id, place) in places {
+            let database = database.clone();
+            match web::block(move || take_snapshot(&database, &place, "scheduled")).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => eprintln!("Failed to snapshot place {}: {}", place_id, e),
+                Err(e) => eprintln!("Failed to snapshot place {}: {}", place_id, e),
+            }
+        }
+    }
+}
+
+fn parse_ids(path: Path<(String, String)>) -> Result<(i64, i64), ApiError> {
+    let (place_id, snapshot_id) = path.into_inner();
+    let place_id = parse_place_id(&place_id)?;
+    let snapshot_id = parse_snapshot_id(&snapshot_id)?;
+    Ok((place_id, snapshot_id))
+}
+
+fn parse_snapshot_id(id: &str) -> Result<i64, ApiError> {
+    id.parse::<i64>().map_err(|_| ApiError::BadRequest("Invalid snapshot".to_string()))
+}
+
+#[post("/api/place/snapshots/{id}")]
+async fn create_snapshot(
+    data: Data<RwLock<AppState>>,
+    path: Path<String>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_place_id(&path.into_inner())?;
+    user.require(Role::Moderator, Some(id))?;
+
+    let app_state = data.read()?;
+
+    let place = app_state.places.get(&id).ok_or_else(no_such_place)?;
+
+    let snapshot_id = take_snapshot(&app_state.database, place, "manual")?
+        .ok_or_else(|| ApiError::Internal("Failed to take snapshot".to_string()))?;
+
+    Ok(HttpResponse::Ok().json(snapshot_id.to_string()))
+}
+
+#[get("/api/place/snapshots/{id}")]
+async fn get_snapshots(
+    data: Data<RwLock<AppState>>,
+    path: Path<String>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_place_id(&path.into_inner())?;
+    user.require(Role::Moderator, Some(id))?;
+
+    let app_state = data.read()?;
+
+    let db = app_state.database.lock()?;
+
+    Ok(HttpResponse::Ok().json(db.get_place_snapshots(id)?))
+}
+
+// Differences going from the snapshot to `against` (another snapshot), or to the live place.
+#[get("/api/place/snapshots/{id}/{snapshot_id}/diff")]
+async fn diff_snapshot(
+    data: Data<RwLock<AppState>>,
+    query: Query<DiffQuery>,
+    path: Path<(String, String)>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let (id, snapshot_id) = parse_ids(path)?;
+    user.require(Role::Moderator, Some(id))?;
+
+    let against_id = match &query.against {
+        Some(against) => Some(parse_snapshot_id(against)?),
+        None => None,
+    };
+
+    let app_state = data.read()?;
+
+    let db = app_state.database.lock()?;
+
+    let snapshot = db.get_place_snapshot(id, snapshot_id)?;
+
+    let against = match against_id {
+        Some(against_id) => {
+            let against = db.get_place_snapshot(id, against_id)?;
+            Some((against.grid_size, against.grid))
+        }
+        None => None,
+    };
+    // Places are always locked before the database.
+    drop(db);
+
+    let (grid_size, grid) = match against {
+        Some(against) => against,
+        None => {
+            let place = app_state.places.get(&id).ok_or_else(no_such_place)?.read()?;
+            (place.voxel.grid_size, place.voxel.get_grid_bytes())
+        }
+    };
+
+    if grid_size != snapshot.grid_size {
+        return Err(ApiError::BadRequest("Grid sizes differ".to_string()));
+    }
+
+    let mut count = 0;
+    let mut changes = Vec::new();
+    for (index, (x, y, z)) in GridLayout::new(grid_size).iter().enumerate() {
+        let from = snapshot.grid.get(index).copied().unwrap_or(0);
+        let to = grid.get(index).copied().unwrap_or(0);
+        if from != to {
+            count += 1;
+            if changes.len() < MAX_DIFF_CHANGES {
+                changes.push(VoxelDiff { x, y, z, from, to });
+            }
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(DiffResponse { count, changes }))
+}
+
+// The live grid is saved first as a "restore" snapshot, so a restore can itself be undone without
+// pushing manual snapshots out. Every restored voxel is logged as an edit by the user restoring,
+// keeping history and timelapses consistent with the grid. Viewers are told to reload the grid
+// rather than sent every changed voxel.
+#[post("/api/place/snapshots/{id}/{snapshot_id}/restore")]
+async fn restore_snapshot(
+    data: Data<RwLock<AppState>>,
+    path: Path<(String, String)>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let (id, snapshot_id) = parse_ids(path)?;
+    user.require(Role::PlaceOwner, Some(id))?;
+    let admin_id = user.id;
+
+    let app_state = data.read()?;
+
+    // Held for writing from the backup on, so no draw lands between it and the restore.
+    let place = app_state.places.get(&id).ok_or_else(no_such_place)?.write()?;
+
+    let db = app_state.database.lock()?;
+
+    let snapshot = db.get_place_snapshot(id, snapshot_id)?;
+
+    if snapshot.grid_size != place.voxel.grid_size {
+        return Err(ApiError::BadRequest("Grid sizes differ".to_string()));
+    }
+
+    save_snapshot(&db, &place, "restore", Some(snapshot_id))?;
+    drop(db);
+
+    let time = Utc::now().timestamp();
+    let mut changes = Vec::new();
+    for (index, (x, y, z)) in GridLayout::new(snapshot.grid_size).iter().enumerate() {
+        let old_color = place.voxel.get(x, y, z);
+        let new_color = snapshot.grid.get(index).copied().unwrap_or(0);
+        if old_color == new_color {
+            continue;
+        }
+        place.voxel.set(x, y, z, new_color);
+        place.add_place_update(x, y, z, admin_id);
+        changes.push(VoxelChange {
+            user_id: admin_id,
+            x,
+            y,
+            z,
+            old_color,
+            new_color,
+            created_at: time,
+        });
+    }
+
+    let seq = place.voxel.reset_updates();
+    place.voxel.broadcast_message(ServerMessage::ReloadGrid { seq }.to_json());
+
+    let db = app_state.database.lock()?;
+    flush_place(&db, &place, false);
+    if let Err(e) = db.save_place_events(id, &changes) {
+        eprintln!("Failed to save place events: {}", e);
+    }
+    flush_place(&db, &place, true);
+
+    Ok(HttpResponse::Ok().json(changes.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::grid::ChunkedGrid;
+    use crate::voxel::Voxel;
+    use super::*;
+
+    fn place(id: i64) -> RwLock<Place> {
+        let size = (4, 4, 4);
+        let voxel = Voxel::new(id, "test", 0, size, Some(ChunkedGrid::new(size)), None, None);
+        RwLock::new(Place::new(id, true, 60, voxel))
+    }
+
+    #[test]
+    fn poisoned_places_are_skipped() {
+        let db = Database::open(":memory:").unwrap();
+        db.migrate().unwrap();
+        let database = Mutex::new(db);
+
+        let poisoned = place(1);
+        let _ = std::panic::catch_unwind(|| {
+            let _place = poisoned.write().unwrap();
+            panic!("poison");
+        });
+        assert!(matches!(take_snapshot(&database, &poisoned, "scheduled"), Err(DatabaseError::LockError(_))));
+
+        let healthy = place(2);
+        assert!(take_snapshot(&database, &healthy, "scheduled").unwrap().is_some());
+        // Nothing was drawn since, so the next scheduled snapshot is skipped.
+        assert_eq!(take_snapshot(&database, &healthy, "scheduled").unwrap(), None);
+    }
+}
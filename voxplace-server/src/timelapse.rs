@@ -0,0 +1,110 @@
+use actix_web::web::Bytes;
+use serde_derive::Deserialize;
+use crate::database::place::VoxelChange;
+use crate::palette::Palette;
+use crate::render::{encode_apng, render_grid};
+use crate::voxel::Voxel;
+
+const MAX_FRAMES: usize = 300;
+const MAX_SIZE: usize = 512;
+
+#[derive(Deserialize, Clone, Copy, Default)]
+pub struct TimelapseOptions {
+    pub frames: Option<usize>,
+    pub size: Option<usize>,
+    pub angle: Option<f32>,
+    pub delay: Option<u16>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+}
+
+pub enum TimelapseJob {
+    Running,
+    Done(Bytes),
+    Failed(String),
+}
+
+// The cutoff of every frame: frame i shows every change made before it, so the first frame is the
+// grid right before `from` and the last one includes `to`. None when `from` is after `to` or the
+// range overflows.
+pub fn frame_cutoffs(options: &TimelapseOptions, changes: &[VoxelChange]) -> Option<Vec<i64>> {
+    let frame_count = options.frames.unwrap_or(60).clamp(2, MAX_FRAMES) as i64;
+
+    let first = changes.first().map(|change| change.created_at).unwrap_or(0);
+    let last = changes.last().map(|change| change.created_at).unwrap_or(0);
+    let from = options.from.unwrap_or(first);
+    let to = options.to.unwrap_or(last.max(from));
+    if from > to {
+        return None;
+    }
+
+    let span = to.checked_sub(from)?.checked_add(1)?;
+    (0..frame_count)
+        .map(|frame| from.checked_add(span.checked_mul(frame)? / (frame_count - 1)))
+        .collect()
+}
+
+// `voxel` holds the current grid and `changes` every logged change in order. The grid is first
+// rewound to the first cutoff by undoing changes newest first, then replayed forward, rendering a
+// frame each time the replay reaches the next cutoff.
+pub fn build_timelapse(
+    voxel: Voxel,
+    palette: &Palette,
+    changes: &[VoxelChange],
+    cutoffs: &[i64],
+    options: TimelapseOptions,
+) -> Result<Vec<u8>, png::EncodingError> {
+    let size = options.size.unwrap_or(256).clamp(16, MAX_SIZE);
+    let angle = options.angle.unwrap_or(45.0);
+    let delay = options.delay.unwrap_or(100).max(10);
+
+    let from = cutoffs.first().copied().unwrap_or(0);
+    let start = changes
+        .iter()
+        .position(|change| change.created_at >= from)
+        .unwrap_or(changes.len());
+    for change in changes[start..].iter().rev() {
+        voxel.set(change.x, change.y, change.z, change.old_color);
+    }
+
+    let mut next = start;
+    let mut frame = 0;
+    encode_apng(size, cutoffs.len(), delay, || {
+        let cutoff = cutoffs[frame];
+        while next < changes.len() && changes[next].created_at < cutoff {
+            let change = &changes[next];
+            voxel.set(change.x, change.y, change.z, change.new_color);
+            next += 1;
+        }
+        frame += 1;
+        render_grid(&voxel, palette, size, angle)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(from: Option<i64>, to: Option<i64>) -> TimelapseOptions {
+        TimelapseOptions {
+            frames: Some(3),
+            from,
+            to,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn cutoffs_span_from_to_to() {
+        assert_eq!(frame_cutoffs(&options(Some(10), Some(19)), &[]), Some(vec![10, 15, 20]));
+        assert_eq!(frame_cutoffs(&options(Some(10), None), &[]), Some(vec![10, 10, 11]));
+    }
+
+    #[test]
+    fn invalid_ranges_have_no_cutoffs() {
+        assert_eq!(frame_cutoffs(&options(Some(20), Some(10)), &[]), None);
+        assert_eq!(frame_cutoffs(&options(Some(i64::MIN), Some(i64::MAX)), &[]), None);
+        assert_eq!(frame_cutoffs(&options(Some(0), Some(i64::MAX)), &[]), None);
+        assert_eq!(frame_cutoffs(&options(Some(i64::MAX), Some(i64::MAX)), &[]), None);
+    }
+}
@@ -1,12 +1,18 @@
 use std::sync::RwLock;
-use actix_web::{get, HttpRequest, HttpResponse, post, Responder};
+use actix_web::{get, HttpResponse, post};
 use actix_web::web::{Data, Json, Path};
 use bcrypt::{DEFAULT_COST, hash};
 use chrono::{Duration, Utc};
 use jsonwebtoken::{Algorithm, decode, DecodingKey, encode, EncodingKey, Header, Validation};
 use rand::{Rng, thread_rng};
 use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use crate::app_state::AppState;
+use crate::auth::{AuthUser, OptionalUser};
+use crate::config::Config;
+use crate::database::db::Database;
+use crate::error::ApiError;
+use crate::role::Role;
 use crate::voxel::Voxel;
 
 pub struct User {
@@ -15,12 +21,14 @@ pub struct User {
     pub email: String,
     pub password_hash: String,
     pub voxel_id: i64,
+    #[allow(dead_code)]
     pub admin: bool,
     pub created_at: i64,
     pub last_connected_at: i64,
 }
 
 impl User {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         user_id: i64,
         username: &str,
@@ -57,6 +65,18 @@ struct LoginRequest {
     password: String,
 }
 
+#[derive(Deserialize)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    token: String,
+    refresh_token: String,
+    expires_in: i64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Claims {
     sub: String,
@@ -75,31 +95,18 @@ struct EditUserRequest {
 
 #[get("/api/user/checkadmin")]
 async fn check_admin(
-    data: Data<RwLock<AppState>>,
-    req: HttpRequest
-) -> impl Responder {
-    let user_id = match check_user(req) {
-        Ok(id) => id,
-        Err(res) => return res,
-    };
-
-    let is_admin = match check_user_admin(user_id, &data) {
-        Ok(is_admin) => is_admin,
-        Err(res) => return res,
-    };
-
-    HttpResponse::Ok().json(is_admin)
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(user.role(None)? == Role::Admin))
 }
 
 #[post("/api/user/register")]
 async fn register_user(
     data: Data<RwLock<AppState>>,
     req: Json<RegisterRequest>
-) -> impl Responder {
-    let password_hash = match hash(&req.password, DEFAULT_COST) {
-        Ok(hash) => hash,
-        Err(_) => return HttpResponse::InternalServerError().json("Failed to hash password"),
-    };
+) -> Result<HttpResponse, ApiError> {
+    let password_hash = hash(&req.password, DEFAULT_COST)
+        .map_err(|_| ApiError::Internal("Failed to hash password".to_string()))?;
 
     let created_at = Utc::now().timestamp();
 
@@ -107,19 +114,13 @@ async fn register_user(
     let voxel_id = thread_rng().gen::<i64>();
     let voxel_name = format!("{}'s voxel", req.username);
 
-    let mut app_state = match data.write() {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::InternalServerError().json("Failed to read app state"),
-    };
+    let mut app_state = data.write()?;
 
     let voxel = Voxel::new(voxel_id, &voxel_name, 0, (8, 8, 8), None, None, None);
 
     app_state.add_voxel(voxel);
 
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().json("Failed to lock database"),
-    };
+    let db = app_state.database.lock()?;
 
     let user = User::new(
         user_id,
@@ -132,110 +133,112 @@ async fn register_user(
         created_at,
     );
 
-    match db.register_user(user) {
-        Ok(_) => (),
-        Err(e) => return HttpResponse::InternalServerError().json(format!("Failed to register user : {}", e)),
-    };
-
-    let claim = Claims {
-        sub: user_id.to_string(),
-        exp: (Utc::now() + Duration::hours(24)).timestamp() as usize,
-    };
-
-    let key = "secret".as_bytes();
-
-    let token = match encode(&Header::default(), &claim, &EncodingKey::from_secret(key)) {
-        Ok(t) => t,
-        Err(_) => return HttpResponse::InternalServerError().json("Failed to generate token"),
-    };
+    db.register_user(user)?;
 
-    HttpResponse::Ok().json(token)
+    Ok(HttpResponse::Ok().json(create_session(&db, user_id, &app_state.config)?))
 }
 
 #[post("/api/user/login")]
 async fn login_user(
     data: Data<RwLock<AppState>>,
     json: Json<LoginRequest>
-) -> impl Responder {
-    let app_state = match data.read() {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read app state"),
-    };
+) -> Result<HttpResponse, ApiError> {
+    let app_state = data.read()?;
 
     let last_connected_at = Utc::now().timestamp();
 
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock database"),
-    };
+    let db = app_state.database.lock()?;
 
-    let user_id = match db.login_user(&json.username, &json.password) {
-        Ok(user_id) => user_id,
-        Err(_) => return HttpResponse::Unauthorized().body("Invalid username or password"),
-    };
+    let user_id = db
+        .login_user(&json.username, &json.password)
+        .map_err(|_| ApiError::Unauthorized("Invalid username or password".to_string()))?;
 
-    match db.update_last_connected_at(user_id, last_connected_at)
-    {
-        Ok(_) => (),
-        Err(_) => eprintln!("Failed to update last connected at for user {}", user_id),
-    };
+    if db.update_last_connected_at(user_id, last_connected_at).is_err() {
+        eprintln!("Failed to update last connected at for user {}", user_id);
+    }
 
-    let claim = Claims {
-        sub: user_id.to_string(),
-        exp: (Utc::now() + Duration::hours(24)).timestamp() as usize,
-    };
+    Ok(HttpResponse::Ok().json(create_session(&db, user_id, &app_state.config)?))
+}
 
-    let key = "secret".as_bytes();
+#[post("/api/user/refresh")]
+async fn refresh_session(
+    data: Data<RwLock<AppState>>,
+    json: Json<RefreshRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let app_state = data.read()?;
+
+    let db = app_state.database.lock()?;
+
+    let time = Utc::now().timestamp();
+    let refresh_token = generate_refresh_token();
+
+    let user_id = db
+        .rotate_session(
+            &hash_refresh_token(&json.refresh_token),
+            &hash_refresh_token(&refresh_token),
+            time,
+            time + app_state.config.refresh_expiry_secs,
+        )?
+        .ok_or_else(|| ApiError::Unauthorized("Invalid refresh token".to_string()))?;
+
+    Ok(HttpResponse::Ok().json(TokenResponse {
+        token: create_token(user_id, &app_state.config)?,
+        refresh_token,
+        expires_in: app_state.config.jwt_expiry_secs,
+    }))
+}
 
-    let token = match encode(&Header::default(), &claim, &EncodingKey::from_secret(key)) {
-        Ok(t) => t,
-        Err(_) => return HttpResponse::InternalServerError().json("Failed to generate token"),
-    };
+// Ends the session of the refresh token; its access token stays valid until it expires.
+#[post("/api/user/logout")]
+async fn logout_user(
+    data: Data<RwLock<AppState>>,
+    json: Json<RefreshRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let app_state = data.read()?;
+
+    let db = app_state.database.lock()?;
+
+    db.delete_session(&hash_refresh_token(&json.refresh_token))?;
 
-    HttpResponse::Ok().json(token)
+    Ok(HttpResponse::Ok().json("Logged out"))
+}
+
+#[post("/api/user/logout/all")]
+async fn logout_user_everywhere(
+    data: Data<RwLock<AppState>>,
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let app_state = data.read()?;
+
+    let db = app_state.database.lock()?;
+
+    db.delete_user_sessions(user.id)?;
+
+    Ok(HttpResponse::Ok().json("Logged out everywhere"))
 }
 
 #[get("/api/user/profile/{id}")]
 async fn get_user_profile(
     data: Data<RwLock<AppState>>,
     path: Path<String>,
-    req: HttpRequest,
-) -> impl Responder {
-    let user_id;
+    user: OptionalUser,
+) -> Result<HttpResponse, ApiError> {
     let path = path.into_inner();
 
-    if path == "me" {
-        user_id = match check_user(req) {
-            Ok(id) => id,
-            Err(res) => return res,
-        };
+    let user_id = if path == "me" {
+        user.required()?.id
     } else {
-        user_id = match path.parse::<i64>() {
-            Ok(id) => id,
-            Err(_) => return HttpResponse::BadRequest().body("Invalid id"),
-        };
-    }
-
-    let app_state = match data.read() {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read app state"),
+        path.parse::<i64>().map_err(|_| ApiError::BadRequest("Invalid id".to_string()))?
     };
 
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock database"),
-    };
+    let app_state = data.read()?;
+
+    let db = app_state.database.lock()?;
 
     if path == "me" {
-        match db.get_full_user_profile(user_id) {
-            Ok(user_profile) => HttpResponse::Ok().json(user_profile),
-            Err(_) => HttpResponse::InternalServerError().body("Failed to get user profile"),
-        }
+        Ok(HttpResponse::Ok().json(db.get_full_user_profile(user_id)?))
     } else {
-        match db.get_user_profile(user_id) {
-            Ok(user_profile) => HttpResponse::Ok().json(user_profile),
-            Err(_) => HttpResponse::InternalServerError().body("Failed to get user profile"),
-        }
+        Ok(HttpResponse::Ok().json(db.get_user_profile(user_id)?))
     }
 }
 
@@ -243,117 +246,98 @@ async fn get_user_profile(
 async fn get_top_users(
     data: Data<RwLock<AppState>>,
     path: Path<i64>,
-) -> impl Responder {
+) -> Result<HttpResponse, ApiError> {
     let limit = path.into_inner();
 
-    let app_state = match data.read() {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read app state"),
-    };
+    let app_state = data.read()?;
 
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock database"),
-    };
+    let db = app_state.database.lock()?;
 
-    match db.get_top_users(limit) {
-        Ok(users) => HttpResponse::Ok().json(users),
-        Err(_) => HttpResponse::InternalServerError().body("Failed to get top users"),
-    }
+    Ok(HttpResponse::Ok().json(db.get_top_users(limit)?))
 }
 
 #[post("/api/user/edit")]
 async fn edit_user(
     data: Data<RwLock<AppState>>,
     json: Json<EditUserRequest>,
-) -> impl Responder {
-    let app_state = match data.write() {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read app state"),
-    };
+) -> Result<HttpResponse, ApiError> {
+    let app_state = data.write()?;
 
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock database"),
-    };
+    let db = app_state.database.lock()?;
 
-    let user_id = match db.login_user(&json.username, &json.password) {
-        Ok(user_id) => user_id,
-        Err(_) => return HttpResponse::Unauthorized().body("Invalid username or password"),
-    };
+    let user_id = db
+        .login_user(&json.username, &json.password)
+        .map_err(|_| ApiError::Unauthorized("Invalid username or password".to_string()))?;
 
     if !json.new_username.is_empty() {
-        match db.update_username(user_id, &json.new_username) {
-            Ok(_) => (),
-            Err(_) => return HttpResponse::InternalServerError().body("Failed to update username"),
-        };
+        db.update_username(user_id, &json.new_username)?;
+        app_state.forget_username(user_id);
     }
 
     if !json.new_email.is_empty() {
-        match db.update_email(user_id, &json.new_email) {
-            Ok(_) => (),
-            Err(_) => return HttpResponse::InternalServerError().body("Failed to update email"),
-        };
+        db.update_email(user_id, &json.new_email)?;
     }
 
     if !json.new_password.is_empty() {
-        let password_hash = match hash(&json.new_password, DEFAULT_COST) {
-            Ok(hash) => hash,
-            Err(_) => return HttpResponse::InternalServerError().body("Failed to hash password"),
-        };
-
-        match db.update_password(user_id, &password_hash) {
-            Ok(_) => (),
-            Err(_) => return HttpResponse::InternalServerError().body("Failed to update password"),
-        };
+        let password_hash = hash(&json.new_password, DEFAULT_COST)
+            .map_err(|_| ApiError::Internal("Failed to hash password".to_string()))?;
+
+        db.update_password(user_id, &password_hash)?;
+        db.delete_user_sessions(user_id)?;
     }
 
-    HttpResponse::Ok().json("User updated")
+    Ok(HttpResponse::Ok().json("User updated"))
 }
 
-pub fn check_user(req: HttpRequest) -> Result<i64, HttpResponse> {
-    let header = match req.headers().get("Authorization") {
-        Some(header) => header,
-        None => return Err(HttpResponse::Unauthorized().body("No token provided")),
-    };
-
-    let token = match header.to_str() {
-        Ok(token) => token,
-        Err(_) => return Err(HttpResponse::Unauthorized().body("No token provided")),
-    };
-
-    let user_id_str = match decode::<Claims>(
+pub fn check_token(token: &str, secret: &str) -> Option<i64> {
+    let claims = decode::<Claims>(
         token,
-        &DecodingKey::from_secret("secret".as_bytes()),
+        &DecodingKey::from_secret(secret.as_bytes()),
         &Validation::new(Algorithm::HS256),
-    ) {
-        Ok(c) => c.claims.sub,
-        Err(_) => return Err(HttpResponse::Unauthorized().body("Invalid token")),
-    };
+    ).ok()?;
 
-    let user_id = match user_id_str.parse::<i64>() {
-        Ok(id) => id,
-        Err(_) => return Err(HttpResponse::Unauthorized().body("Invalid token")),
-    };
+    claims.claims.sub.parse::<i64>().ok()
+}
 
-    Ok(user_id)
+fn create_session(db: &Database, user_id: i64, config: &Config) -> Result<TokenResponse, ApiError> {
+    let refresh_token = generate_refresh_token();
+    let time = Utc::now().timestamp();
+
+    db.save_new_session(
+        thread_rng().gen::<i64>(),
+        user_id,
+        &hash_refresh_token(&refresh_token),
+        time,
+        time + config.refresh_expiry_secs,
+    )?;
+
+    Ok(TokenResponse {
+        token: create_token(user_id, config)?,
+        refresh_token,
+        expires_in: config.jwt_expiry_secs,
+    })
 }
 
-pub fn check_user_admin(user_id: i64, data: &Data<RwLock<AppState>>) -> Result<bool, HttpResponse> {
-    let app_state = match data.read() {
-        Ok(state) => state,
-        Err(_) => return Err(HttpResponse::InternalServerError().body("Failed to lock state")),
-    };
+fn generate_refresh_token() -> String {
+    thread_rng()
+        .gen::<[u8; 32]>()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
 
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return Err(HttpResponse::InternalServerError().body("Failed to lock database")),
-    };
+// Refresh tokens are 256 random bits, so unlike passwords they don't need a slow salted hash, and
+// a plain one can be looked up.
+fn hash_refresh_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
 
-    let is_admin = match db.is_admin(user_id) {
-        Ok(admin) => admin,
-        Err(_) => return Err(HttpResponse::InternalServerError().body("Failed to check admin")),
+fn create_token(user_id: i64, config: &Config) -> Result<String, ApiError> {
+    let claim = Claims {
+        sub: user_id.to_string(),
+        exp: (Utc::now() + Duration::seconds(config.jwt_expiry_secs)).timestamp() as usize,
     };
 
-    Ok(is_admin)
+    encode(&Header::default(), &claim, &EncodingKey::from_secret(config.jwt_secret.as_bytes()))
+        .map_err(|_| ApiError::Internal("Failed to generate token".to_string()))
 }
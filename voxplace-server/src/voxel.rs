@@ -1,33 +1,67 @@
 use std::io::Write;
 use actix::Message;
-use crossbeam::atomic::AtomicCell;
 use rand::{Rng, thread_rng};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::{Mutex, RwLock};
-use actix_web::{get, HttpRequest, HttpResponse, post, Responder, web};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use actix_web::{get, HttpRequest, HttpResponse, post, web};
 use actix_web::http::header;
-use actix_web::web::{Data, Json, Path};
-use actix_ws::Session;
+use actix_web::web::{Bytes, Data, Json, Path, Query};
 use chrono::Utc;
 use flate2::Compression;
 use flate2::write::GzEncoder;
 use serde_derive::Deserialize;
-use serde_json::json;
 use crate::app_state::AppState;
-use crate::user::check_user;
+use crate::auth::{AuthUser, OptionalUser};
+use crate::config::grid_size_allowed;
+use crate::error::ApiError;
+use crate::format::{gltf, obj, stl, vox, vxl};
+use crate::format::vox::VoxError;
+use crate::format::vxl::VxlError;
+use crate::grid::ChunkedGrid;
+use crate::mesh::Mesh;
+use crate::render;
+use crate::palette::Palette;
+use crate::role::Role;
+use crate::websocket::{encode_update_frame, Cursor, SessionQueue};
+
+const RECENT_UPDATES: usize = 4096;
+const CHAT_COOLDOWN: i64 = 2;
 
 #[derive(Message, Clone, Copy)]
 #[rtype(result = "()")]
 pub struct UpdateMessage(pub usize, pub usize, pub usize, pub u8);
 
+pub struct CursorChanges {
+    pub updated: Vec<(u64, Cursor)>,
+    pub removed: Vec<String>,
+}
+
+pub enum Replay {
+    UpToDate,
+    Frame(Bytes),
+    // The missing updates are no longer buffered; holds the next sequence number.
+    Unavailable(u64),
+}
+
 pub struct Voxel {
     pub id: i64,
     pub name: String,
     pub grid_size: (usize, usize, usize),
-    pub grid: Vec<AtomicCell<u8>>,
+    pub grid: ChunkedGrid,
     pub palette_id: i64,
     pub created_at: i64,
     pub last_modified_at: i64,
-    sessions: Mutex<Vec<Session>>,
+    sessions: Mutex<HashMap<u64, SessionQueue>>,
+    next_session_id: AtomicU64,
+    viewers_changed: AtomicBool,
+    pending_updates: Mutex<Vec<UpdateMessage>>,
+    recent_updates: Mutex<VecDeque<UpdateMessage>>,
+    next_seq: AtomicU64,
+    cursors: Mutex<HashMap<u64, Cursor>>,
+    changed_cursors: Mutex<HashSet<u64>>,
+    pending_messages: Mutex<Vec<String>>,
+    chat_cooldowns: Mutex<HashMap<i64, i64>>,
 }
 
 impl Voxel {
@@ -36,7 +70,7 @@ impl Voxel {
         name: &str,
         palette_id: i64,
         grid_size: (usize, usize, usize),
-        grid: Option<Vec<AtomicCell<u8>>>,
+        grid: Option<ChunkedGrid>,
         created_at: Option<i64>,
         last_modified_at: Option<i64>,
     ) -> Self {
@@ -48,7 +82,16 @@ impl Voxel {
             grid_size,
             grid,
             palette_id,
-            sessions: Mutex::new(Vec::new()),
+            sessions: Mutex::new(HashMap::new()),
+            next_session_id: AtomicU64::new(1),
+            viewers_changed: AtomicBool::new(false),
+            pending_updates: Mutex::new(Vec::new()),
+            recent_updates: Mutex::new(VecDeque::with_capacity(RECENT_UPDATES)),
+            next_seq: AtomicU64::new(1),
+            cursors: Mutex::new(HashMap::new()),
+            changed_cursors: Mutex::new(HashSet::new()),
+            pending_messages: Mutex::new(Vec::new()),
+            chat_cooldowns: Mutex::new(HashMap::new()),
             created_at: created_at.unwrap_or_else(|| Utc::now().timestamp()),
             last_modified_at: last_modified_at.unwrap_or_else(|| Utc::now().timestamp()),
         }
@@ -60,99 +103,252 @@ impl Voxel {
         y: usize,
         z: usize,
         color: u8,
-    ) -> Result<(), String> {
+    ) -> Result<u8, String> {
         let grid = &self.grid;
         let at_bottom = y == 0;
         let mut has_neighbor = false;
 
-        if x >= self.grid_size.0 || y >= self.grid_size.1 || z >= self.grid_size.2 {
+        if !grid.layout().contains(x, y, z) {
             return Err("Out of bounds".to_string());
         }
 
-        if x > 0 && grid[self.get_index(x - 1, y, z)].load() > 0 {
+        // Cells outside the grid read as empty, so only the lower bounds need a check.
+        if x > 0 && grid.get(x - 1, y, z) > 0 {
             has_neighbor = true;
         }
-        if x < self.grid_size.0 - 1 && grid[self.get_index(x + 1, y, z)].load() > 0 {
+        if grid.get(x + 1, y, z) > 0 {
             has_neighbor = true;
         }
-        if y > 0 && grid[self.get_index(x, y - 1, z)].load() > 0 {
+        if y > 0 && grid.get(x, y - 1, z) > 0 {
             has_neighbor = true;
         }
-        if y < self.grid_size.1 - 1 && grid[self.get_index(x, y + 1, z)].load() > 0 {
+        if grid.get(x, y + 1, z) > 0 {
             has_neighbor = true;
         }
-        if z > 0 && grid[self.get_index(x, y, z - 1)].load() > 0 {
+        if z > 0 && grid.get(x, y, z - 1) > 0 {
             has_neighbor = true;
         }
-        if z < self.grid_size.2 - 1 && grid[self.get_index(x, y, z + 1)].load() > 0 {
+        if grid.get(x, y, z + 1) > 0 {
             has_neighbor = true;
         }
 
-        if at_bottom || has_neighbor || grid[self.get_index(x, y, z)].load() > 0 {
-            grid[self.get_index(x, y, z)].store(color);
+        let old_color = grid.get(x, y, z);
+        if at_bottom || has_neighbor || old_color > 0 {
+            grid.set(x, y, z, color);
             self.broadcast(UpdateMessage(x, y, z, color));
-            Ok(())
+            Ok(old_color)
         } else {
             Err("Voxel has no neighbors".to_string())
         }
     }
 
-    pub fn add_session(&self, session: Session) {
-        self.sessions.lock().unwrap().push(session);
+    // Moderation path: no neighbor rule, but connected clients still get the update.
+    pub fn overwrite_voxel(&self, x: usize, y: usize, z: usize, color: u8) {
+        self.grid.set(x, y, z, color);
+        self.broadcast(UpdateMessage(x, y, z, color));
     }
 
-    fn broadcast(&self, update_message: UpdateMessage) {
-        let sessions = self.sessions.lock().unwrap();
-        for session in sessions.iter() {
-            let msg = serde_json::to_string(&json!({
-                "type": "update",
-                "x": update_message.0,
-                "y": update_message.1,
-                "z": update_message.2,
-                "color": update_message.3,
-            }))
-            .unwrap();
-            let mut cloned_session = session.clone();
-            actix_web::rt::spawn(async move {
-                let _ = cloned_session.text(msg).await;
-            });
+    pub fn get(&self, x: usize, y: usize, z: usize) -> u8 {
+        self.grid.get(x, y, z)
+    }
+
+    pub fn set(&self, x: usize, y: usize, z: usize, color: u8) {
+        self.grid.set(x, y, z, color);
+    }
+
+    pub fn get_grid_bytes(&self) -> Vec<u8> {
+        self.grid.to_bytes()
+    }
+
+    pub fn snapshot(&self) -> Voxel {
+        Voxel::new(
+            self.id,
+            &self.name,
+            self.palette_id,
+            self.grid_size,
+            Some(self.grid.snapshot()),
+            Some(self.created_at),
+            Some(self.last_modified_at),
+        )
+    }
+
+    pub fn add_session(&self, session: SessionQueue) -> u64 {
+        let id = self.next_session_id.fetch_add(1, Ordering::AcqRel);
+        self.sessions.lock().unwrap().insert(id, session);
+        self.viewers_changed.store(true, Ordering::Release);
+        id
+    }
+
+    pub fn remove_session(&self, id: u64) {
+        if self.sessions.lock().unwrap().remove(&id).is_some() {
+            self.viewers_changed.store(true, Ordering::Release);
         }
+        self.remove_cursor(id);
     }
 
-    fn get_index(&self, x: usize, y: usize, z: usize) -> usize {
-        x * self.grid_size.0 * self.grid_size.0 + y * self.grid_size.1 + z
+    pub fn sessions(&self) -> Vec<(u64, SessionQueue)> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, session)| (*id, session.clone()))
+            .collect()
     }
 
-    #[allow(dead_code)]
-    fn generate_random_grid(grid_size: (usize, usize, usize)) -> Vec<AtomicCell<u8>> {
-        let mut rng = rand::thread_rng();
-        let mut grid_data = Vec::new();
-        for _ in 0..grid_size.0 {
-            for y in 0..grid_size.1 {
-                for _ in 0..grid_size.2 {
-                    let voxel_spawn_rate =
-                        1.0 / (1.0 + ((y as f64 / grid_size.1 as f64) * 16.0 - 1.0).exp());
-                    if rng.gen::<f64>() < voxel_spawn_rate {
-                        grid_data.push(rng.gen_range(1..=32));
-                    } else {
-                        grid_data.push(0);
-                    }
-                }
+    pub fn viewers(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    // The viewer count, if sessions joined or left since the last call.
+    pub fn take_viewers_change(&self) -> Option<usize> {
+        if self.viewers_changed.swap(false, Ordering::AcqRel) {
+            Some(self.viewers())
+        } else {
+            None
+        }
+    }
+
+    pub fn set_cursor(&self, session_id: u64, cursor: Cursor) {
+        self.cursors.lock().unwrap().insert(session_id, cursor);
+        self.changed_cursors.lock().unwrap().insert(session_id);
+    }
+
+    pub fn remove_cursor(&self, session_id: u64) {
+        if self.cursors.lock().unwrap().remove(&session_id).is_some() {
+            self.changed_cursors.lock().unwrap().insert(session_id);
+        }
+    }
+
+    // Cursors set or removed since the last call; a session that moved several times only reports
+    // its latest cursor.
+    pub fn take_cursor_changes(&self) -> Option<CursorChanges> {
+        let changed: Vec<u64> = self.changed_cursors.lock().unwrap().drain().collect();
+        if changed.is_empty() {
+            return None;
+        }
+
+        let cursors = self.cursors.lock().unwrap();
+        let mut updated = Vec::new();
+        let mut removed = Vec::new();
+        for session_id in changed {
+            match cursors.get(&session_id) {
+                Some(cursor) => updated.push((session_id, cursor.clone())),
+                None => removed.push(session_id.to_string()),
             }
         }
-        grid_data.into_iter().map(AtomicCell::new).collect()
+        Some(CursorChanges { updated, removed })
+    }
+
+    // JSON messages for every viewer, sent along with the next batch of updates.
+    pub fn broadcast_message(&self, message: String) {
+        self.pending_messages.lock().unwrap().push(message);
+    }
+
+    pub fn take_messages(&self) -> Vec<String> {
+        self.pending_messages.lock().unwrap().drain(..).collect()
+    }
+
+    // Starts the user's chat cooldown, or returns when the running one ends. Cooldowns are shared
+    // by all of a user's sessions on this place and are not persisted.
+    pub fn start_chat_cooldown(&self, user_id: i64, time: i64) -> Result<(), i64> {
+        let mut chat_cooldowns = self.chat_cooldowns.lock().unwrap();
+        match chat_cooldowns.get(&user_id) {
+            Some(&cooldown) if cooldown > time => Err(cooldown),
+            _ => {
+                chat_cooldowns.retain(|_, cooldown| *cooldown > time);
+                chat_cooldowns.insert(user_id, time + CHAT_COOLDOWN);
+                Ok(())
+            }
+        }
+    }
+
+    // For a message that failed to send: the cooldown it started was the only running one.
+    pub fn cancel_chat_cooldown(&self, user_id: i64) {
+        self.chat_cooldowns.lock().unwrap().remove(&user_id);
+    }
+
+    // Updates are queued and sent in batches by `run_update_batching`.
+    fn broadcast(&self, update_message: UpdateMessage) {
+        self.pending_updates.lock().unwrap().push(update_message);
+    }
+
+    // Drains the queued updates into one frame. A voxel drawn several times since the last frame
+    // only keeps its last color; the remaining updates get consecutive sequence numbers.
+    pub fn take_update_frame(&self) -> Option<Bytes> {
+        let updates: Vec<UpdateMessage> = self.pending_updates.lock().unwrap().drain(..).collect();
+        if updates.is_empty() {
+            return None;
+        }
+
+        let mut seen = HashSet::new();
+        let mut coalesced: Vec<UpdateMessage> = updates
+            .into_iter()
+            .rev()
+            .filter(|update| seen.insert((update.0, update.1, update.2)))
+            .collect();
+        coalesced.reverse();
+
+        // The last RECENT_UPDATES updates are kept for replay; they hold the sequence numbers
+        // right before next_seq, which is only moved while the buffer is locked.
+        let mut recent_updates = self.recent_updates.lock().unwrap();
+        let seq = self.next_seq.load(Ordering::Acquire);
+        recent_updates.extend(coalesced.iter().copied());
+        while recent_updates.len() > RECENT_UPDATES {
+            recent_updates.pop_front();
+        }
+        self.next_seq.store(seq + coalesced.len() as u64, Ordering::Release);
+
+        Some(encode_update_frame(seq, &coalesced))
+    }
+
+    // For changes too large to replay, like a restored grid: queued and recent updates are dropped
+    // and the sequence moves on, so any resync asks for the grid. Returns the new sequence number.
+    pub fn reset_updates(&self) -> u64 {
+        let mut recent_updates = self.recent_updates.lock().unwrap();
+        self.pending_updates.lock().unwrap().clear();
+        recent_updates.clear();
+        self.next_seq.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq.load(Ordering::Acquire)
+    }
+
+    pub fn replay_updates(&self, from_seq: u64) -> Replay {
+        let recent_updates = self.recent_updates.lock().unwrap();
+        let next_seq = self.next_seq.load(Ordering::Acquire);
+        let oldest_seq = next_seq - recent_updates.len() as u64;
+
+        if from_seq >= next_seq {
+            return Replay::UpToDate;
+        }
+        if from_seq < oldest_seq {
+            return Replay::Unavailable(next_seq);
+        }
+
+        let updates: Vec<UpdateMessage> = recent_updates
+            .iter()
+            .skip((from_seq - oldest_seq) as usize)
+            .copied()
+            .collect();
+        Replay::Frame(encode_update_frame(from_seq, &updates))
     }
 
-    fn generate_empty_grid(grid_size: (usize, usize, usize)) -> Vec<AtomicCell<u8>> {
-        let mut grid_data = Vec::new();
-        for _ in 0..grid_size.0 {
-            for _ in 0..grid_size.1 {
-                for _ in 0..grid_size.2 {
-                    grid_data.push(0);
-                }
+    fn generate_random_grid(grid_size: (usize, usize, usize)) -> ChunkedGrid {
+        let mut rng = rand::thread_rng();
+        let grid = ChunkedGrid::new(grid_size);
+        for (x, y, z) in grid.layout().iter() {
+            let voxel_spawn_rate =
+                1.0 / (1.0 + ((y as f64 / grid_size.1 as f64) * 16.0 - 1.0).exp());
+            if rng.gen::<f64>() < voxel_spawn_rate {
+                grid.set(x, y, z, rng.gen_range(1..=32));
             }
         }
-        grid_data.into_iter().map(AtomicCell::new).collect()
+        grid
+    }
+
+    pub fn generate_empty_grid(grid_size: (usize, usize, usize)) -> ChunkedGrid {
+        ChunkedGrid::new(grid_size)
     }
 }
 
@@ -166,142 +362,393 @@ struct CreateVoxelRequest {
 async fn get_voxel(
     data: Data<RwLock<AppState>>,
     path: Path<String>
-) -> impl Responder {
-    let id = match path.into_inner().parse::<i64>() {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid place"),
-    };
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_voxel_id(&path.into_inner())?;
 
-    let app_state = match data.read() {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read app state"),
-    };
+    let app_state = data.read()?;
 
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read database"),
-    };
+    let db = app_state.database.lock()?;
 
-    let voxel = match db.get_voxel(id) {
-        Ok(info) => info,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read voxel info"),
-    };
+    let voxel = db.get_voxel(id)?;
 
-    let grid: Vec<u8> = voxel.grid.iter().map(|cell| cell.load()).collect();
+    let grid = voxel.get_grid_bytes();
 
+    let compress_error = |_| ApiError::Internal("Failed to compress data".to_string());
     let mut e = GzEncoder::new(Vec::new(), Compression::default());
-    match e.write_all(&grid) {
-        Ok(_) => (),
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to compress data"),
-    };
-
-    let compressed_data = match e.finish() {
-        Ok(data) => data,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to compress data"),
-    };
+    e.write_all(&grid).map_err(compress_error)?;
+    let compressed_data = e.finish().map_err(compress_error)?;
 
-    HttpResponse::Ok()
+    Ok(HttpResponse::Ok()
         .append_header((header::CONTENT_ENCODING, "gzip"))
-        .body(compressed_data)
+        .body(compressed_data))
 }
 
+// Users save the voxels they own, admins any other voxel. A place's voxel is live: it is only
+// changed by drawing or restoring a snapshot, so a save would be overwritten by the next flush.
 #[post("/api/voxel/save/{id}")]
 async fn save_voxel(
     data: Data<RwLock<AppState>>,
     path: Path<String>,
     body: web::Bytes,
-) -> impl Responder {
-    let id = match path.into_inner().parse::<i64>() {
-        Ok(id) => id,
-        Err(_) => return HttpResponse::BadRequest().body("Invalid voxel"),
+    user: AuthUser,
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_voxel_id(&path.into_inner())?;
+
+    let (owned, is_place) = {
+        let app_state = data.read()?;
+        let owned = app_state.database.lock()?.user_owns_voxel(user.id, id)?;
+        let is_place = app_state
+            .places
+            .values()
+            .any(|place| place.read().is_ok_and(|place| place.voxel.id == id));
+        (owned, is_place)
     };
+    if is_place {
+        return Err(ApiError::Conflict("Place voxels can't be saved".to_string()));
+    }
+    if !owned {
+        user.require(Role::Admin, None)?;
+    }
 
-    let app_state = match data.read() {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read app state"),
-    };
+    let app_state = data.read()?;
 
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read database"),
-    };
+    let db = app_state.database.lock()?;
 
     let grid = body.to_vec();
 
-    match db.save_voxel_grid(id, grid) {
-        Ok(_) => (),
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to save voxel grid"),
-    };
+    db.save_voxel_grid(id, grid)?;
 
-    HttpResponse::Ok().body("Voxel saved")
+    Ok(HttpResponse::Ok().body("Voxel saved"))
 }
 
 #[get("/api/voxel/user/{user_id}")]
 async fn get_user_voxels(
     data: Data<RwLock<AppState>>,
     path: Path<String>,
-    req: HttpRequest,
-) -> impl Responder {
+    user: OptionalUser,
+) -> Result<HttpResponse, ApiError> {
     let path = path.into_inner();
     let user_id = if path == "me" {
-        match check_user(req) {
-            Ok(id) => id,
-            Err(_) => return HttpResponse::Unauthorized().body("Unauthorized"),
-        }
+        user.required()?.id
     } else {
-        match path.parse::<i64>() {
-            Ok(id) => id,
-            Err(_) => return HttpResponse::BadRequest().body("Invalid user"),
-        }
+        path.parse::<i64>().map_err(|_| ApiError::BadRequest("Invalid user".to_string()))?
     };
 
-    let app_state = match data.read() {
-        Ok(state) => state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to read app state"),
-    };
-
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock database"),
-    };
+    let app_state = data.read()?;
 
-    let voxels = match db.get_user_voxels(user_id) {
-        Ok(voxels) => voxels,
-        Err(e) => return HttpResponse::InternalServerError().body(format!("Failed to get user voxels : {}", e)),
-    };
+    let db = app_state.database.lock()?;
 
-    HttpResponse::Ok().json(voxels)
+    Ok(HttpResponse::Ok().json(db.get_user_voxels(user_id)?))
 }
 
 #[post("/api/voxel/create")]
 async fn create_voxel(
     data: Data<RwLock<AppState>>,
-    req: HttpRequest,
+    user: AuthUser,
     json: Json<CreateVoxelRequest>,
-) -> impl Responder {
-    let user_id = match check_user(req) {
-        Ok(id) => id,
-        Err(res) => return res,
-    };
+) -> Result<HttpResponse, ApiError> {
+    let user_id = user.id;
 
     let voxel_id = thread_rng().gen::<i64>();
 
+    let mut app_state = data.write()?;
+
+    if !grid_size_allowed(json.size, app_state.config.max_voxel_size) {
+        return Err(ApiError::InvalidSize("Voxel", app_state.config.max_voxel_size));
+    }
+
     let voxel = Voxel::new(voxel_id, &json.name, 0, json.size, None, None, None);
 
-    let mut app_state = match data.write() {
-        Ok(app_state) => app_state,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock app state"),
+    app_state.add_voxel(voxel);
+
+    let db = app_state.database.lock()?;
+
+    db.save_new_user_voxel(user_id, voxel_id)?;
+
+    Ok(HttpResponse::Ok().json("Voxel user link created"))
+}
+
+#[get("/api/voxel/export/{id}.vxl")]
+async fn export_voxel_vxl(
+    data: Data<RwLock<AppState>>,
+    path: Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let (voxel, palette) = load_voxel_with_palette(&data, path.into_inner())?;
+
+    let bytes = vxl::encode(&voxel, &palette, true)
+        .map_err(|e| ApiError::Internal(format!("Failed to export voxel : {}", e)))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .append_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.vxl\"", voxel.id)))
+        .body(bytes))
+}
+
+#[get("/api/voxel/export/{id}.vox")]
+async fn export_voxel_vox(
+    data: Data<RwLock<AppState>>,
+    path: Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let (voxel, palette) = load_voxel_with_palette(&data, path.into_inner())?;
+
+    let bytes = vox::encode(&voxel, &palette)
+        .map_err(|e| ApiError::BadRequest(format!("Failed to export voxel : {}", e)))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .append_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.vox\"", voxel.id)))
+        .body(bytes))
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    format: String,
+    scale: Option<f32>,
+}
+
+#[get("/api/voxel/export/{id}")]
+async fn export_voxel(
+    data: Data<RwLock<AppState>>,
+    path: Path<String>,
+    query: Query<ExportQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let path = path.into_inner();
+
+    export_mesh(move || load_voxel_with_palette(&data, path), query.into_inner()).await
+}
+
+// Loading and meshing a large grid takes a while, so both run on the blocking pool.
+pub async fn export_mesh<F>(load: F, query: ExportQuery) -> Result<HttpResponse, ApiError>
+where
+    F: FnOnce() -> Result<(Voxel, Palette), ApiError> + Send + 'static,
+{
+    let scale = query.scale.unwrap_or(1.0);
+    if !scale.is_finite() || scale <= 0.0 {
+        return Err(ApiError::BadRequest("Invalid scale".to_string()));
+    }
+    let content_type = match query.format.as_str() {
+        "obj" => "model/obj",
+        "mtl" => "model/mtl",
+        "stl" => "model/stl",
+        _ => return Err(ApiError::BadRequest("Invalid format".to_string())),
     };
 
-    app_state.add_voxel(voxel);
+    let format = query.format.clone();
+    let (id, bytes) = web::block(move || {
+        let (voxel, palette) = load()?;
+        let bytes = match format.as_str() {
+            "obj" => {
+                let mesh = Mesh::greedy(&voxel);
+                obj::encode_obj(&mesh, &format!("{}.mtl", voxel.id), scale).into_bytes()
+            }
+            "mtl" => {
+                let mesh = Mesh::greedy(&voxel);
+                obj::encode_mtl(&mesh, &palette).into_bytes()
+            }
+            _ => {
+                let mesh = Mesh::surface(&voxel);
+                stl::encode_stl(&mesh, voxel.grid_size.2, scale)
+                    .map_err(|_| ApiError::Internal("Failed to encode mesh".to_string()))?
+            }
+        };
+        Ok::<_, ApiError>((voxel.id, bytes))
+    })
+    .await
+    .map_err(|_| ApiError::Internal("Failed to encode mesh".to_string()))??;
+
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .append_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}.{}\"", id, query.format),
+        ))
+        .body(bytes))
+}
+
+#[derive(Deserialize)]
+struct ThumbnailQuery {
+    size: Option<usize>,
+    angle: Option<f32>,
+}
+
+#[get("/api/voxel/thumbnail/{id}.png")]
+async fn get_voxel_thumbnail(
+    data: Data<RwLock<AppState>>,
+    path: Path<String>,
+    query: Query<ThumbnailQuery>,
+    req: HttpRequest,
+) -> Result<HttpResponse, ApiError> {
+    let path = path.into_inner();
+    let id = parse_voxel_id(&path)?;
+
+    let size = render::thumbnail_size(query.size.unwrap_or(256));
+    let angle = render::thumbnail_angle(query.angle.unwrap_or(45.0));
+    let key = (id, size, angle);
+
+    let last_modified_at = {
+        let app_state = data.read()?;
+
+        let last_modified_at = app_state.database.lock()?.get_voxel_last_modified_at(id)?;
 
-    let db = match app_state.database.lock() {
-        Ok(db) => db,
-        Err(_) => return HttpResponse::InternalServerError().body("Failed to lock database"),
+        let etag = format!("\"{}-{}-{}-{}\"", id, last_modified_at, size, angle);
+        let not_modified = req
+            .headers()
+            .get(header::IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value == etag);
+        if not_modified {
+            return Ok(HttpResponse::NotModified().append_header((header::ETAG, etag)).finish());
+        }
+
+        let cached = match app_state.thumbnails.lock() {
+            Ok(mut thumbnails) => thumbnails.get(key, last_modified_at),
+            Err(_) => None,
+        };
+        if let Some(png) = cached {
+            return Ok(thumbnail_response(png, etag));
+        }
+
+        last_modified_at
     };
 
-    match db.save_new_user_voxel(user_id, voxel_id) {
-        Ok(_) => HttpResponse::Ok().json("Voxel user link created"),
-        Err(e) => HttpResponse::InternalServerError().body(format!("Failed to create voxel user link : {}", e)),
+    // Loading decompresses the whole grid, so it runs on the blocking pool with the render.
+    let block_data = data.clone();
+    let png = web::block(move || {
+        let (voxel, palette) = load_voxel_with_palette(&block_data, path)?;
+        render::render(&voxel, &palette, size, angle as f32)
+            .encode_png()
+            .map_err(|_| ApiError::Internal("Failed to render thumbnail".to_string()))
+    })
+    .await
+    .map_err(|_| ApiError::Internal("Failed to render thumbnail".to_string()))??;
+    let png = web::Bytes::from(png);
+
+    if let Ok(app_state) = data.read() {
+        if let Ok(mut thumbnails) = app_state.thumbnails.lock() {
+            thumbnails.insert(key, last_modified_at, png.clone());
+        }
+    }
+
+    Ok(thumbnail_response(png, format!("\"{}-{}-{}-{}\"", id, last_modified_at, size, angle)))
+}
+
+fn thumbnail_response(png: web::Bytes, etag: String) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("image/png")
+        .append_header((header::ETAG, etag))
+        .append_header((header::CACHE_CONTROL, "public, max-age=60"))
+        .body(png)
+}
+
+#[get("/api/voxel/mesh/{id}.glb")]
+async fn get_voxel_mesh(
+    data: Data<RwLock<AppState>>,
+    path: Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let path = path.into_inner();
+
+    let bytes = web::block(move || {
+        let (voxel, palette) = load_voxel_with_palette(&data, path)?;
+        let mesh = Mesh::greedy(&voxel);
+        gltf::encode_glb(&voxel.name, &mesh, &palette)
+            .map_err(|_| ApiError::Internal("Failed to encode mesh".to_string()))
+    })
+    .await
+    .map_err(|_| ApiError::Internal("Failed to encode mesh".to_string()))??;
+
+    Ok(HttpResponse::Ok().content_type("model/gltf-binary").body(bytes))
+}
+
+#[post("/api/voxel/import")]
+async fn import_voxel_vxl(
+    data: Data<RwLock<AppState>>,
+    user: AuthUser,
+    body: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = user.id;
+
+    let voxel_id = thread_rng().gen::<i64>();
+    let palette_id = thread_rng().gen::<i64>();
+    let max_size = data.read()?.config.max_voxel_size;
+
+    let (voxel, palette) = vxl::decode(&body, voxel_id, palette_id, max_size).map_err(|e| match e {
+        VxlError::InvalidGridSize(_) => ApiError::InvalidSize("Voxel", max_size),
+        e => ApiError::BadRequest(format!("Invalid vxl file : {}", e)),
+    })?;
+
+    save_imported_voxel(&data, user_id, voxel, palette)
+}
+
+#[derive(Deserialize)]
+struct ImportVoxQuery {
+    name: Option<String>,
+}
+
+#[post("/api/voxel/import/vox")]
+async fn import_voxel_vox(
+    data: Data<RwLock<AppState>>,
+    user: AuthUser,
+    query: Query<ImportVoxQuery>,
+    body: web::Bytes,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = user.id;
+
+    let voxel_id = thread_rng().gen::<i64>();
+    let palette_id = thread_rng().gen::<i64>();
+    let name = query.name.as_deref().unwrap_or("Imported voxel");
+    let max_size = data.read()?.config.max_voxel_size;
+
+    let (voxel, palette) = vox::decode(&body, name, voxel_id, palette_id, max_size).map_err(|e| match e {
+        VoxError::InvalidGridSize(_) => ApiError::InvalidSize("Voxel", max_size),
+        e => ApiError::BadRequest(format!("Invalid vox file : {}", e)),
+    })?;
+
+    save_imported_voxel(&data, user_id, voxel, palette)
+}
+
+fn parse_voxel_id(id: &str) -> Result<i64, ApiError> {
+    id.parse::<i64>().map_err(|_| ApiError::BadRequest("Invalid voxel".to_string()))
+}
+
+fn load_voxel_with_palette(data: &Data<RwLock<AppState>>, id: String) -> Result<(Voxel, Palette), ApiError> {
+    let id = parse_voxel_id(&id)?;
+
+    let app_state = data.read()?;
+
+    let db = app_state.database.lock()?;
+
+    let voxel = db.get_voxel(id)?;
+
+    let palette = db.get_full_palette(voxel.palette_id)?;
+
+    Ok((voxel, palette))
+}
+
+fn save_imported_voxel(
+    data: &Data<RwLock<AppState>>,
+    user_id: i64,
+    mut voxel: Voxel,
+    palette: Palette,
+) -> Result<HttpResponse, ApiError> {
+    let voxel_id = voxel.id;
+
+    let mut app_state = data.write()?;
+
+    {
+        let db = app_state.database.lock()?;
+
+        if palette.same_entries(&Palette::new(0, None, None)) {
+            voxel.palette_id = 0;
+        } else {
+            db.save_new_palette(palette)?;
+        }
     }
-}
\ No newline at end of file
+
+    app_state.add_voxel(voxel);
+
+    let db = app_state.database.lock()?;
+
+    db.save_new_user_voxel(user_id, voxel_id)?;
+
+    Ok(HttpResponse::Ok().json(voxel_id.to_string()))
+}
@@ -1,46 +1,593 @@
-use crate::place::Place;
-use actix_ws::{Message, MessageStream, Session};
+use crate::app_state::AppState;
+use crate::comment::Comment;
+use crate::database::comment::PlaceComment;
+use crate::place::{DrawError, Place};
+use crate::user::check_token;
+use crate::voxel::{Replay, UpdateMessage, Voxel};
+use actix_web::web::{Bytes, Data};
+use actix_ws::{CloseReason, Message, MessageStream, Session};
+use chrono::Utc;
+use rand::{Rng, thread_rng};
+use std::future::Future;
 use std::sync::{Arc, RwLock};
+use std::pin::pin;
+use std::time::{Duration, Instant};
+use futures_util::future::{select, Either};
 use futures_util::StreamExt;
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Auth {
+        token: String,
+    },
+    Draw {
+        id: Option<u64>,
+        x: usize,
+        y: usize,
+        z: usize,
+        color: u8,
+    },
+    Resync {
+        from_seq: u64,
+    },
+    Cursor {
+        position: [f32; 3],
+        camera: [f32; 3],
+        color: Option<u8>,
+    },
+    HideCursor,
+    Chat {
+        id: Option<u64>,
+        content: String,
+    },
+}
+
+// Live presence of a viewer who opted in by sending cursor messages. It only lives in memory and
+// is gone once the session ends or the viewer hides it.
+#[derive(Serialize, Clone)]
+pub struct Cursor {
+    pub session_id: String,
+    pub username: String,
+    pub position: [f32; 3],
+    pub camera: [f32; 3],
+    pub color: Option<u8>,
+}
+
+// `id` echoes the id of the client request being answered, if it had one.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Authenticated {
+        user_id: String,
+    },
+    Ack {
+        id: Option<u64>,
+        username: String,
+        cooldown: i64,
+    },
+    Error {
+        id: Option<u64>,
+        code: &'static str,
+        message: String,
+        cooldown: Option<i64>,
+    },
+    Presence {
+        viewers: usize,
+    },
+    // The requested updates are gone: refetch the grid, which carries the sequence number to
+    // continue from in its X-Place-Seq header.
+    ResyncRequired {
+        seq: u64,
+    },
+    UpToDate {
+        seq: u64,
+    },
+    // The grid changed too much to send as updates: refetch it, like for ResyncRequired.
+    ReloadGrid {
+        seq: u64,
+    },
+    // Cursors that moved since the last message, and the sessions whose cursor went away.
+    Cursors {
+        cursors: Vec<Cursor>,
+        removed: Vec<String>,
+    },
+    ChatSent {
+        id: Option<u64>,
+        comment_id: String,
+    },
+    Chat {
+        comment: PlaceComment,
+    },
+    ChatDeleted {
+        comment_id: String,
+    },
+}
+
+enum Reply {
+    Message(ServerMessage),
+    Frame(Bytes),
+    None,
+}
+
+impl ServerMessage {
+    fn error(id: Option<u64>, code: &'static str, message: &str) -> Self {
+        ServerMessage::Error {
+            id,
+            code,
+            message: message.to_string(),
+            cooldown: None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+const UPDATE_BATCH_INTERVAL_MS: u64 = 50;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+const UPDATE_FRAME: u8 = 1;
+const CURSOR_MIN_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_CHAT_LENGTH: usize = 500;
+const SESSION_QUEUE: usize = 64;
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub enum Outgoing {
+    Text(String),
+    Binary(Bytes),
+    Ping,
+    Pong(Bytes),
+    Close(Option<CloseReason>),
+}
+
+// Messages for a session wait in a bounded queue that only its own writer task drains, so nothing
+// else ever waits on the socket. A session whose queue fills up, or whose socket takes longer than
+// SEND_TIMEOUT to accept a message, has its writer stopped, which drops the connection.
+#[derive(Clone)]
+pub struct SessionQueue {
+    sender: mpsc::Sender<Outgoing>,
+    writer: AbortHandle,
+}
+
+impl SessionQueue {
+    pub fn new(session: Session) -> Self {
+        Self::spawn(move |message| {
+            let mut session = session.clone();
+            async move {
+                match message {
+                    Outgoing::Text(text) => session.text(text).await.is_ok(),
+                    Outgoing::Binary(bytes) => session.binary(bytes).await.is_ok(),
+                    Outgoing::Ping => session.ping(b"").await.is_ok(),
+                    Outgoing::Pong(bytes) => session.pong(&bytes).await.is_ok(),
+                    Outgoing::Close(reason) => session.close(reason).await.is_ok(),
+                }
+            }
+        })
+    }
+
+    fn spawn<W, F>(mut write: W) -> Self
+    where
+        W: FnMut(Outgoing) -> F + 'static,
+        F: Future<Output = bool> + 'static,
+    {
+        let (sender, mut receiver) = mpsc::channel(SESSION_QUEUE);
+        let writer = actix_web::rt::spawn(async move {
+            while let Some(message) = receiver.recv().await {
+                let close = matches!(message, Outgoing::Close(_));
+                let sent = actix_web::rt::time::timeout(SEND_TIMEOUT, write(message)).await;
+                if close || !matches!(sent, Ok(true)) {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            sender,
+            writer: writer.abort_handle(),
+        }
+    }
+
+    // Never waits: returns false, and stops the session, if the message can't be queued.
+    pub fn send(&self, message: Outgoing) -> bool {
+        if self.sender.try_send(message).is_ok() {
+            return true;
+        }
+        self.writer.abort();
+        false
+    }
+
+    pub fn close(&self, reason: Option<CloseReason>) {
+        self.send(Outgoing::Close(reason));
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+// Binary update frame: a type byte (1), the sequence number of the first update (u64), the update
+// count (u32), then per update x, y, z (u16 each) and the color (u8). Integers are little-endian
+// and the updates are numbered consecutively.
+pub fn encode_update_frame(seq: u64, updates: &[UpdateMessage]) -> Bytes {
+    let mut frame = Vec::with_capacity(13 + updates.len() * 7);
+    frame.push(UPDATE_FRAME);
+    frame.extend(seq.to_le_bytes());
+    frame.extend((updates.len() as u32).to_le_bytes());
+    for update in updates {
+        frame.extend((update.0 as u16).to_le_bytes());
+        frame.extend((update.1 as u16).to_le_bytes());
+        frame.extend((update.2 as u16).to_le_bytes());
+        frame.push(update.3);
+    }
+    Bytes::from(frame)
+}
+
+// Every UPDATE_BATCH_INTERVAL_MS, the updates queued on each place are sent to its sessions.
+pub async fn run_update_batching(data: Data<RwLock<AppState>>) {
+    let mut interval = actix_web::rt::time::interval(Duration::from_millis(UPDATE_BATCH_INTERVAL_MS));
+    loop {
+        interval.tick().await;
+
+        let voxels: Vec<Arc<Voxel>> = match data.read() {
+            Ok(app_state) => app_state
+                .places
+                .iter()
+                .filter_map(|(place_id, place)| match place.read() {
+                    Ok(place) => Some(place.voxel.clone()),
+                    Err(_) => {
+                        eprintln!("Skipping updates of place {}: lock poisoned", place_id);
+                        None
+                    }
+                })
+                .collect(),
+            Err(_) => continue,
+        };
+
+        for voxel in voxels {
+            send_pending(&voxel);
+        }
+    }
+}
+
+// The queued updates are encoded once and the same frame is handed to every session, followed by
+// a presence message if viewers came or went and one with the cursors that changed, leaving out
+// each viewer's own, then any chat messages. Only queues are written to, so a slow viewer delays
+// no one; sessions whose queue is full or closed are dropped.
+pub fn send_pending(voxel: &Voxel) {
+    let frame = voxel.take_update_frame();
+    let presence = voxel
+        .take_viewers_change()
+        .map(|viewers| ServerMessage::Presence { viewers }.to_json());
+    let cursor_changes = voxel.take_cursor_changes();
+    let messages = voxel.take_messages();
+    if frame.is_none() && presence.is_none() && cursor_changes.is_none() && messages.is_empty() {
+        return;
+    }
+
+    for (id, session) in voxel.sessions() {
+        let mut sent = true;
+        if let Some(frame) = &frame {
+            sent = session.send(Outgoing::Binary(frame.clone()));
+        }
+        if let Some(presence) = &presence {
+            sent = sent && session.send(Outgoing::Text(presence.clone()));
+        }
+        if let Some(changes) = &cursor_changes {
+            let cursors: Vec<Cursor> = changes
+                .updated
+                .iter()
+                .filter(|(session_id, _)| *session_id != id)
+                .map(|(_, cursor)| cursor.clone())
+                .collect();
+            if !cursors.is_empty() || !changes.removed.is_empty() {
+                let message = ServerMessage::Cursors {
+                    cursors,
+                    removed: changes.removed.clone(),
+                };
+                sent = sent && session.send(Outgoing::Text(message.to_json()));
+            }
+        }
+        for message in &messages {
+            sent = sent && session.send(Outgoing::Text(message.clone()));
+        }
+        if !sent {
+            voxel.remove_session(id);
+        }
+    }
+}
 
 pub struct PlaceWebSocketConnection {
     pub place: Arc<RwLock<Place>>,
+    voxel: Arc<Voxel>,
+    place_id: i64,
+    data: Data<RwLock<AppState>>,
+    session_id: u64,
+    user_id: Option<i64>,
+    username: Option<String>,
+    last_cursor: Option<Instant>,
 }
 
 impl PlaceWebSocketConnection {
-    pub fn new(place: Arc<RwLock<Place>>) -> Self {
-        Self { place }
+    pub fn new(place: Arc<RwLock<Place>>, voxel: Arc<Voxel>, place_id: i64, data: Data<RwLock<AppState>>) -> Self {
+        Self {
+            place,
+            voxel,
+            place_id,
+            data,
+            session_id: 0,
+            user_id: None,
+            username: None,
+            last_cursor: None,
+        }
     }
 
-    pub async fn run(self, mut session: Session, mut msg_stream: MessageStream) {
-        {
-            let place = self.place.write().unwrap();
-            place.voxel.add_session(session.clone());
-        }
+    // The server pings every HEARTBEAT_INTERVAL and evicts the session once nothing, pongs
+    // included, has been received for CLIENT_TIMEOUT, or once its queue was closed.
+    pub async fn run(mut self, session: Session, mut msg_stream: MessageStream) {
+        let voxel = self.voxel.clone();
+        let queue = SessionQueue::new(session);
+        let session_id = voxel.add_session(queue.clone());
+        self.session_id = session_id;
 
-        while let Some(msg) = msg_stream.next().await {
-            match msg {
-                Ok(Message::Ping(bytes)) => {
-                    if session.pong(&bytes).await.is_err() {
+        let mut heartbeat = actix_web::rt::time::interval(HEARTBEAT_INTERVAL);
+        let mut last_heartbeat = Instant::now();
+
+        loop {
+            let tick = pin!(heartbeat.tick());
+            let msg = match select(msg_stream.next(), tick).await {
+                Either::Left((Some(msg), _)) => msg,
+                Either::Left((None, _)) => break,
+                Either::Right(_) => {
+                    if last_heartbeat.elapsed() > CLIENT_TIMEOUT || !queue.send(Outgoing::Ping) {
                         break;
                     }
+                    continue;
                 }
-                Ok(Message::Text(text)) => {
-                    if session.text(text).await.is_err() {
-                        break;
+            };
+
+            last_heartbeat = Instant::now();
+            let sent = match msg {
+                Ok(Message::Ping(bytes)) => queue.send(Outgoing::Pong(bytes)),
+                Ok(Message::Pong(_)) => true,
+                Ok(Message::Text(text)) => match self.handle_text(&text) {
+                    Reply::Message(response) => queue.send(Outgoing::Text(response.to_json())),
+                    Reply::Frame(frame) => queue.send(Outgoing::Binary(frame)),
+                    Reply::None => true,
+                },
+                Ok(Message::Binary(_)) => {
+                    let response = ServerMessage::error(None, "invalid_message", "Binary messages are not supported");
+                    queue.send(Outgoing::Text(response.to_json()))
+                }
+                Ok(Message::Close(reason)) => {
+                    voxel.remove_session(session_id);
+                    queue.close(reason);
+                    return;
+                }
+                _ => false,
+            };
+            if !sent || queue.is_closed() {
+                break;
+            }
+        }
+
+        voxel.remove_session(session_id);
+        queue.close(None);
+    }
+
+    fn handle_text(&mut self, text: &str) -> Reply {
+        let message = match serde_json::from_str::<ClientMessage>(text) {
+            Ok(message) => message,
+            Err(e) => {
+                return Reply::Message(ServerMessage::error(None, "invalid_message", &e.to_string()))
+            }
+        };
+
+        let response = match message {
+            ClientMessage::Auth { token } => {
+                let app_state = match self.data.read() {
+                    Ok(app_state) => app_state,
+                    Err(_) => return Reply::Message(ServerMessage::error(None, "internal", "Failed to read app state")),
+                };
+
+                match check_token(&token, &app_state.config.jwt_secret) {
+                    Some(user_id) => {
+                        self.user_id = Some(user_id);
+                        self.username = app_state.username(user_id);
+                        ServerMessage::Authenticated {
+                            user_id: user_id.to_string(),
+                        }
                     }
+                    None => ServerMessage::error(None, "unauthenticated", "Invalid token"),
                 }
-                Ok(Message::Binary(bin)) => {
-                    if session.binary(bin).await.is_err() {
-                        break;
+            }
+            ClientMessage::Draw { id, x, y, z, color } => {
+                let (user_id, username) = match (self.user_id, &self.username) {
+                    (Some(user_id), Some(username)) => (user_id, username.clone()),
+                    _ => {
+                        let error = ServerMessage::error(id, "unauthenticated", "Not authenticated");
+                        return Reply::Message(error);
+                    }
+                };
+
+                let drawn = match self.place.read() {
+                    Ok(place) => place.draw(user_id, x, y, z, color),
+                    Err(_) => return Reply::Message(ServerMessage::error(id, "internal", "Failed to read place")),
+                };
+                match drawn {
+                    Ok(cooldown) => ServerMessage::Ack { id, username, cooldown },
+                    Err(e) => {
+                        let (code, cooldown) = match e {
+                            DrawError::Cooldown(cooldown) => ("cooldown", Some(cooldown)),
+                            DrawError::Rejected(_) => ("invalid_draw", None),
+                        };
+                        ServerMessage::Error {
+                            id,
+                            code,
+                            message: e.message(),
+                            cooldown,
+                        }
                     }
                 }
-                Ok(Message::Close(reason)) => {
-                    let _ = session.close(reason).await;
-                    break;
+            }
+            ClientMessage::Resync { from_seq } => {
+                match self.voxel.replay_updates(from_seq) {
+                    Replay::UpToDate => ServerMessage::UpToDate { seq: self.voxel.next_seq() },
+                    Replay::Frame(frame) => return Reply::Frame(frame),
+                    Replay::Unavailable(seq) => ServerMessage::ResyncRequired { seq },
                 }
-                _ => break,
             }
+            ClientMessage::Cursor { position, camera, color } => {
+                let username = match &self.username {
+                    Some(username) => username.clone(),
+                    None => {
+                        let error = ServerMessage::error(None, "unauthenticated", "Not authenticated");
+                        return Reply::Message(error);
+                    }
+                };
+                if !position.iter().chain(camera.iter()).all(|v| v.is_finite()) {
+                    return Reply::Message(ServerMessage::error(None, "invalid_message", "Invalid cursor"));
+                }
+
+                // Cursor updates faster than CURSOR_MIN_INTERVAL are dropped rather than queued:
+                // the next one supersedes them anyway.
+                if self.last_cursor.is_some_and(|last| last.elapsed() < CURSOR_MIN_INTERVAL) {
+                    return Reply::None;
+                }
+                self.last_cursor = Some(Instant::now());
+
+                self.voxel.set_cursor(
+                    self.session_id,
+                    Cursor {
+                        session_id: self.session_id.to_string(),
+                        username,
+                        position,
+                        camera,
+                        color,
+                    },
+                );
+                return Reply::None;
+            }
+            ClientMessage::Chat { id, content } => {
+                let (user_id, username) = match (self.user_id, &self.username) {
+                    (Some(user_id), Some(username)) => (user_id, username.clone()),
+                    _ => {
+                        let error = ServerMessage::error(id, "unauthenticated", "Not authenticated");
+                        return Reply::Message(error);
+                    }
+                };
+                let content = content.trim();
+                if content.is_empty() || content.chars().count() > MAX_CHAT_LENGTH {
+                    let message = format!("Messages must be 1 to {} characters long", MAX_CHAT_LENGTH);
+                    return Reply::Message(ServerMessage::error(id, "invalid_chat", &message));
+                }
+
+                let time = Utc::now().timestamp();
+                if let Err(cooldown) = self.voxel.start_chat_cooldown(user_id, time) {
+                    return Reply::Message(ServerMessage::Error {
+                        id,
+                        code: "cooldown",
+                        message: "Chat cooldown not finished".to_string(),
+                        cooldown: Some(cooldown),
+                    });
+                }
+
+                let comment_id = thread_rng().gen::<i64>();
+                let comment = Comment::new(comment_id, user_id, Some(self.place_id), None, content, time);
+                let saved = match self.data.read() {
+                    Ok(app_state) => match app_state.database.lock() {
+                        Ok(db) => db.save_new_comment(comment).is_ok(),
+                        Err(_) => false,
+                    },
+                    Err(_) => false,
+                };
+                if !saved {
+                    self.voxel.cancel_chat_cooldown(user_id);
+                    return Reply::Message(ServerMessage::error(id, "internal", "Failed to save message"));
+                }
+
+                let chat = ServerMessage::Chat {
+                    comment: PlaceComment {
+                        comment_id: comment_id.to_string(),
+                        user_id: user_id.to_string(),
+                        username,
+                        place_id: self.place_id.to_string(),
+                        content: content.to_string(),
+                        created_at: time,
+                    },
+                };
+                self.voxel.broadcast_message(chat.to_json());
+
+                ServerMessage::ChatSent {
+                    id,
+                    comment_id: comment_id.to_string(),
+                }
+            }
+            ClientMessage::HideCursor => {
+                self.voxel.remove_cursor(self.session_id);
+                return Reply::None;
+            }
+        };
+
+        Reply::Message(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::grid::ChunkedGrid;
+    use super::*;
+
+    fn recording_queue(frames: Rc<RefCell<usize>>) -> SessionQueue {
+        SessionQueue::spawn(move |message| {
+            if let Outgoing::Binary(_) = message {
+                *frames.borrow_mut() += 1;
+            }
+            async { true }
+        })
+    }
+
+    // Like a client that stopped reading: the first write never completes.
+    fn stalled_queue() -> SessionQueue {
+        SessionQueue::spawn(|_| std::future::pending())
+    }
+
+    #[actix_web::test]
+    async fn a_session_that_never_reads_is_dropped_without_delaying_others() {
+        let size = (16, 16, 16);
+        let voxel = Voxel::new(1, "test", 0, size, Some(ChunkedGrid::new(size)), None, None);
+        let frames = Rc::new(RefCell::new(0));
+        let stalled = stalled_queue();
+        let stalled_id = voxel.add_session(stalled.clone());
+        voxel.add_session(recording_queue(frames.clone()));
+
+        let rounds = SESSION_QUEUE * 2;
+        for round in 0..rounds {
+            voxel.overwrite_voxel(round % 16, 0, round / 16, 1);
+            send_pending(&voxel);
+            tokio::task::yield_now().await;
         }
+
+        assert_eq!(voxel.viewers(), 1);
+        assert!(voxel.sessions().iter().all(|(id, _)| *id != stalled_id));
+        assert!(stalled.is_closed());
+        assert!(!stalled.send(Outgoing::Ping));
+        assert_eq!(*frames.borrow(), rounds);
     }
-}
\ No newline at end of file
+
+    #[actix_web::test]
+    async fn a_full_queue_stops_its_writer() {
+        let queue = stalled_queue();
+        // The writer takes the first message, then the queue fills up.
+        let sent = (0..SESSION_QUEUE * 2).take_while(|_| queue.send(Outgoing::Ping)).count();
+        tokio::task::yield_now().await;
+        assert!(sent <= SESSION_QUEUE + 1);
+        assert!(queue.is_closed());
+    }
+}